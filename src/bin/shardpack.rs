@@ -0,0 +1,46 @@
+use std::path::Path;
+use std::process::ExitCode;
+
+use shardpack::{Error, LocalStorageProvider, ShardReader};
+
+const USAGE: &str = "usage: shardpack repair <shard>...";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, shards)) = args.split_first() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    match command.as_str() {
+        "repair" if !shards.is_empty() => {
+            let mut status = ExitCode::SUCCESS;
+            for shard in shards {
+                if let Err(err) = repair(Path::new(shard)).await {
+                    eprintln!("{}: {}", shard, err);
+                    status = ExitCode::FAILURE;
+                }
+            }
+            status
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Repairs the shard file at `shard`, truncating it after its last valid record block.
+async fn repair(shard: &Path) -> Result<(), Error> {
+    let root = shard.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = shard.file_name().map(Path::new).unwrap_or(shard);
+
+    let before = tokio::fs::metadata(shard).await?.len();
+    let provider = LocalStorageProvider::new(root).await?;
+    let reader = ShardReader::recover(provider, name).await?;
+    let after = tokio::fs::metadata(shard).await?.len();
+
+    println!("{}: {} records, {} -> {} bytes", shard.display(), reader.len(), before, after);
+    Ok(())
+}
@@ -1,79 +1,162 @@
-use serde::{Deserialize, Serialize};
-//use sha2::{Sha256, Digest};
+use bytes::Bytes;
 use tokio::sync::RwLock;
 
-use crate::checksum::{compute_checksum, verify_checksum};
+pub use crate::compression::CompressionType;
 use crate::error::Error;
-use crate::index::bucket::{BucketIndex, IndexEntry};
-use crate::shard::config::shard_size;
+use crate::index::bucket::{BucketIndex, BuildProgress, IndexEntry};
+use crate::index::generation::{decode_versions, Generation, RecordVersion};
+use crate::index::manifest::{Manifest, RetiredShard, ShardInfo, Tombstone, MANIFEST_NAME, SNAPSHOTS_DIR};
+use crate::shard::config::ShardPolicy;
+use crate::shard::set::ShardNameTemplate;
+use crate::shard::format::{decode_record_block, EntryHeader};
+use crate::shard::record::{concat, EntryInfo, FileEntry, Record, DEFAULT_CONTENT_TYPE};
+use crate::shard::{read_entry_content, read_record_header};
+use crate::shard::writer::DEFAULT_ENTRY_NAME;
 use crate::shard::shard::Shard;
 use crate::types::Result;
-use crate::storage::StorageProvider;
-use std::io::{Read, Write};
-use std::sync::Arc;
+use crate::storage::{partial_path, StorageLock, StorageProvider, LOCK_SUFFIX, PARTIAL_SUFFIX};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
 
+/// Appended to the name of the shards of a batch until it is committed, so that they never
+/// match the shard name template of the bucket.
+const BATCH_SUFFIX: &str = ".batch";
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone)]
 #[derive(Default)]
-pub enum CompressionType {
-   #[default]
-   None,
-   Gzip,
-   Lz4,
-   Zstd,
-   Snappy,
+pub struct BucketConfig {
+    pub compression: CompressionType,
+    pub parallelism: usize,
+    pub policy: ShardPolicy,
+    pub shard_name: ShardNameTemplate,
 }
 
+impl BucketConfig {
+    pub fn new(compression: CompressionType, parallelism: usize) -> Self {
+        Self { compression, parallelism, policy: ShardPolicy::default(), shard_name: ShardNameTemplate::default() }
+    }
 
- fn compress_gzip(data: &[u8]) -> Result<Vec<u8>> {
-    use flate2::write::GzEncoder;
-    use flate2::Compression;
-    use std::io::Write;
+    /// Sets the policy deciding when the open shard of the bucket is sealed.
+    pub fn with_policy(mut self, policy: ShardPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
 
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(data)?;
-    encoder.finish().map_err(Error::from)
+    /// Sets the template the shards of the bucket are named after, e.g.
+    /// `dataset-train-{id:06}.shardpack`.
+    pub fn with_shard_name(mut self, shard_name: ShardNameTemplate) -> Self {
+        self.shard_name = shard_name;
+        self
+    }
 }
 
-fn decompress_gzip(data: &[u8]) -> Result<Vec<u8>> {
-    use flate2::read::GzDecoder;
-    use std::io::Read;
-
-    let mut decoder = GzDecoder::new(data);
-    let mut decompressed = Vec::new();
-    decoder.read_to_end(&mut decompressed)?;
-    Ok(decompressed)
+/// Decides which shards `Bucket::compact` rewrites.
+///
+/// # Fields
+///
+/// * `min_live_ratio` - The share of the bytes of a shard that must belong to live records for
+///   the shard to be kept as is. Shards below it are rewritten without their deleted and
+///   superseded records.
+/// * `keep_generations` - How many of the latest generations of every key stay live, or `None`
+///   to keep every generation readable with `read_version`. The latest generation is always
+///   kept; older ones only count as superseded beyond this number.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionPolicy {
+    pub min_live_ratio: f64,
+    pub keep_generations: Option<usize>,
 }
 
-fn compress_lz4(data: &[u8]) -> Result<Vec<u8>> {
-    Ok(lz4_flex::block::compress(data))
+impl Default for CompactionPolicy {
+    /// Constructs a policy rewriting shards that are less than half live, keeping every
+    /// generation.
+    fn default() -> Self {
+        Self { min_live_ratio: 0.5, keep_generations: None }
+    }
 }
- 
- fn decompress_lz4(data: &[u8]) -> Result<Vec<u8>> {
-    lz4_flex::block::decompress(data, data.len() * 3)
-        .map_err(|e| Error::Storage(e.to_string()))
- }
 
+impl CompactionPolicy {
+    /// Sets the share of live bytes below which a shard is rewritten.
+    pub fn with_min_live_ratio(mut self, min_live_ratio: f64) -> Self {
+        self.min_live_ratio = min_live_ratio;
+        self
+    }
 
-#[derive(Clone)]
-#[derive(Default)]
-pub struct BucketConfig {
-    pub compression: CompressionType,
-    pub parallelism: usize,
-}
+    /// Sets how many of the latest generations of every key stay live.
+    pub fn with_keep_generations(mut self, keep_generations: usize) -> Self {
+        self.keep_generations = Some(keep_generations);
+        self
+    }
 
-impl BucketConfig {
-    pub fn new(compression: CompressionType, parallelism: usize) -> Self {
-        Self { compression, parallelism }
+    /// Returns how many of the `count` generations of a key, oldest first, are superseded.
+    pub fn superseded(&self, count: usize) -> usize {
+        self.keep_generations.map_or(0, |keep| count.saturating_sub(keep.max(1)))
     }
+
+    /// Returns `true` if a shard of `size` bytes holding `live` bytes of live records must be
+    /// rewritten.
+    pub fn is_due(&self, live: u64, size: u64) -> bool {
+        (live as f64) < self.min_live_ratio * size as f64
+    }
+}
+
+/// Describes what a run of `Bucket::compact` did.
+///
+/// # Fields
+///
+/// * `shards_removed` - The number of shards rewritten and removed.
+/// * `shards_written` - The number of shards written with the live records of the removed ones.
+/// * `records_copied` - The number of live records copied.
+/// * `bytes_reclaimed` - The size of the removed shards minus the size of the written shards.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub shards_removed: usize,
+    pub shards_written: usize,
+    pub records_copied: usize,
+    pub bytes_reclaimed: u64,
 }
 
 pub struct Bucket<P: StorageProvider> {
     name: String,
     provider: Arc<P>,
     index: RwLock<BucketIndex>,
-    shards: Vec<Shard<P>>,
+    manifest: RwLock<Manifest>,
+    shards: Vec<Shard<Arc<P>>>,
     config: BucketConfig,
+    snapshot: Option<String>,
+    staging: bool,
+    claims: HashMap<u64, Box<dyn StorageLock>>,
+    deleted_pending: Mutex<HashMap<String, u64>>,
+}
+
+/// A group of writes and deletes applied to a bucket at once, created by `Bucket::begin_batch`.
+///
+/// Records written to the batch are streamed into shards of its own, which are sealed as they
+/// fill up but left out of the manifest, while deletes are kept in memory. The shards are
+/// staged under their name followed by `.batch`, so that `Bucket::recover` never picks them
+/// up. `commit` adds them and the tombstones of the batch to the manifest in a single update,
+/// so readers see either none or all of the batch, and then renames them.
+///
+/// The shards of an aborted batch are removed. Those of a batch dropped without being
+/// committed or aborted, or interrupted by a crash, are removed by `Bucket::collect_garbage`.
+///
+/// # Fields
+///
+/// * `bucket` - The bucket the batch is applied to.
+/// * `infos` - The sealed shards of the batch.
+/// * `generations` - The generations of the records in the sealed shards of the batch.
+/// * `written` - The latest generation of every key written to the batch, and the identifier
+///   of the last shard holding it.
+/// * `tombstones` - The keys deleted by the batch.
+pub struct Batch<'a, P: StorageProvider> {
+    bucket: &'a mut Bucket<P>,
+    infos: Vec<ShardInfo>,
+    generations: Vec<(String, Generation)>,
+    written: HashMap<String, (u64, u64)>,
+    tombstones: Vec<Tombstone>,
 }
 
 
@@ -83,117 +166,2155 @@ impl<P: StorageProvider> Bucket<P> {
             name, 
             provider, 
             index: Default::default(), 
+            manifest: Default::default(),
             shards: Default::default(), 
-            config 
+            config,
+            snapshot: None,
+            staging: false,
+            claims: HashMap::new(),
+            deleted_pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens an existing bucket from its manifest.
+    ///
+    /// The manifest is loaded with a single read, without listing the bucket or reading any
+    /// shard. The index at the end of a shard is only read once a key within the key range
+    /// the manifest records for the shard is looked up. Records of shards that were never
+    /// sealed are not part of the bucket.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the bucket.
+    /// * `provider` - The storage provider holding the bucket.
+    /// * `config` - The configuration the bucket was created with; its `shard_name` must match
+    ///   the names of the existing shards.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the bucket, or an error if it has no manifest. Shards that cannot
+    ///   be read fail the lookups that load them.
+    pub async fn open(name: String, provider: Arc<P>, config: BucketConfig) -> Result<Self> {
+        let bucket = Self::new(name, provider, config);
+        let manifest = Manifest::load(bucket.provider.as_ref(), &bucket.manifest_path()).await?
+            .ok_or_else(|| Error::Storage(format!("Bucket {} has no manifest", bucket.name)))?;
+        bucket.load(manifest).await
+    }
+
+    /// Opens a bucket in the state recorded by one of its snapshots.
+    ///
+    /// The bucket is read-only: it serves the records and generations visible when the
+    /// snapshot was taken, regardless of later writes, deletes and compactions.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the bucket.
+    /// * `provider` - The storage provider holding the bucket.
+    /// * `config` - The configuration the bucket was created with.
+    /// * `snapshot` - The name of the snapshot, as passed to `snapshot`.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the bucket, or an error if the snapshot does not exist.
+    pub async fn open_at(name: String, provider: Arc<P>, config: BucketConfig, snapshot: &str) -> Result<Self> {
+        let mut bucket = Self::new(name, provider, config);
+        let manifest = Manifest::load(bucket.provider.as_ref(), &bucket.snapshot_path(snapshot)?).await?
+            .ok_or_else(|| Error::Storage(format!("Snapshot {} not found", snapshot)))?;
+        bucket.snapshot = Some(snapshot.to_string());
+        bucket.load(manifest).await
+    }
+
+    /// Sets up the index of the bucket for the shards listed in `manifest`, without loading
+    /// any of them.
+    async fn load(self, manifest: Manifest) -> Result<Self> {
+        let bucket = self;
+        if let Some(info) = manifest.shards.iter().find(|info| info.path != bucket.get_shard_path(info.id)) {
+            return Err(Error::Config(format!(
+                "Shard {} does not match the shard name template {}",
+                info.path.display(),
+                bucket.config.shard_name,
+            )));
+        }
+        let mut index = BucketIndex { unloaded: manifest.shards.clone(), ..BucketIndex::default() };
+        index.apply_tombstones(&manifest.tombstones);
+
+        *bucket.index.write().await = index;
+        *bucket.manifest.write().await = manifest;
+        Ok(bucket)
+    }
+
+    /// Recovers a bucket whose manifest is lost, from the shards found in storage.
+    ///
+    /// The key index is rebuilt from the index at the end of every object of the bucket named
+    /// after `config.shard_name`, reading `config.parallelism` shards at a time. A new manifest
+    /// listing these shards is then stored, which reads every shard in full to checksum it.
+    ///
+    /// Tombstones are kept in the manifest only, so keys deleted before the manifest was lost
+    /// are visible again.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the bucket.
+    /// * `provider` - The storage provider holding the bucket.
+    /// * `config` - The configuration the bucket was created with.
+    /// * `progress` - Called after every shard indexed with the progress of the rebuild.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the bucket, or an error naming every corrupt shard.
+    pub async fn recover(
+        name: String,
+        provider: Arc<P>,
+        config: BucketConfig,
+        progress: impl FnMut(&BuildProgress),
+    ) -> Result<Self> {
+        let bucket = Self::new(name, provider, config);
+        let provider = bucket.provider.as_ref();
+        let shards = BucketIndex::list_shards(provider, &bucket.name, &bucket.config.shard_name).await?;
+        let index = BucketIndex::build(provider, shards.clone(), bucket.config.parallelism, progress).await?;
+
+        let infos = stream::iter(shards)
+            .map(|(id, path)| ShardInfo::read(provider, id, path))
+            .buffered(bucket.config.parallelism.max(1))
+            .try_collect()
+            .await?;
+        let manifest = Manifest { shards: infos, ..Manifest::default() };
+        manifest.store(provider, &bucket.manifest_path()).await?;
+
+        *bucket.index.write().await = index;
+        *bucket.manifest.write().await = manifest;
+        Ok(bucket)
+    }
+
+    /// Picks up the shards added to or removed from the bucket since it was opened or last
+    /// refreshed, e.g. by another process writing to it.
+    ///
+    /// The stored manifest is compared with the one the bucket was opened with. New shards are
+    /// loaded like those listed on `open`, once a key within their key range is looked up.
+    /// Keys of removed shards and keys deleted since are dropped. The changes are applied to
+    /// the index in one step, so concurrent reads see either the previous or the refreshed
+    /// bucket and keep working throughout.
+    ///
+    /// # Returns
+    /// * `Result<bool>` with `true` if shards were added or removed.
+    pub async fn refresh(&self) -> Result<bool> {
+        if self.snapshot.is_some() {
+            return Ok(false);
+        }
+        let Some(stored) = Manifest::load(self.provider.as_ref(), &self.manifest_path()).await? else {
+            return Ok(false);
+        };
+        let current = self.manifest.read().await.clone();
+        if stored == current {
+            return Ok(false);
+        }
+
+        // A shard replaced under the same identifier counts as removed and added
+        let removed: HashSet<u64> = current.shards.iter()
+            .filter(|info| !stored.shards.contains(info))
+            .map(|info| info.id)
+            .collect();
+        let added = stored.shards.iter().filter(|info| !current.shards.contains(info)).cloned();
+
+        let mut index = self.index.write().await;
+        let mut manifest = self.manifest.write().await;
+        index.remove_shards(&removed);
+        index.unloaded.extend(added);
+        index.apply_tombstones(&stored.tombstones);
+        *manifest = stored;
+        Ok(true)
+    }
+
+    /// Records the current state of the bucket as the snapshot `name`.
+    ///
+    /// The snapshot lists the sealed shards and the tombstones of the bucket, so that
+    /// `open_at` reads exactly the records visible now, without copying any of them. Records
+    /// of the open shard are not part of the snapshot; `flush` first to include them. Shards a
+    /// snapshot lists are kept by `compact` and `collect_garbage` until it is dropped.
+    ///
+    /// # Returns
+    /// * `Result<()>`, or an error if a snapshot named `name` already exists.
+    pub async fn snapshot(&self, name: &str) -> Result<()> {
+        let path = self.snapshot_path(name)?;
+        if Manifest::load(self.provider.as_ref(), &path).await?.is_some() {
+            return Err(Error::Storage(format!("Snapshot {} already exists", name)));
         }
+        let manifest = self.manifest.read().await.clone();
+        manifest.store(self.provider.as_ref(), &path).await
+    }
+
+    /// Lists the snapshots of the bucket.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` with the names of the snapshots in sorted order.
+    pub async fn snapshots(&self) -> Result<Vec<String>> {
+        let dir = PathBuf::from(&self.name).join(SNAPSHOTS_DIR);
+        let files = match self.provider.list(&dir).await {
+            Ok(files) => files,
+            Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let mut names: Vec<String> = files.iter()
+            .filter_map(|file| PathBuf::from(file).file_name()?.to_str().map(str::to_string))
+            .filter(|name| self.snapshot_path(name).is_ok())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Drops the snapshot `name`. The retired shards only it referenced are removed by the
+    /// next `collect_garbage`.
+    pub async fn drop_snapshot(&self, name: &str) -> Result<()> {
+        self.provider.delete(&self.snapshot_path(name)?).await
+    }
+
+    /// Removes the shards of the bucket that are no longer needed.
+    ///
+    /// Shards retired by `compact` are removed once they were retired more than `grace` ago,
+    /// so that readers in other processes that opened the bucket earlier keep reading them
+    /// until they `refresh`, and once no snapshot lists them any more. `grace` must therefore
+    /// exceed how long readers go without refreshing. Shards that are listed nowhere, such as
+    /// those left behind by interrupted writes or batches, are removed as soon as no writer
+    /// claims their identifier, along with the objects still being streamed for them.
+    ///
+    /// The manifest stays locked throughout, so that no writer publishes a shard or claims an
+    /// identifier while its objects are looked at. Shards of a batch whose commit stored the
+    /// manifest but was interrupted before renaming them are renamed instead.
+    ///
+    /// # Returns
+    /// * `Result<usize>` with the number of shards removed.
+    pub async fn collect_garbage(&self, grace: Duration) -> Result<usize> {
+        self.check_writable()?;
+        let lock = self.provider.lock(&self.manifest_path()).await?;
+        let removed = self.collect_garbage_locked(grace).await;
+        let released = lock.release().await;
+        let removed = removed?;
+        released?;
+        Ok(removed)
+    }
+
+    /// Removes the shards `collect_garbage` removes, with the manifest already locked.
+    async fn collect_garbage_locked(&self, grace: Duration) -> Result<usize> {
+        let provider = self.provider.as_ref();
+        let cutoff = SystemTime::now().checked_sub(grace).unwrap_or(SystemTime::UNIX_EPOCH);
+        let snapshots = self.snapshot_shards().await?;
+
+        // Expired shards leave the manifest before their objects are removed
+        let mut manifest = Manifest::load(provider, &self.manifest_path()).await?.unwrap_or_default();
+        let retired = manifest.retired.len();
+        manifest.retired.retain(|shard| shard.time() > cutoff || snapshots.contains(&shard.path));
+        if manifest.retired.len() < retired {
+            manifest.store(provider, &self.manifest_path()).await?;
+            let kept: HashSet<&PathBuf> = manifest.retired.iter().map(|shard| &shard.path).collect();
+            self.manifest.write().await.retired.retain(|shard| kept.contains(&shard.path));
+        }
+
+        let mut referenced = snapshots;
+        referenced.extend(manifest.retired.iter().map(|shard| shard.path.clone()));
+        let listed: HashSet<u64> = manifest.shards.iter().map(|info| info.id).collect();
+
+        // Group the objects of every shard identifier: the shard, its staged copy, the objects
+        // still being streamed for either, and the file backing its claim
+        let mut objects: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        for path in self.provider.list(self.name.as_ref()).await?.into_iter().map(PathBuf::from) {
+            if let Some(shard_id) = path.file_name().and_then(|name| self.object_shard_id(name.to_str()?)) {
+                objects.entry(shard_id).or_default().push(path);
+            }
+        }
+
+        let mut removed = 0;
+        for (shard_id, paths) in objects {
+            let shard_path = self.get_shard_path(shard_id);
+            let batch_path = self.get_batch_path(shard_id);
+            if listed.contains(&shard_id) {
+                // The batch published the shard but was interrupted before renaming it
+                if paths.contains(&batch_path) && !paths.contains(&shard_path) {
+                    provider.rename(&batch_path, &shard_path).await?;
+                }
+                continue;
+            }
+            if referenced.contains(&shard_path) {
+                continue;
+            }
+            // Identifiers still claimed belong to shards other writers are filling or staging
+            let Some(claim) = provider.try_lock(&shard_path).await? else {
+                continue;
+            };
+            let mut deleted = false;
+            for path in paths.iter().filter(|path| !path.to_string_lossy().ends_with(LOCK_SUFFIX)) {
+                provider.delete(path).await?;
+                deleted = true;
+            }
+            claim.remove().await?;
+            removed += usize::from(deleted);
+        }
+        Ok(removed)
+    }
+
+    /// Returns the paths of the shards listed in any snapshot of the bucket.
+    async fn snapshot_shards(&self) -> Result<HashSet<PathBuf>> {
+        let mut paths = HashSet::new();
+        for name in self.snapshots().await? {
+            if let Some(snapshot) = Manifest::load(self.provider.as_ref(), &self.snapshot_path(&name)?).await? {
+                paths.extend(snapshot.shards.into_iter().map(|info| info.path));
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Starts a batch of writes and deletes that become visible together.
+    ///
+    /// The open shard is flushed first, so that records written before the batch are not part
+    /// of it. The bucket cannot be used until the batch is committed, aborted or dropped.
+    ///
+    /// # Returns
+    /// * `Result<Batch<'_, P>>` with the empty batch, or an error if the bucket is read-only or
+    ///   the open shard cannot be flushed.
+    pub async fn begin_batch(&mut self) -> Result<Batch<'_, P>> {
+        self.check_writable()?;
+        self.flush().await?;
+        self.staging = true;
+        Ok(Batch {
+            bucket: self,
+            infos: Vec::new(),
+            generations: Vec::new(),
+            written: HashMap::new(),
+            tombstones: Vec::new(),
+        })
+    }
+
+    /// Returns a copy of the manifest listing the sealed shards of the bucket.
+    pub async fn manifest(&self) -> Manifest {
+        self.manifest.read().await.clone()
     }
     
-    pub async fn write(&mut self, key:  &str, data:  &Vec<u8>, metadata: Option<Vec<u8>>)  -> Result<()> {
-        let index = self.index.write().await;
-        let current_shards = self.shards.len();
-
-        if current_shards == 0 {
-            // No active shards yet; create a new one
-            let new_shard = Shard::new();
-            self.shards.push(new_shard);
-        }
-
-        let last_shard_idx = current_shards - 1;
-        let last_shard_path = self.get_shard_path(last_shard_idx);
-
-        // Read the existing data from the last shard to check its size
-        let mut file = self.provider.read(&last_shard_path).await?;
-        // let mut buffer = Vec::new();
-        // file.read_to_end(&mut buffer)?;
-        let current_size = file.len();
-
-        // Determine if we need to create a new shard or use the existing one
-        let (shard_id, offset) = {
-            let available_space = shard_size() - current_size;
-            if available_space < data.len() {
-                // The data is too large for the current shard; create a new one
-                self.shards.push(Shard::new());
-                (current_shards, 0)
-            } else {
-                // Write to the current shard
-                (last_shard_idx, current_size)
+    /// Writes `data` under `key` with optional record-level metadata.
+    ///
+    /// The data is stored as the single file entry of the record, compressed as configured
+    /// in `BucketConfig::compression`.
+    pub async fn write(&mut self, key:  &str, data:  &[u8], metadata: Option<Vec<u8>>)  -> Result<()> {
+        let record = self.data_record(key, data, metadata);
+        self.write_record(&record).await
+    }
+
+    /// Writes a record with any number of file entries.
+    ///
+    /// The record is streamed into the open shard of the bucket. When it does not fit, the
+    /// open shard is sealed and a new one is started. Records become visible to `read` once
+    /// their shard is sealed, either by a rollover or by `flush`.
+    ///
+    /// A record too large for a shard of its own is split into chunks written to consecutive
+    /// shards, and becomes visible as soon as its last chunk is written. Its shards are removed
+    /// and `Error::Conflict` returned if another writer published a later generation of the
+    /// key meanwhile.
+    ///
+    /// The open shard is also sealed once it reaches the record count or age limit of the
+    /// `ShardPolicy` of the bucket.
+    ///
+    /// Every write creates a new generation of the key. `read` returns the latest generation,
+    /// while earlier ones stay readable with `read_version`.
+    pub async fn write_record(&mut self, record: &Record) -> Result<()> {
+        self.check_writable()?;
+        let version = RecordVersion::now(self.next_generation(record.key()).await?);
+        loop {
+            let shard = self.open_shard().await?;
+            match shard.write(record, version).await {
+                // The open shard is full; seal it and retry on a new one
+                Err(Error::ShardFull) if !shard.is_empty() => self.flush().await?,
+                Err(Error::ShardFull) => return self.write_chunked(record, version).await,
+                result => {
+                    result?;
+                    return self.seal_if_due().await.map(|_| ());
+                }
+            }
+        }
+    }
+
+    /// Seals the open shard if it has reached the record count or age limit of the
+    /// `ShardPolicy` of the bucket.
+    ///
+    /// Writes check the policy on their own, but a shard only ages out on a write. Streaming
+    /// writers call this periodically, e.g. from a timer, so that their records become visible
+    /// within `max_open` even when no more records arrive.
+    ///
+    /// # Returns
+    /// * `Result<bool>` with `true` if the open shard was sealed.
+    pub async fn seal_if_due(&mut self) -> Result<bool> {
+        let due = self.is_seal_due();
+        if due {
+            self.flush().await?;
+        }
+        Ok(due)
+    }
+
+    /// Returns `true` if the open shard has reached the record count or age limit of the
+    /// `ShardPolicy` of the bucket.
+    fn is_seal_due(&self) -> bool {
+        self.shards.last()
+            .filter(|shard| !shard.is_sealed() && !shard.is_empty())
+            .is_some_and(|shard| self.config.policy.is_due(shard.len(), shard.age()))
+    }
+
+    /// Writes a record too large for a single shard as chunks spread over consecutive shards.
+    ///
+    /// Every chunk fills a shard of its own, which is sealed right away. The chunks are
+    /// published to the index together once the last one is sealed, so readers never see
+    /// part of a record.
+    async fn write_chunked(&mut self, record: &Record, version: RecordVersion) -> Result<()> {
+        let (generation, infos) = self.write_chunks(record, version).await?;
+        let generations = [(record.key().to_string(), generation)];
+        self.commit(infos, &generations).await?;
+        let [(key, generation)] = generations;
+        self.index.write().await.insert(key, generation)
+    }
+
+    /// Writes the chunks of a record too large for a single shard, sealing every shard they
+    /// fill without publishing it.
+    ///
+    /// # Returns
+    /// * `Result<(Generation, Vec<ShardInfo>)>` with the generation of the record, locating
+    ///   every chunk, and the descriptions of the sealed shards.
+    async fn write_chunks(&mut self, record: &Record, version: RecordVersion) -> Result<(Generation, Vec<ShardInfo>)> {
+        // Leave room for the record header and for compression that does not pay off
+        let max_size = self.config.policy.max_size;
+        let mut chunk_size = max_size - max_size / 64;
+        let mut entries = Vec::new();
+        let mut infos = Vec::new();
+        let mut start = 0;
+        loop {
+            let chunk = record.split_chunk(entries.len() as u64, start, chunk_size);
+            let shard = self.open_shard().await?;
+            let written = match shard.write(&chunk, version).await {
+                // The chunk still does not fit; retry with less content
+                Err(Error::ShardFull) if chunk_size > 1 => {
+                    chunk_size /= 2;
+                    continue;
+                }
+                result => result?,
+            };
+            entries.push(IndexEntry::new(shard.id(), written.offset, written.size, written.checksum));
+            if let Some((_, info)) = shard.seal().await? {
+                infos.push(info);
+            }
+
+            start += chunk.content_size();
+            if chunk.chunk().is_some_and(|chunk| chunk.last) {
+                break;
+            }
+        }
+        Ok((Generation { version, entries }, infos))
+    }
+
+    /// Adds sealed shards to the manifest and stores it, making them part of the bucket.
+    ///
+    /// The generations of the records of sealed shards cannot change any more, so they are
+    /// checked under the lock of the manifest to follow the generations other writers
+    /// published meanwhile. The shards are removed if they do not, or if the manifest cannot
+    /// be stored.
+    ///
+    /// # Returns
+    /// * `Result<()>`, or `Error::Conflict` if another writer published one of `generations`
+    ///   or a later generation of its key.
+    async fn commit(&mut self, infos: Vec<ShardInfo>, generations: &[(String, Generation)]) -> Result<()> {
+        let provider = self.provider.as_ref();
+        let lock = provider.lock(&self.manifest_path()).await?;
+        let committed: Result<()> = async {
+            let mut manifest = Manifest::load(provider, &self.manifest_path()).await?.unwrap_or_default();
+            self.check_generations(&manifest, generations, &[]).await?;
+            manifest.shards.extend(infos.iter().cloned());
+            manifest.store(provider, &self.manifest_path()).await
+        }.await;
+        let released = lock.release().await;
+        if let Err(err) = committed {
+            self.discard_shards(infos).await?;
+            return Err(err);
+        }
+        released?;
+        self.manifest.write().await.shards.extend(infos.iter().cloned());
+        self.release_claims(infos.iter().map(|info| info.id)).await
+    }
+
+    /// Checks that `generations` follow the generations of their keys stored in the bucket as
+    /// `stored` lists it, ignoring those hidden by `tombstones`.
+    ///
+    /// # Returns
+    /// * `Result<()>`, or `Error::Conflict` naming the first generation that does not.
+    async fn check_generations(
+        &self,
+        stored: &Manifest,
+        generations: &[(String, Generation)],
+        tombstones: &[Tombstone],
+    ) -> Result<()> {
+        let keys: Vec<String> = generations.iter().map(|(key, _)| key.clone()).collect();
+        let latest = self.stored_generations(stored, &keys).await?;
+        for (key, generation) in generations {
+            if tombstones.iter().any(|tombstone| tombstone.key == *key && tombstone.hides(generation)) {
+                continue;
+            }
+            if generation.version.generation <= latest[key] {
+                return Err(Error::Conflict(format!(
+                    "Generation {} of key {} was already written by another writer",
+                    generation.version.generation, key
+                )));
             }
+        }
+        Ok(())
+    }
+
+    /// Returns the latest generation of every key of `keys` stored in the bucket as `stored`
+    /// lists it, or hidden by one of its tombstones, 0 for keys without any.
+    ///
+    /// Shards listed in `stored` but not in the manifest of the bucket, i.e. published by
+    /// other writers since it was last refreshed, are read as well if they may hold one of
+    /// the keys.
+    async fn stored_generations(&self, stored: &Manifest, keys: &[String]) -> Result<HashMap<String, u64>> {
+        let (Some(first), Some(last)) = (keys.iter().min(), keys.iter().max()) else {
+            return Ok(HashMap::new());
         };
+        let in_range = |info: &ShardInfo| info.records > 0 && info.min_key <= *last && *first <= info.max_key;
+        self.load_shards(in_range).await?;
 
-        let index_entry = IndexEntry::new(
-            shard_id,
-            offset,
-            data.len(),
-            compute_checksum(data)
-        );
-
-        // Handle compression based on config
-        let compressed_data = match self.config.compression {
-            CompressionType::None => data.clone(),
-            CompressionType::Gzip => compress_gzip(data)?,
-            CompressionType::Lz4 => compress_lz4(data)?,
-            _ => return Err(Error::Storage("Unsupported compression".into()))
+        let added = {
+            let manifest = self.manifest.read().await;
+            let known: HashMap<u64, &ShardInfo> = manifest.shards.iter().map(|info| (info.id, info)).collect();
+            stored.shards.iter()
+                .filter(|info| in_range(info) && known.get(&info.id) != Some(info))
+                .map(|info| (info.id, info.path.clone()))
+                .collect()
         };
+        let added = BucketIndex::build(self.provider.as_ref(), added, self.config.parallelism, |_| {}).await?;
+        let deleted: HashMap<&str, u64> = stored.tombstones.iter()
+            .map(|tombstone| (tombstone.key.as_str(), tombstone.generation))
+            .collect();
 
-        // Write the compressed data to the appropriate shard
-        self.provider.write(&last_shard_path, &compressed_data).await?;
+        let index = self.index.read().await;
+        Ok(keys.iter()
+            .map(|key| {
+                let latest = index.next_generation(key).max(added.next_generation(key)) - 1;
+                (key.clone(), latest.max(deleted.get(key.as_str()).copied().unwrap_or(0)))
+            })
+            .collect())
+    }
+
+    /// Applies `update` to the stored manifest, on top of the changes of other writers sharing
+    /// the bucket, and then to the manifest the index of the bucket reflects.
+    ///
+    /// Shards and tombstones added by other writers are kept in the stored manifest but only
+    /// reach the index, and the manifest it reflects, with `refresh`. No lock of the bucket is
+    /// held while the stored manifest is replaced, so callers update the index afterwards. If
+    /// `update` returns an error for the stored manifest, neither manifest is changed.
+    async fn update_manifest(&self, update: impl Fn(&mut Manifest) -> Result<()>) -> Result<()> {
+        Manifest::update(self.provider.as_ref(), &self.manifest_path(), &update).await?;
+        update(&mut *self.manifest.write().await)
+    }
 
-        // if let Some(meta) = metadata {
-        //     index.metadata.insert(key.to_string(), meta);
-        // }
+    /// Seals the open shard, if any, adds it to the manifest and publishes its records to the
+    /// index.
+    ///
+    /// Generations are numbered from what this writer knows of the bucket, so other writers
+    /// may have published the same generations of a key meanwhile, or deleted them. The open
+    /// shard is therefore sealed under the lock of the manifest, once the generations of its
+    /// records were moved past those stored in the bucket.
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.shards.last().is_none_or(|shard| shard.is_sealed() || shard.is_empty()) {
+            return self.seal_open().await.map(|_| ());
+        }
+        let lock = self.provider.lock(&self.manifest_path()).await?;
+        let published = self.publish_open().await;
+        let released = lock.release().await;
+        let (info, generations) = published?;
+        released?;
+        self.release_claims([info.id]).await?;
+
+        // Records deleted while the shard was open are hidden by their tombstone
+        let mut index = self.index.write().await;
+        for (key, generation) in generations {
+            index.insert(key, generation)?;
+        }
         Ok(())
     }
+
+    /// Renumbers, seals and lists the open shard in the manifest, which is locked.
+    ///
+    /// # Returns
+    /// * `Result<(ShardInfo, Vec<(String, Generation)>)>` with the description of the shard
+    ///   and the generations of its records.
+    async fn publish_open(&mut self) -> Result<(ShardInfo, Vec<(String, Generation)>)> {
+        let mut manifest = Manifest::load(self.provider.as_ref(), &self.manifest_path()).await?.unwrap_or_default();
+        let keys: Vec<String> = self.shards.last().into_iter().flat_map(Shard::keys).map(str::to_string).collect();
+        let latest = self.stored_generations(&manifest, &keys).await?;
+        let deleted = std::mem::take(&mut *self.deleted_pending.lock().unwrap_or_else(PoisonError::into_inner));
+        if let Some(shard) = self.shards.last_mut() {
+            for (key, latest) in &latest {
+                // Records deleted while pending keep their generation, which their tombstone hides
+                shard.renumber(key, deleted.get(key).copied().unwrap_or(0), *latest);
+            }
+        }
+
+        let (info, generations) = self.seal_open().await?
+            .ok_or_else(|| Error::Storage("No open shard".into()))?;
+        manifest.shards.push(info.clone());
+        manifest.store(self.provider.as_ref(), &self.manifest_path()).await?;
+        self.manifest.write().await.shards.push(info.clone());
+        Ok((info, generations))
+    }
+
+    /// Seals the open shard, if any, without publishing it.
+    ///
+    /// # Returns
+    /// * `Result<Option<(ShardInfo, Vec<(String, Generation)>)>>` with the description of the
+    ///   sealed shard and the generations of its records, or `None` if no shard was open or
+    ///   nothing was written to it.
+    async fn seal_open(&mut self) -> Result<Option<(ShardInfo, Vec<(String, Generation)>)>> {
+        let Some(shard) = self.shards.last_mut() else {
+            return Ok(None);
+        };
+
+        if shard.is_empty() {
+            // Nothing was written; drop the shard instead of persisting an empty one
+            shard.abort().await?;
+            let shard_id = shard.id();
+            self.shards.pop();
+            self.release_claims([shard_id]).await?;
+            return Ok(None);
+        }
+
+        let Some((shard_index, info)) = shard.seal().await? else {
+            return Ok(None);
+        };
+        let shard_id = shard.id();
+        let versions = decode_versions(&shard_index.metadata, shard_index.records.len())?
+            .ok_or_else(|| Error::Index("Sealed shard has no record versions".into()))?;
+        let generations = shard_index.records.into_iter()
+            .zip(versions)
+            .map(|(record, version)| {
+                let entry = IndexEntry::new(shard_id, record.offset, record.size, record.checksum);
+                (record.key, Generation { version, entries: vec![entry] })
+            })
+            .collect();
+        Ok(Some((info, generations)))
+    }
  
-    pub async fn read(&self, key: &str) -> Result<Vec<u8>> {
+    /// Rewrites the shards holding too few live records, reclaiming the space of deleted
+    /// records.
+    ///
+    /// The open shard is flushed and the bucket refreshed first, so that shards are selected
+    /// from the current manifest. The live records of every shard selected by `policy` are
+    /// then copied as-is, keeping their generation, into new shards filled up to the
+    /// `ShardPolicy` of the bucket, verifying the checksum of every record on the way. The
+    /// manifest is switched over to the new shards in a single update, so a bucket opened or
+    /// refreshed at any point sees every live record exactly once. The old shards are retired rather than removed: they stay in
+    /// storage, readable by readers holding an older manifest, until `collect_garbage` removes
+    /// them once its grace period has passed.
+    ///
+    /// Generations of a key older than the `keep_generations` latest ones of `policy` are
+    /// superseded: they are not copied, and are no longer readable once their shard has been
+    /// rewritten. Shards holding a chunk of a live chunked record are kept as is. Tombstones
+    /// are kept even once no shard holds a record they hide, since they record the latest
+    /// generation of their key that later writes continue from.
+    ///
+    /// # Returns
+    /// * `Result<CompactionReport>` with the shards rewritten and the space reclaimed, or an
+    ///   error if a record cannot be copied or another writer removed a selected shard
+    ///   meanwhile, in which case the new shards are removed and the bucket is left as it was.
+    pub async fn compact(&mut self, policy: &CompactionPolicy) -> Result<CompactionReport> {
+        self.check_writable()?;
+        self.flush().await?;
+        self.refresh().await?;
+        self.load_shards(|_| true).await?;
+        let manifest = self.manifest.read().await.clone();
+
+        let (selected, records) = {
+            let index = self.index.read().await;
+            let mut live: HashMap<u64, u64> = HashMap::new();
+            let mut chunked = HashSet::new();
+            let retained = || index.entries.iter().flat_map(|(key, generations)| {
+                generations[policy.superseded(generations.len())..].iter().map(move |generation| (key, generation))
+            });
+            for (_, generation) in retained() {
+                for entry in &generation.entries {
+                    *live.entry(entry.shard_id).or_default() += entry.size;
+                    if generation.entries.len() > 1 {
+                        chunked.insert(entry.shard_id);
+                    }
+                }
+            }
+            let selected: HashSet<u64> = manifest.shards.iter()
+                .filter(|info| !chunked.contains(&info.id))
+                .filter(|info| policy.is_due(live.get(&info.id).copied().unwrap_or(0), info.size))
+                .map(|info| info.id)
+                .collect();
+
+            // Copy in shard order, so that every old shard is read front to back
+            let mut records: Vec<(String, RecordVersion, IndexEntry)> = retained()
+                .filter(|(_, generation)| {
+                    generation.entries.len() == 1 && selected.contains(&generation.shard_id())
+                })
+                .map(|(key, generation)| (key.clone(), generation.version, generation.entries[0].clone()))
+                .collect();
+            records.sort_by_key(|(_, _, entry)| (entry.shard_id, entry.offset));
+            (selected, records)
+        };
+        if selected.is_empty() {
+            return Ok(CompactionReport::default());
+        }
+
+        let mut infos = Vec::new();
+        let mut moved = Vec::with_capacity(records.len());
+        if let Err(err) = self.copy_records(records, &mut infos, &mut moved).await {
+            self.discard_shards(infos).await?;
+            return Err(err);
+        }
+
+        let removed: Vec<ShardInfo> = manifest.shards.into_iter()
+            .filter(|info| selected.contains(&info.id))
+            .collect();
+        let retired: Vec<RetiredShard> = removed.iter().map(|info| RetiredShard::now(info.path.clone())).collect();
+        let report = CompactionReport {
+            shards_removed: removed.len(),
+            shards_written: infos.len(),
+            records_copied: moved.len(),
+            bytes_reclaimed: removed.iter().map(|info| info.size).sum::<u64>()
+                .saturating_sub(infos.iter().map(|info| info.size).sum()),
+        };
+
+        let updated = self.update_manifest(|updated| {
+            // Another writer may have compacted the same shards since the bucket was refreshed
+            if !selected.iter().all(|id| updated.shards.iter().any(|info| info.id == *id)) {
+                return Err(Error::Conflict("A shard selected for compaction was removed by another writer".into()));
+            }
+            updated.shards.retain(|info| !selected.contains(&info.id));
+            updated.shards.extend(infos.iter().cloned());
+            updated.retired.extend(retired.iter().cloned());
+            Ok(())
+        }).await;
+        if let Err(err) = updated {
+            self.discard_shards(infos).await?;
+            return Err(err);
+        }
+        self.release_claims(infos.iter().map(|info| info.id)).await?;
+
+        // Superseded generations left in the old shards are dropped with them
+        let mut index = self.index.write().await;
+        index.remove_shards(&selected);
+        for (key, generation) in moved {
+            index.insert(key, generation)?;
+        }
+        Ok(report)
+    }
+
+    /// Copies the records `compact` keeps into new shards, sealing every shard it fills.
+    ///
+    /// # Arguments
+    /// * `records` - The key, version and index entry of every record to copy.
+    /// * `infos` - Receives the description of every shard sealed, also when an error occurs.
+    /// * `moved` - Receives the generation of every record copied.
+    async fn copy_records(
+        &mut self,
+        records: Vec<(String, RecordVersion, IndexEntry)>,
+        infos: &mut Vec<ShardInfo>,
+        moved: &mut Vec<(String, Generation)>,
+    ) -> Result<()> {
+        for (key, version, entry) in records {
+            let shard_path = self.get_shard_path(entry.shard_id);
+            let block = self.provider.read_range(&shard_path, entry.offset, entry.size).await?;
+            let record = decode_record_block(block.clone(), Some(&entry.checksum))?;
+            loop {
+                let shard = self.open_shard().await?;
+                match shard.copy(&record, &block, entry.checksum, version).await {
+                    // The new shard is full; seal it and continue on another one
+                    Err(Error::ShardFull) => infos.extend(shard.seal().await?.map(|(_, info)| info)),
+                    result => {
+                        let written = result?;
+                        let entry = IndexEntry::new(shard.id(), written.offset, written.size, written.checksum);
+                        moved.push((key, Generation { version, entries: vec![entry] }));
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(shard) = self.shards.last_mut().filter(|shard| !shard.is_sealed()) {
+            infos.extend(shard.seal().await?.map(|(_, info)| info));
+        }
+        Ok(())
+    }
+
+    /// Removes the open shard and the sealed shards `infos`, none of which was published,
+    /// and releases their identifiers.
+    async fn discard_shards(&mut self, infos: Vec<ShardInfo>) -> Result<()> {
+        if let Some(shard) = self.shards.last_mut().filter(|shard| !shard.is_sealed()) {
+            shard.abort().await?;
+            let shard_id = shard.id();
+            self.shards.pop();
+            self.release_claims([shard_id]).await?;
+        }
+        for info in infos {
+            self.provider.delete(&info.path).await?;
+            self.release_claims([info.id]).await?;
+        }
+        Ok(())
+    }
+
+
+    /// Reads the data written under `key`, i.e. the content of the first file entry of its record.
+    ///
+    /// Uncompressed content of a record stored whole is returned as a slice of the buffer the
+    /// record block was read into, without being copied.
+    pub async fn read(&self, key: &str) -> Result<Bytes> {
+        Self::into_data(self.read_record(key).await?)
+    }
+
+    /// Reads the data written under `key` by the write that created generation `generation`.
+    ///
+    /// # Returns
+    /// * `Result<Bytes>` with the content of the first file entry of that generation, or an
+    ///   error if the key has no such generation, e.g. because it was deleted.
+    pub async fn read_version(&self, key: &str, generation: u64) -> Result<Bytes> {
+        Self::into_data(self.read_record_at(key, Some(generation)).await?)
+    }
+
+    /// Lists the generations of `key` that can be read, oldest first.
+    ///
+    /// # Returns
+    /// * `Result<Vec<RecordVersion>>` with the number and write time of every generation, or
+    ///   an error if the key is not found.
+    pub async fn history(&self, key: &str) -> Result<Vec<RecordVersion>> {
+        self.load_shards(|info| info.may_hold(key)).await?;
         let index = self.index.read().await;
-        let entries = index.entries.get(key)
-            .ok_or_else(|| Error::Storage("Key not found".into()))?;
- 
-        let mut data = Vec::new();
-        for entry in entries {
+        index.entries.get(key)
+            .map(|generations| generations.iter().map(|generation| generation.version).collect())
+            .ok_or_else(|| Error::Storage("Key not found".into()))
+    }
+
+    /// Reads `len` bytes of the data written under `key`, starting at `offset`.
+    ///
+    /// Only the chunks of the record holding part of the range are read, and a range may span
+    /// several chunks.
+    ///
+    /// # Returns
+    /// * `Result<Bytes>` with the requested bytes, or an error if the key is not found or the
+    ///   range exceeds the data.
+    pub async fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Bytes> {
+        let end = offset.checked_add(len)
+            .ok_or_else(|| Error::Storage("Range exceeds record data".into()))?;
+        let entry = self.read_entry_range(key, None, Some(offset..end)).await?;
+        if entry.data().len() as u64 != len {
+            return Err(Error::Storage("Range exceeds record data".into()));
+        }
+        Ok(entry.into_data())
+    }
+
+    /// Reads the record stored under `key` with all of its file entries.
+    ///
+    /// A record split across shards is read chunk by chunk and reassembled.
+    pub async fn read_record(&self, key: &str) -> Result<Record> {
+        self.read_record_at(key, None).await
+    }
+
+    /// Reads generation `generation` of the record stored under `key`, or its latest generation
+    /// if `None`.
+    async fn read_record_at(&self, key: &str, generation: Option<u64>) -> Result<Record> {
+        let mut chunks = Vec::new();
+        for entry in self.chunks(key, generation).await? {
             let shard_path = self.get_shard_path(entry.shard_id);
-            let chunk = self.provider.read(&shard_path).await?;
-            
-            let decompressed = match self.config.compression {
-                CompressionType::None => chunk,
-                CompressionType::Gzip => decompress_gzip(&chunk)?,
-                CompressionType::Lz4 => decompress_lz4(&chunk)?,
-                _ => return Err(Error::Storage("Unsupported compression".into()))
-            };
- 
-            verify_checksum(&decompressed, &entry.checksum)?;
-            data.extend_from_slice(&decompressed);
+            let block = self.provider
+                .read_range(&shard_path, entry.offset, entry.size)
+                .await?;
+            let record = decode_record_block(block, Some(&entry.checksum))?;
+            if record.key() != key {
+                return Err(Error::Index("Index entry does not point at the key".into()));
+            }
+            chunks.push(record);
         }
- 
-        Ok(data)
+        Record::join(chunks)
     }
  
-    pub async fn delete(&self, key: &str) -> Result<()> {
-        let mut index = self.index.write().await;
-        
-        if let Some(entries) = index.entries.remove(key) {
-            for entry in entries {
-                let shard_path = self.get_shard_path(entry.shard_id);
-                self.provider.delete(&shard_path).await?;
+    /// Reads a single file entry of the record stored under `key`.
+    ///
+    /// Only the record header and the content of the requested entry are fetched from
+    /// storage, so reading e.g. `meta.json` does not pull the images stored alongside it.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the record.
+    /// * `entry_name` - The name of the file entry, e.g. `meta.json`.
+    pub async fn read_entry(&self, key: &str, entry_name: &str) -> Result<FileEntry> {
+        self.read_entry_range(key, Some(entry_name), None).await
+    }
+
+    /// Lists the file entries of the record stored under `key` without reading their content.
+    ///
+    /// # Returns
+    /// * `Result<Vec<EntryInfo>>` with the name, content type and sizes of every entry, in
+    ///   write order.
+    pub async fn describe_record(&self, key: &str) -> Result<Vec<EntryInfo>> {
+        let mut infos: Vec<EntryInfo> = Vec::new();
+        for entry in self.chunks(key, None).await? {
+            let shard_path = self.get_shard_path(entry.shard_id);
+            let (header, _) = read_record_header(self.provider.as_ref(), &shard_path, key, entry.offset, entry.size)
+                .await?;
+            for (position, entry) in header.entries.iter().enumerate() {
+                match infos.last_mut() {
+                    // The first entry of a chunk continues an entry cut by the chunk boundary
+                    Some(info) if position == 0 && info.name == entry.name => {
+                        info.size += entry.raw_size;
+                        info.stored_size += entry.size;
+                    }
+                    _ => infos.push(EntryInfo::from(entry)),
+                }
             }
         }
- 
-        index.metadata.remove(key);
-        Ok(())
+        Ok(infos)
+    }
+
+    /// Deletes the record stored under `key`.
+    ///
+    /// The delete is logical: a tombstone is added to the manifest and the key is removed
+    /// from the index, while the record stays in its shards, next to the other records stored
+    /// there, until the shards are rewritten. Every generation of the key is deleted. Writing
+    /// the key again creates a new generation, which is visible.
+    ///
+    /// # Returns
+    /// * `Result<bool>` with `true` if the key was found, or an error if the manifest cannot
+    ///   be stored.
+    pub async fn delete(&self, key: &str) -> Result<bool> {
+        self.check_writable()?;
+        self.load_shards(|info| info.may_hold(key)).await?;
+        let (tombstone, pending) = {
+            let index = self.index.read().await;
+            // Generations still in the open shard are deleted too, and never published by `flush`
+            let deleted = index.deleted.get(key).copied().unwrap_or(0);
+            let pending = self.shards.last()
+                .and_then(|shard| Some((shard.generation(key)?, shard.id())))
+                .filter(|&(generation, _)| generation > deleted);
+            let stored = index.entries.get(key);
+            if stored.is_none() && pending.is_none() {
+                return Ok(false);
+            }
+            let generation = index.next_generation(key).max(pending.map_or(0, |(generation, _)| generation + 1)) - 1;
+            let shard_id = stored.into_iter()
+                .flatten()
+                .flat_map(|generation| &generation.entries)
+                .map(|entry| entry.shard_id)
+                .chain(pending.map(|(_, shard_id)| shard_id))
+                .max()
+                .unwrap_or(0);
+            (Tombstone { key: key.to_string(), shard_id, generation }, pending.is_some())
+        };
+
+        // The tombstone is durable before the key disappears from the index, which is only
+        // locked once the manifest is stored
+        self.update_manifest(|manifest| {
+            manifest.add_tombstone(key, tombstone.shard_id, tombstone.generation);
+            Ok(())
+        }).await?;
+        if pending {
+            // The generations of the open shard are renumbered by `flush` past this one
+            self.deleted_pending.lock().unwrap_or_else(PoisonError::into_inner)
+                .insert(key.to_string(), tombstone.generation);
+        }
+        self.index.write().await.apply_tombstones(&[tombstone]);
+        Ok(true)
+    }
+
+    /// Lists the keys of the records visible in the bucket that start with `prefix`.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` with the keys in sorted order, or an error if a shard that may
+    ///   hold such keys cannot be loaded.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.load_shards(|info| info.may_hold_prefix(prefix)).await?;
+        let index = self.index.read().await;
+        let mut keys: Vec<String> = index.entries.keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
     }
  
+    /// Reads the record-level metadata of the record stored under `key` from its header.
+    ///
+    /// # Returns
+    /// * `Result<Option<Vec<u8>>>` with the metadata, `None` if the record was written without
+    ///   metadata, or an error if the key is not found.
     pub async fn get_metadata(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        let index = self.index.read().await;
-        Ok(index.metadata.get(key).cloned())
+        let entry = self.chunks(key, None).await?.swap_remove(0);
+        let shard_path = self.get_shard_path(entry.shard_id);
+        let (header, _) = read_record_header(self.provider.as_ref(), &shard_path, key, entry.offset, entry.size)
+            .await?;
+        Ok(Some(header.metadata).filter(|metadata| !metadata.is_empty()))
     }
  
-    async fn get_next_shard_id(&self) -> Result<usize> {
-        // Implementation for generating unique shard IDs
-        Ok(0)
+    /// Reads the part of a file entry of the record stored under `key` within `range`.
+    ///
+    /// Only record headers and the content of the requested entry are fetched, and chunks
+    /// past the end of the range are not read at all.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the record.
+    /// * `name` - The name of the file entry, or `None` for the first entry of the record.
+    /// * `range` - The range of the decoded content to read, or `None` for all of it.
+    async fn read_entry_range(
+        &self,
+        key: &str,
+        name: Option<&str>,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<FileEntry> {
+        let mut name = name.map(str::to_string);
+        let mut found: Option<EntryHeader> = None;
+        let mut parts = Vec::new();
+        let mut position = 0;
+        for entry in self.chunks(key, None).await? {
+            if range.as_ref().is_some_and(|range| position >= range.end) && found.is_some() {
+                break;
+            }
+
+            let shard_path = self.get_shard_path(entry.shard_id);
+            let (header, payload_offset) =
+                read_record_header(self.provider.as_ref(), &shard_path, key, entry.offset, entry.size).await?;
+            let name = name.get_or_insert_with(|| {
+                header.entries.first().map(|entry| entry.name.clone()).unwrap_or_default()
+            });
+            let Some(part) = header.entry(name) else {
+                continue;
+            };
+            found.get_or_insert_with(|| part.clone());
+
+            // Parts outside the range are skipped without reading their content
+            let part_range = position..position + part.raw_size;
+            position = part_range.end;
+            let overlap = match &range {
+                Some(range) => range.start.max(part_range.start)..range.end.min(part_range.end),
+                None => part_range.clone(),
+            };
+            if overlap.is_empty() {
+                continue;
+            }
+
+            let block_end = entry.offset + entry.size;
+            let content =
+                read_entry_content(self.provider.as_ref(), &shard_path, block_end, payload_offset, part).await?;
+            let start = (overlap.start - part_range.start) as usize;
+            let end = (overlap.end - part_range.start) as usize;
+            parts.push(content.into_data().slice(start..end));
+        }
+
+        let entry = found
+            .ok_or_else(|| Error::Storage(format!("Entry {} not found", name.unwrap_or_default())))?;
+        Ok(FileEntry::new(entry.name, entry.content_type, concat(parts)).with_encoding(entry.encoding))
+    }
+
+    /// Returns the index entries of generation `generation` of the record stored under `key`,
+    /// or of its latest generation if `None`, one per chunk.
+    async fn chunks(&self, key: &str, generation: Option<u64>) -> Result<Vec<IndexEntry>> {
+        self.load_shards(|info| info.may_hold(key)).await?;
+        let index = self.index.read().await;
+        let found = match generation {
+            Some(generation) => index.generation(key, generation)
+                .ok_or_else(|| Error::Storage(format!("Generation {} of the key not found", generation))),
+            None => index.latest(key).ok_or_else(|| Error::Storage("Key not found".into())),
+        }?;
+        Some(found.entries.clone())
+            .filter(|entries| !entries.is_empty())
+            .ok_or_else(|| Error::Storage("Key not found".into()))
+    }
+
+    /// Returns the number of the generation the next write of `key` creates, following the
+    /// generations published to the index and those still in the open shard.
+    async fn next_generation(&self, key: &str) -> Result<u64> {
+        self.load_shards(|info| info.may_hold(key)).await?;
+        let next = self.index.read().await.next_generation(key);
+        let pending = self.shards.last().and_then(|shard| shard.generation(key));
+        Ok(pending.map_or(next, |pending| next.max(pending + 1)))
+    }
+
+    /// Loads the shards selected by `filter` that are not loaded yet into the index.
+    ///
+    /// The footers are read without holding the lock of the index, and the shards are only
+    /// merged if none of them was loaded or removed meanwhile, e.g. by a concurrent lookup or
+    /// `refresh`; otherwise the shards still missing are loaded again. If one of the shards
+    /// does not record the versions of its records, every shard of the bucket is loaded in
+    /// the order they were sealed, as the generations of its records depend on all of them.
+    async fn load_shards(&self, filter: impl Fn(&ShardInfo) -> bool) -> Result<()> {
+        let provider = self.provider.as_ref();
+        loop {
+            let pending: Vec<ShardInfo> = self.index.read().await.unloaded.iter()
+                .filter(|info| filter(info))
+                .cloned()
+                .collect();
+            if pending.is_empty() {
+                return Ok(());
+            }
+            let shards = pending.iter().map(|info| (info.id, info.path.clone())).collect();
+            // Tombstones are applied while building, so that records hidden by them never
+            // conflict with other records of their key
+            let first = pending.iter().map(|info| info.min_key.as_str()).min().unwrap_or_default().to_string();
+            let last = pending.iter().map(|info| info.max_key.as_str()).max().unwrap_or_default().to_string();
+            let tombstones = |manifest: &Manifest| -> Vec<Tombstone> {
+                manifest.tombstones.iter()
+                    .filter(|tombstone| first <= tombstone.key && tombstone.key <= last)
+                    .cloned()
+                    .collect()
+            };
+            let known = tombstones(&*self.manifest.read().await);
+            let Some(update) = BucketIndex::build_versioned(provider, shards, self.config.parallelism, &known).await? else {
+                return self.reload_shards().await;
+            };
+
+            let mut index = self.index.write().await;
+            if !pending.iter().all(|info| index.unloaded.contains(info)) {
+                continue;
+            }
+            index.merge(update)?;
+            index.apply_tombstones(&tombstones(&*self.manifest.read().await));
+            index.unloaded.retain(|info| !pending.contains(info));
+            return Ok(());
+        }
+    }
+
+    /// Loads every shard of the bucket into a new index, in the order they were sealed, and
+    /// replaces the index with it unless the manifest changed meanwhile.
+    async fn reload_shards(&self) -> Result<()> {
+        loop {
+            let manifest = self.manifest.read().await.clone();
+            let shards = manifest.shards.iter().map(|info| (info.id, info.path.clone())).collect();
+            let mut update = BucketIndex::build(self.provider.as_ref(), shards, self.config.parallelism, |_| {}).await?;
+            update.apply_tombstones(&manifest.tombstones);
+
+            let mut index = self.index.write().await;
+            if *self.manifest.read().await == manifest {
+                *index = update;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Builds the record `write` stores `data` in, as its single file entry.
+    fn data_record(&self, key: &str, data: &[u8], metadata: Option<Vec<u8>>) -> Record {
+        let entry = FileEntry::new(DEFAULT_ENTRY_NAME, DEFAULT_CONTENT_TYPE, data.to_vec())
+            .with_encoding(self.config.compression);
+        Record::new(key, metadata.unwrap_or_default(), vec![entry])
+    }
+
+    /// Returns the content of the first file entry of `record`.
+    fn into_data(record: Record) -> Result<Bytes> {
+        record.into_entries().into_iter().next()
+            .map(FileEntry::into_data)
+            .ok_or_else(|| Error::Storage("Record has no file entries".into()))
+    }
+
+    /// Picks the identifier of a new shard and claims it by locking the shard path, which
+    /// its writer holds until the shard is published or discarded.
+    async fn claim_shard_id(&mut self) -> Result<u64> {
+        let next = self.manifest.read().await.next_shard_id();
+        let mut shard_id = self.shards.last().map_or(next, |shard| next.max(shard.id() + 1));
+        loop {
+            // Skip identifiers taken by shards outside the manifest, e.g. kept for a snapshot or
+            // staged by a batch, including batches dropped while streaming a shard, and those
+            // other writers are filling
+            let path = self.get_shard_path(shard_id);
+            let batch_path = self.get_batch_path(shard_id);
+            if !self.exists(&path).await? && !self.exists(&batch_path).await?
+                && !self.exists(&partial_path(&batch_path)).await?
+                && let Some(claim) = self.provider.try_lock(&path).await? {
+                self.claims.insert(shard_id, claim);
+                return Ok(shard_id);
+            }
+            shard_id += 1;
+        }
+    }
+
+    /// Releases the claims on the identifiers of shards that were published or discarded.
+    async fn release_claims(&mut self, shard_ids: impl IntoIterator<Item = u64>) -> Result<()> {
+        for shard_id in shard_ids {
+            if let Some(claim) = self.claims.remove(&shard_id) {
+                claim.remove().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the identifier of the shard the object named `name` belongs to: the shard
+    /// itself, its copy staged by a batch, either being streamed, or the file backing its claim.
+    fn object_shard_id(&self, name: &str) -> Option<u64> {
+        let template = &self.config.shard_name;
+        template.id(name).or_else(|| {
+            let name = name.strip_suffix(LOCK_SUFFIX)
+                .or_else(|| name.strip_suffix(PARTIAL_SUFFIX))
+                .unwrap_or(name);
+            template.id(name.strip_suffix(BATCH_SUFFIX).unwrap_or(name))
+        })
+    }
+
+    /// Returns `true` if an object is stored at `path`.
+    async fn exists(&self, path: &Path) -> Result<bool> {
+        match self.provider.stat(path).await {
+            Ok(_) => Ok(true),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns an error if the bucket was opened at a snapshot and must not be modified.
+    fn check_writable(&self) -> Result<()> {
+        match &self.snapshot {
+            Some(snapshot) => Err(Error::Storage(format!("Bucket is opened at snapshot {} and read-only", snapshot))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the path of the manifest of the snapshot `name`.
+    fn snapshot_path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') || name.ends_with(".staging") {
+            return Err(Error::Config(format!("Invalid snapshot name `{}`", name)));
+        }
+        Ok(PathBuf::from(&self.name).join(SNAPSHOTS_DIR).join(name))
+    }
+
+    /// Returns the path of the manifest of the bucket.
+    fn manifest_path(&self) -> PathBuf {
+        std::path::PathBuf::from(&self.name).join(MANIFEST_NAME)
+    }
+
+    /// Returns the open shard, starting a new one if the last shard is sealed.
+    async fn open_shard(&mut self) -> Result<&mut Shard<Arc<P>>> {
+        if self.shards.last().is_none_or(Shard::is_sealed) {
+            // The identifier is claimed under the manifest lock, so that writers sharing the
+            // bucket never pick the same one
+            let lock = self.provider.lock(&self.manifest_path()).await?;
+            let claimed = self.claim_shard_id().await;
+            lock.release().await?;
+            let shard_id = claimed?;
+            let path = match self.staging {
+                true => self.get_batch_path(shard_id),
+                false => self.get_shard_path(shard_id),
+            };
+            self.shards.push(Shard::new(shard_id, path, Arc::clone(&self.provider), self.config.policy.max_size));
+        }
+        self.shards.last_mut()
+            .ok_or_else(|| Error::Storage("No open shard".into()))
     }
  
-    fn get_shard_path(&self, shard_id: usize) -> std::path::PathBuf {
-        std::path::PathBuf::from(&self.name).join(format!("shard_{:016x}", shard_id))
+    fn get_shard_path(&self, shard_id: u64) -> PathBuf {
+        std::path::PathBuf::from(&self.name).join(self.config.shard_name.name(shard_id))
+    }
+
+    /// Returns the path the shard `shard_id` is staged at while its batch is not committed.
+    fn get_batch_path(&self, shard_id: u64) -> PathBuf {
+        std::path::PathBuf::from(&self.name).join(self.config.shard_name.name(shard_id) + BATCH_SUFFIX)
     }
     
- }
\ No newline at end of file
+ }
+
+impl<P: StorageProvider> Batch<'_, P> {
+    /// Writes `data` under `key` with optional record-level metadata, as `Bucket::write` does.
+    pub async fn write(&mut self, key: &str, data: &[u8], metadata: Option<Vec<u8>>) -> Result<()> {
+        let record = self.bucket.data_record(key, data, metadata);
+        self.write_record(&record).await
+    }
+
+    /// Writes a record with any number of file entries to the batch.
+    ///
+    /// Every write creates a new generation of the key, numbered after the generations of the
+    /// bucket and those written earlier in the batch.
+    pub async fn write_record(&mut self, record: &Record) -> Result<()> {
+        let key = record.key();
+        let pending = self.written.get(key).map_or(0, |&(generation, _)| generation + 1);
+        let version = RecordVersion::now(self.bucket.next_generation(key).await?.max(pending));
+        let shard_id = loop {
+            let shard = self.bucket.open_shard().await?;
+            let shard_id = shard.id();
+            match shard.write(record, version).await {
+                // The open shard is full; seal it and retry on a new one
+                Err(Error::ShardFull) if !shard.is_empty() => self.seal().await?,
+                Err(Error::ShardFull) => {
+                    let (generation, infos) = self.bucket.write_chunks(record, version).await?;
+                    let shard_id = generation.entries.last().map_or(shard_id, |entry| entry.shard_id);
+                    self.infos.extend(infos);
+                    self.generations.push((key.to_string(), generation));
+                    break shard_id;
+                }
+                result => {
+                    result?;
+                    if self.bucket.is_seal_due() {
+                        self.seal().await?;
+                    }
+                    break shard_id;
+                }
+            }
+        };
+        self.written.insert(key.to_string(), (version.generation, shard_id));
+        Ok(())
+    }
+
+    /// Deletes the record stored under `key` once the batch is committed, including the
+    /// generations written earlier in the batch.
+    ///
+    /// # Returns
+    /// * `Result<bool>` with `true` if the key is visible in the bucket or was written to the
+    ///   batch since it was last deleted.
+    pub async fn delete(&mut self, key: &str) -> Result<bool> {
+        self.bucket.load_shards(|info| info.may_hold(key)).await?;
+        let (mut generation, mut shard_id) = self.written.get(key).copied().unwrap_or_default();
+        if let Some(generations) = self.bucket.index.read().await.entries.get(key) {
+            generation = generation.max(generations.last().map_or(0, |latest| latest.version.generation));
+            let stored = generations.iter().flat_map(|generation| &generation.entries).map(|entry| entry.shard_id);
+            shard_id = stored.fold(shard_id, u64::max);
+        }
+
+        let deleted = self.tombstones.iter()
+            .find(|tombstone| tombstone.key == key)
+            .map_or(0, |tombstone| tombstone.generation);
+        if generation <= deleted {
+            return Ok(false);
+        }
+        self.tombstones.retain(|tombstone| tombstone.key != key);
+        self.tombstones.push(Tombstone { key: key.to_string(), shard_id, generation });
+        Ok(true)
+    }
+
+    /// Publishes every write and delete of the batch with a single update of the manifest.
+    ///
+    /// The manifest lists the shards of the batch under their shard names before they are
+    /// renamed from their staged names, all under the lock of the manifest. If the commit is
+    /// interrupted in between, `Bucket::collect_garbage` completes the renames.
+    ///
+    /// # Returns
+    /// * `Result<()>`, or an error if the last shard of the batch cannot be sealed, in which
+    ///   case none of the batch is visible, or if the manifest cannot be stored or the shards
+    ///   cannot be renamed. The shards of the batch are removed if the manifest was not stored.
+    ///   `Error::Conflict` is returned if another writer published a generation of a key
+    ///   written by the batch since it was numbered.
+    pub async fn commit(mut self) -> Result<()> {
+        self.seal().await?;
+        let staged = std::mem::take(&mut self.infos);
+        if staged.is_empty() && self.tombstones.is_empty() {
+            return Ok(());
+        }
+        let infos: Vec<ShardInfo> = staged.iter()
+            .map(|info| ShardInfo { path: self.bucket.get_shard_path(info.id), ..info.clone() })
+            .collect();
+        let update = |manifest: &mut Manifest| {
+            manifest.shards.extend(infos.iter().cloned());
+            for tombstone in &self.tombstones {
+                manifest.add_tombstone(&tombstone.key, tombstone.shard_id, tombstone.generation);
+            }
+        };
+
+        let provider = self.bucket.provider.as_ref();
+        let manifest_path = self.bucket.manifest_path();
+        let lock = provider.lock(&manifest_path).await?;
+        let mut stored = false;
+        let published: Result<()> = async {
+            let mut manifest = Manifest::load(provider, &manifest_path).await?.unwrap_or_default();
+            self.bucket.check_generations(&manifest, &self.generations, &self.tombstones).await?;
+            update(&mut manifest);
+            manifest.store(provider, &manifest_path).await?;
+            stored = true;
+            // The identifiers were claimed when the shards were staged, so the names are free
+            for (staged, info) in staged.iter().zip(&infos) {
+                provider.rename(&staged.path, &info.path).await?;
+            }
+            Ok(())
+        }.await;
+        let released = lock.release().await;
+
+        if !stored {
+            for info in &staged {
+                provider.delete(&info.path).await?;
+            }
+            self.bucket.release_claims(staged.iter().map(|info| info.id)).await?;
+            return published.and(released);
+        }
+        update(&mut *self.bucket.manifest.write().await);
+        self.bucket.release_claims(infos.iter().map(|info| info.id)).await?;
+
+        // Records deleted later in the batch are hidden by its tombstones
+        let mut index = self.bucket.index.write().await;
+        index.apply_tombstones(&self.tombstones);
+        for (key, generation) in std::mem::take(&mut self.generations) {
+            index.insert(key, generation)?;
+        }
+        published.and(released)
+    }
+
+    /// Discards every write and delete of the batch, removing the shards written for it.
+    pub async fn abort(mut self) -> Result<()> {
+        let infos = std::mem::take(&mut self.infos);
+        self.bucket.discard_shards(infos).await
+    }
+
+    /// Seals the open shard of the batch, if any, keeping it out of the manifest until commit.
+    async fn seal(&mut self) -> Result<()> {
+        if let Some((info, generations)) = self.bucket.seal_open().await? {
+            self.infos.push(info);
+            self.generations.extend(generations);
+        }
+        Ok(())
+    }
+}
+
+impl<P: StorageProvider> Drop for Batch<'_, P> {
+    /// Leaves the bucket of a batch that was neither committed nor aborted usable again, so that
+    /// its records are not published by the next flush of the bucket.
+    ///
+    /// The shards of the batch cannot be removed without waiting for storage, so callers must
+    /// call `abort` to discard them. Shards left behind by a dropped batch keep their staged
+    /// names, and their identifiers are no longer claimed, so `Bucket::collect_garbage` removes
+    /// them.
+    fn drop(&mut self) {
+        self.bucket.staging = false;
+        let open = self.bucket.shards.pop_if(|shard| !shard.is_sealed());
+        let sealed = std::mem::take(&mut self.infos);
+        for shard_id in open.iter().map(Shard::id).chain(sealed.iter().map(|info| info.id)) {
+            self.bucket.claims.remove(&shard_id);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::shard::set::ShardSet;
+    use crate::storage::LocalStorageProvider;
+    use tempfile::TempDir;
+
+    /// Returns the names of the objects staged by batches in the test bucket.
+    fn staged_objects(root: &TempDir) -> Vec<String> {
+        std::fs::read_dir(root.path().join("test-bucket")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.contains(BATCH_SUFFIX))
+            .collect()
+    }
+
+    async fn bucket(root: &TempDir, compression: CompressionType) -> Bucket<LocalStorageProvider> {
+        bucket_with_policy(root, compression, ShardPolicy::default()).await
+    }
+
+    async fn bucket_with_policy(
+        root: &TempDir,
+        compression: CompressionType,
+        policy: ShardPolicy,
+    ) -> Bucket<LocalStorageProvider> {
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let config = BucketConfig::new(compression, 4).with_policy(policy);
+        Bucket::new("test-bucket".to_string(), Arc::new(provider), config)
+    }
+
+    #[tokio::test]
+    async fn test_write_flush_read() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+
+        bucket.write("key1", b"test data", Some(b"metadata".to_vec())).await.unwrap();
+        bucket.write("key2", b"more data", None).await.unwrap();
+        assert!(bucket.read("key1").await.is_err());
+
+        bucket.flush().await.unwrap();
+        assert_eq!(bucket.read("key1").await.unwrap(), b"test data"[..]);
+        assert_eq!(bucket.read("key2").await.unwrap(), b"more data"[..]);
+        assert_eq!(bucket.get_metadata("key1").await.unwrap(), Some(b"metadata".to_vec()));
+        assert_eq!(bucket.get_metadata("key2").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_write_record_with_entries() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+
+        let record = Record::builder("images17/image194")
+            .metadata(b"stereo".to_vec())
+            .entry("left.jpg", "image/jpeg", b"left".to_vec())
+            .entry("right.jpg", "image/jpeg", b"right".to_vec())
+            .entry_with_encoding("meta.json", "application/json", CompressionType::Lz4, b"{}".to_vec())
+            .build();
+        bucket.write_record(&record).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        let read = bucket.read_record("images17/image194").await.unwrap();
+        assert_eq!(read, record);
+        assert_eq!(read.entries().len(), 3);
+        let meta = read.entry("meta.json").unwrap();
+        assert_eq!(meta.content_type(), "application/json");
+        assert_eq!(meta.encoding(), CompressionType::Lz4);
+        assert_eq!(meta.data(), b"{}");
+        assert_eq!(bucket.read("images17/image194").await.unwrap(), b"left"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_and_describe_record() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+
+        let image = vec![7u8; 64 * 1024];
+        let record = Record::builder("images17/image194")
+            .entry("left.jpg", "image/jpeg", image.clone())
+            .entry_with_encoding("meta.json", "application/json", CompressionType::Gzip, b"{\"w\":64}".to_vec())
+            .build();
+        bucket.write_record(&record).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        let meta = bucket.read_entry("images17/image194", "meta.json").await.unwrap();
+        assert_eq!(meta.data(), b"{\"w\":64}");
+        assert_eq!(meta.encoding(), CompressionType::Gzip);
+        let left = bucket.read_entry("images17/image194", "left.jpg").await.unwrap();
+        assert_eq!(left.data(), image.as_slice());
+        assert!(bucket.read_entry("images17/image194", "right.jpg").await.is_err());
+        assert!(bucket.read_entry("missing", "meta.json").await.is_err());
+
+        let entries = bucket.describe_record("images17/image194").await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "left.jpg");
+        assert_eq!(entries[0].content_type, "image/jpeg");
+        assert_eq!(entries[0].size, image.len() as u64);
+        assert_eq!(entries[0].stored_size, image.len() as u64);
+        assert_eq!(entries[1].name, "meta.json");
+        assert_eq!(entries[1].encoding, CompressionType::Gzip);
+        assert_eq!(entries[1].size, 8);
+    }
+
+    #[tokio::test]
+    async fn test_flush_starts_new_shard() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::Gzip).await;
+
+        bucket.write("key1", b"first", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.write("key2", b"second", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.flush().await.unwrap();
+
+        assert_eq!(bucket.shards.len(), 2);
+        assert!(root.path().join("test-bucket/shard_0000000000000000").exists());
+        assert!(root.path().join("test-bucket/shard_0000000000000001").exists());
+        assert_eq!(bucket.read("key1").await.unwrap(), b"first"[..]);
+        assert_eq!(bucket.read("key2").await.unwrap(), b"second"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_write_record_larger_than_shard() {
+        let root = TempDir::new().unwrap();
+        let policy = ShardPolicy::default().with_max_size(16 * 1024);
+        let mut bucket = bucket_with_policy(&root, CompressionType::Lz4, policy).await;
+
+        // Incompressible content, so that compression cannot make it fit
+        let video: Vec<u8> = (0..100_000)
+            .scan(0x2545f4914f6cdd1du64, |state, _| {
+                *state ^= *state << 13;
+                *state ^= *state >> 7;
+                *state ^= *state << 17;
+                Some(*state as u8)
+            })
+            .collect();
+        bucket.write("small", b"before", None).await.unwrap();
+        bucket.write("video", &video, Some(b"long".to_vec())).await.unwrap();
+        bucket.write("after", b"after", None).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        // The video is split over consecutive shards of its own
+        let chunks = bucket.chunks("video", None).await.unwrap();
+        assert!(chunks.len() > 5);
+        assert!(chunks.windows(2).all(|pair| pair[1].shard_id == pair[0].shard_id + 1));
+        assert_eq!(bucket.read("video").await.unwrap(), video);
+        assert_eq!(bucket.read("small").await.unwrap(), b"before"[..]);
+        assert_eq!(bucket.read("after").await.unwrap(), b"after"[..]);
+        assert_eq!(bucket.get_metadata("video").await.unwrap(), Some(b"long".to_vec()));
+
+        let info = bucket.describe_record("video").await.unwrap();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].size, video.len() as u64);
+
+        // Ranged reads within one chunk and across chunk boundaries
+        assert_eq!(bucket.read_range("video", 10, 100).await.unwrap(), video[10..110]);
+        assert_eq!(bucket.read_range("video", 15_000, 40_000).await.unwrap(), video[15_000..55_000]);
+        assert_eq!(bucket.read_range("video", 99_990, 10).await.unwrap(), video[99_990..]);
+        assert!(bucket.read_range("video", 99_990, 11).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_policy_seals_by_record_count_and_age() {
+        let root = TempDir::new().unwrap();
+        let policy = ShardPolicy::default().with_max_records(2);
+        let mut bucket = bucket_with_policy(&root, CompressionType::None, policy).await;
+
+        for key in ["key1", "key2", "key3"] {
+            bucket.write(key, key.as_bytes(), None).await.unwrap();
+        }
+        // The first shard was sealed after two records, without a flush
+        assert_eq!(bucket.shards.len(), 2);
+        assert_eq!(bucket.read("key2").await.unwrap(), b"key2"[..]);
+        assert!(bucket.read("key3").await.is_err());
+
+        let root = TempDir::new().unwrap();
+        let policy = ShardPolicy::default().with_max_open(std::time::Duration::from_millis(20));
+        let mut bucket = bucket_with_policy(&root, CompressionType::None, policy).await;
+
+        bucket.write("key1", b"streamed", None).await.unwrap();
+        assert!(!bucket.seal_if_due().await.unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        assert!(bucket.seal_if_due().await.unwrap());
+        assert_eq!(bucket.read("key1").await.unwrap(), b"streamed"[..]);
+        assert!(!bucket.seal_if_due().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_open_from_manifest() {
+        let root = TempDir::new().unwrap();
+        let policy = ShardPolicy::default().with_max_size(16 * 1024);
+        let mut bucket = bucket_with_policy(&root, CompressionType::None, policy.clone()).await;
+
+        let large: Vec<u8> = (0..40_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        bucket.write("key1", b"first", Some(b"meta".to_vec())).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.write("key1", b"second", None).await.unwrap();
+        bucket.write("large", &large, Some(b"chunked".to_vec())).await.unwrap();
+        bucket.write("unsealed", b"lost", None).await.unwrap();
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4).with_policy(policy);
+        let mut reopened = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone())
+            .await
+            .unwrap();
+        let manifest = reopened.manifest().await;
+        assert_eq!(manifest, bucket.manifest().await);
+        assert!(manifest.shards.len() > 3);
+        assert_eq!(manifest.records(), manifest.shards.len() as u64);
+        assert_eq!(manifest.shards[0].min_key, "key1");
+
+        // Shards are loaded once a key within their key range is looked up
+        assert_eq!(reopened.index.read().await.unloaded.len(), manifest.shards.len());
+        assert_eq!(reopened.read("key1").await.unwrap(), b"second"[..]);
+        let unloaded = reopened.index.read().await.unloaded.clone();
+        assert_eq!(unloaded.len(), manifest.shards.len() - 2);
+        assert!(unloaded.iter().all(|info| info.min_key == "large" && info.max_key == "large"));
+        assert_eq!(reopened.get_metadata("key1").await.unwrap(), None);
+        assert_eq!(reopened.read("large").await.unwrap(), large);
+        assert_eq!(reopened.get_metadata("large").await.unwrap(), Some(b"chunked".to_vec()));
+        assert!(reopened.read("unsealed").await.is_err());
+
+        // New shards continue after the shards of the manifest and the shard still open in `bucket`
+        reopened.write("key2", b"more", None).await.unwrap();
+        reopened.flush().await.unwrap();
+        assert_eq!(reopened.manifest().await.next_shard_id(), manifest.next_shard_id() + 2);
+
+        let template = ShardNameTemplate::new("other-{id}").unwrap();
+        let other = config.clone().with_shard_name(template);
+        assert!(Bucket::open("test-bucket".to_string(), Arc::clone(&provider), other).await.is_err());
+        assert!(Bucket::open("missing".to_string(), provider, config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_recover_without_manifest() {
+        let root = TempDir::new().unwrap();
+        let policy = ShardPolicy::default().with_max_size(16 * 1024);
+        let mut bucket = bucket_with_policy(&root, CompressionType::None, policy.clone()).await;
+
+        let large: Vec<u8> = (0..40_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        bucket.write("key1", b"first", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.write("key1", b"second", None).await.unwrap();
+        bucket.write("large", &large, None).await.unwrap();
+        bucket.flush().await.unwrap();
+        let manifest = bucket.manifest().await;
+        std::fs::remove_file(root.path().join("test-bucket").join(MANIFEST_NAME)).unwrap();
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4).with_policy(policy);
+        assert!(Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone()).await.is_err());
+
+        let mut shards = 0;
+        let recovered = Bucket::recover("test-bucket".to_string(), Arc::clone(&provider), config.clone(), |progress| {
+            shards = progress.shards
+        })
+        .await
+        .unwrap();
+        assert_eq!(shards, manifest.shards.len());
+        assert_eq!(recovered.manifest().await, manifest);
+        assert_eq!(recovered.read("key1").await.unwrap(), b"second"[..]);
+        assert_eq!(recovered.read("large").await.unwrap(), large);
+
+        // The recovered manifest is stored, so the bucket opens again
+        let reopened = Bucket::open("test-bucket".to_string(), provider, config).await.unwrap();
+        assert_eq!(reopened.read("large").await.unwrap(), large);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_picks_up_other_writers() {
+        let root = TempDir::new().unwrap();
+        let mut writer = bucket(&root, CompressionType::None).await;
+        writer.write("key1", b"first", None).await.unwrap();
+        writer.flush().await.unwrap();
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let reader = Bucket::open("test-bucket".to_string(), provider, config).await.unwrap();
+        assert!(!reader.refresh().await.unwrap());
+
+        writer.write("key1", b"second", None).await.unwrap();
+        writer.write("key2", b"new", None).await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(reader.read("key1").await.unwrap(), b"first"[..]);
+        assert!(reader.read("key2").await.is_err());
+
+        assert!(reader.refresh().await.unwrap());
+        assert_eq!(reader.read("key1").await.unwrap(), b"second"[..]);
+        assert_eq!(reader.read("key2").await.unwrap(), b"new"[..]);
+        assert_eq!(reader.manifest().await, writer.manifest().await);
+
+        // A shard dropped from the manifest takes its records with it
+        let mut manifest = writer.manifest().await;
+        manifest.shards.remove(1);
+        manifest.store(writer.provider.as_ref(), &writer.manifest_path()).await.unwrap();
+        assert!(reader.refresh().await.unwrap());
+        assert_eq!(reader.read("key1").await.unwrap(), b"first"[..]);
+        assert!(reader.read("key2").await.is_err());
+        assert_eq!(reader.manifest().await.shards.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writers_keep_each_others_shards() {
+        let root = TempDir::new().unwrap();
+        let mut initial = bucket(&root, CompressionType::None).await;
+        initial.write("initial", b"0", None).await.unwrap();
+        initial.flush().await.unwrap();
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let name = "test-bucket".to_string();
+        let mut a = Bucket::open(name.clone(), Arc::clone(&provider), config.clone()).await.unwrap();
+        let mut b = Bucket::open(name.clone(), Arc::clone(&provider), config.clone()).await.unwrap();
+
+        // Both open a shard before either is sealed; they get different identifiers
+        a.write("from-a", b"a", None).await.unwrap();
+        b.write("from-b", b"b", None).await.unwrap();
+        b.delete("initial").await.unwrap();
+        // Shards being written are claimed, so garbage collection leaves them alone
+        assert_eq!(a.collect_garbage(Duration::ZERO).await.unwrap(), 0);
+        a.flush().await.unwrap();
+        b.flush().await.unwrap();
+
+        let reopened = Bucket::open(name, provider, config).await.unwrap();
+        assert_eq!(reopened.list("").await.unwrap(), ["from-a", "from-b"]);
+        assert_eq!(reopened.manifest().await.shards.len(), 3);
+        assert_eq!(a.collect_garbage(Duration::ZERO).await.unwrap(), 0);
+
+        assert_eq!(a.list("").await.unwrap(), ["from-a", "initial"]);
+        assert!(a.refresh().await.unwrap());
+        assert_eq!(a.list("").await.unwrap(), ["from-a", "from-b"]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writers_number_generations_apart() {
+        let root = TempDir::new().unwrap();
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let name = "test-bucket".to_string();
+        let mut a = Bucket::new(name.clone(), Arc::clone(&provider), config.clone());
+        let mut b = Bucket::new(name.clone(), Arc::clone(&provider), config.clone());
+
+        // Both number their write of the key 1; the second to flush moves past the first
+        a.write("key", b"from-a", None).await.unwrap();
+        b.write("key", b"from-b", None).await.unwrap();
+        a.flush().await.unwrap();
+        b.flush().await.unwrap();
+        let reopened = Bucket::open(name.clone(), Arc::clone(&provider), config.clone()).await.unwrap();
+        let history = reopened.history("key").await.unwrap();
+        assert_eq!(history.iter().map(|version| version.generation).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(reopened.read("key").await.unwrap(), b"from-b"[..]);
+        assert_eq!(reopened.read_version("key", 1).await.unwrap(), b"from-a"[..]);
+
+        // A write numbered before another writer deleted the key is not hidden by its tombstone
+        a.write("gone", b"deleted", None).await.unwrap();
+        a.flush().await.unwrap();
+        a.delete("gone").await.unwrap();
+        b.write("gone", b"written", None).await.unwrap();
+        b.flush().await.unwrap();
+        let reopened = Bucket::open(name.clone(), Arc::clone(&provider), config.clone()).await.unwrap();
+        assert_eq!(reopened.read("gone").await.unwrap(), b"written"[..]);
+        assert_eq!(reopened.history("gone").await.unwrap()[0].generation, 2);
+        assert!(a.refresh().await.unwrap());
+        assert_eq!(a.read("gone").await.unwrap(), b"written"[..]);
+
+        // Sealed shards cannot be renumbered, so a batch numbered too early fails to commit
+        let mut batch = b.begin_batch().await.unwrap();
+        batch.write("key", b"batched", None).await.unwrap();
+        a.write("key", b"flushed", None).await.unwrap();
+        a.flush().await.unwrap();
+        assert!(matches!(batch.commit().await, Err(Error::Conflict(_))));
+        assert!(staged_objects(&root).is_empty());
+        assert!(b.refresh().await.unwrap());
+        assert_eq!(b.read("key").await.unwrap(), b"flushed"[..]);
+        assert_eq!(b.history("key").await.unwrap().last().unwrap().generation, 3);
+    }
+
+    #[tokio::test]
+    async fn test_delete_writes_tombstone() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+        bucket.write("images/1", b"one", None).await.unwrap();
+        bucket.write("images/2", b"two", None).await.unwrap();
+        bucket.write("labels/1", b"cat", None).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        assert!(bucket.delete("images/1").await.unwrap());
+        assert!(!bucket.delete("images/1").await.unwrap());
+        assert!(bucket.read("images/1").await.is_err());
+        assert_eq!(bucket.list("images/").await.unwrap(), ["images/2"]);
+
+        // Other records of the shard are untouched
+        assert!(root.path().join("test-bucket/shard_0000000000000000").exists());
+        assert_eq!(bucket.read("images/2").await.unwrap(), b"two"[..]);
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let reopened = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone())
+            .await
+            .unwrap();
+        assert_eq!(reopened.list("").await.unwrap(), ["images/2", "labels/1"]);
+
+        // Writing the key again makes it visible, also after reopening
+        bucket.write("images/1", b"again", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        assert_eq!(bucket.read("images/1").await.unwrap(), b"again"[..]);
+        assert!(reopened.refresh().await.unwrap());
+        assert_eq!(reopened.read("images/1").await.unwrap(), b"again"[..]);
+        let reopened = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone())
+            .await
+            .unwrap();
+        assert_eq!(reopened.read("images/1").await.unwrap(), b"again"[..]);
+
+        // Records still in the open shard are deleted as well
+        bucket.write("pending", b"new", None).await.unwrap();
+        assert!(bucket.delete("pending").await.unwrap());
+        assert!(!bucket.delete("pending").await.unwrap());
+        bucket.write("images/2", b"updated", None).await.unwrap();
+        assert!(bucket.delete("images/2").await.unwrap());
+        bucket.flush().await.unwrap();
+        assert!(bucket.read("pending").await.is_err());
+        assert!(bucket.read("images/2").await.is_err());
+        assert!(bucket.read_version("images/2", 2).await.is_err());
+
+        let reopened = Bucket::open("test-bucket".to_string(), provider, config).await.unwrap();
+        assert_eq!(reopened.list("").await.unwrap(), ["images/1", "labels/1"]);
+        bucket.write("images/2", b"third", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        assert_eq!(bucket.history("images/2").await.unwrap()[0].generation, 3);
+    }
+
+    #[tokio::test]
+    async fn test_compact_rewrites_sparse_shards() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+        let sample = vec![3u8; 1024];
+        for key in ["key1", "key2", "key3", "key4"] {
+            bucket.write(key, &sample, None).await.unwrap();
+        }
+        bucket.flush().await.unwrap();
+        bucket.write("dense", &sample, None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.write("key1", b"updated", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.delete("key2").await.unwrap();
+        bucket.delete("key3").await.unwrap();
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let reader = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone()).await.unwrap();
+
+        // Only the first shard is less than half live; the first generation of key1 is kept
+        let report = bucket.compact(&CompactionPolicy::default()).await.unwrap();
+        assert_eq!(report.shards_removed, 1);
+        assert_eq!(report.shards_written, 1);
+        assert_eq!(report.records_copied, 2);
+        assert!(report.bytes_reclaimed > 2 * 1024);
+        assert_eq!(bucket.manifest().await.tombstones.len(), 2);
+
+        // The old shard is retired, so a reader opened before compaction keeps reading it
+        let old_shard = root.path().join("test-bucket/shard_0000000000000000");
+        assert_eq!(bucket.manifest().await.retired.len(), 1);
+        assert_eq!(reader.read("key4").await.unwrap(), sample);
+        assert_eq!(bucket.collect_garbage(Duration::from_secs(3600)).await.unwrap(), 0);
+        assert!(old_shard.exists());
+        assert!(reader.refresh().await.unwrap());
+        assert_eq!(reader.read("key4").await.unwrap(), sample);
+        assert_eq!(bucket.collect_garbage(Duration::ZERO).await.unwrap(), 1);
+        assert!(!old_shard.exists());
+        assert!(bucket.manifest().await.retired.is_empty());
+        assert_eq!(reader.read_version("key1", 1).await.unwrap(), sample);
+
+        assert_eq!(bucket.read("key1").await.unwrap(), b"updated"[..]);
+        assert_eq!(bucket.read_version("key1", 1).await.unwrap(), sample);
+        assert_eq!(bucket.read("key4").await.unwrap(), sample);
+        assert_eq!(bucket.list("").await.unwrap(), ["dense", "key1", "key4"]);
+        assert_eq!(bucket.compact(&CompactionPolicy::default()).await.unwrap(), CompactionReport::default());
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let mut reopened = Bucket::open("test-bucket".to_string(), provider, config).await.unwrap();
+        assert_eq!(reopened.list("").await.unwrap(), ["dense", "key1", "key4"]);
+        assert_eq!(reopened.read("key4").await.unwrap(), sample);
+
+        // The tombstones outlive the records they hid, so generations of deleted keys go on
+        reopened.write("key2", b"again", None).await.unwrap();
+        reopened.flush().await.unwrap();
+        assert_eq!(reopened.history("key2").await.unwrap()[0].generation, 2);
+        assert_eq!(reopened.read_version("key2", 2).await.unwrap(), b"again"[..]);
+        assert!(reopened.read_version("key2", 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compact_leaves_bucket_unchanged_on_error() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+        let sample = vec![5u8; 1024];
+        for key in ["key1", "key2", "key3", "key4", "key5"] {
+            bucket.write(key, &sample, None).await.unwrap();
+        }
+        bucket.flush().await.unwrap();
+        for key in ["key2", "key3", "key4"] {
+            bucket.delete(key).await.unwrap();
+        }
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let mut stale = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config).await.unwrap();
+        stale.read("key5").await.unwrap();
+
+        // A record failing its checksum stops the compaction after copying key1, before the
+        // manifest is touched
+        let entry = bucket.index.read().await.latest("key5").unwrap().entries[0].clone();
+        let path = root.path().join("test-bucket/shard_0000000000000000");
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[(entry.offset + entry.size - 1) as usize] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let manifest = bucket.manifest().await;
+        assert!(bucket.compact(&CompactionPolicy::default()).await.is_err());
+        bucket.flush().await.unwrap();
+        assert_eq!(bucket.manifest().await, manifest);
+        assert_eq!(Manifest::load(provider.as_ref(), &bucket.manifest_path()).await.unwrap(), Some(manifest));
+        let mut names: Vec<String> = std::fs::read_dir(root.path().join("test-bucket")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["MANIFEST", "MANIFEST.lock", "shard_0000000000000000"]);
+
+        // A writer that has not seen a compaction selects from the manifest after it
+        bytes[(entry.offset + entry.size - 1) as usize] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(bucket.compact(&CompactionPolicy::default()).await.unwrap().records_copied, 2);
+        assert_eq!(stale.compact(&CompactionPolicy::default()).await.unwrap(), CompactionReport::default());
+        assert_eq!(stale.manifest().await, bucket.manifest().await);
+        assert_eq!(stale.list("").await.unwrap(), ["key1", "key5"]);
+        assert_eq!(stale.read("key5").await.unwrap(), sample);
+    }
+
+    #[tokio::test]
+    async fn test_compact_drops_superseded_generations() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+        let sample = vec![5u8; 1024];
+        bucket.write("label", &sample, None).await.unwrap();
+        bucket.write("label", b"corrected", None).await.unwrap();
+        bucket.write("other", &sample, None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.write("label", b"final", None).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        // Every generation is live by default
+        assert_eq!(bucket.compact(&CompactionPolicy::default()).await.unwrap(), CompactionReport::default());
+
+        let policy = CompactionPolicy::default().with_keep_generations(2);
+        let report = bucket.compact(&policy).await.unwrap();
+        assert_eq!(report.shards_removed, 1);
+        assert_eq!(report.records_copied, 2);
+        assert!(report.bytes_reclaimed > 1024);
+        assert!(bucket.read_version("label", 1).await.is_err());
+        assert_eq!(bucket.read_version("label", 2).await.unwrap(), b"corrected"[..]);
+        assert_eq!(bucket.read("label").await.unwrap(), b"final"[..]);
+        assert_eq!(bucket.history("label").await.unwrap().len(), 2);
+        assert_eq!(bucket.read("other").await.unwrap(), sample);
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let reopened = Bucket::open("test-bucket".to_string(), provider, config).await.unwrap();
+        assert!(reopened.read_version("label", 1).await.is_err());
+        assert_eq!(reopened.history("label").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_survives_delete_and_compaction() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+        let sample = vec![5u8; 1024];
+        for key in ["a", "b", "c"] {
+            bucket.write(key, &sample, None).await.unwrap();
+        }
+        bucket.flush().await.unwrap();
+        bucket.snapshot("exp1").await.unwrap();
+        assert!(bucket.snapshot("exp1").await.is_err());
+        assert!(matches!(bucket.snapshot("../exp1").await, Err(Error::Config(_))));
+
+        // The only shard is compacted away but kept in storage for the snapshot
+        for key in ["a", "b", "c"] {
+            bucket.delete(key).await.unwrap();
+        }
+        let report = bucket.compact(&CompactionPolicy::default()).await.unwrap();
+        assert_eq!(report.shards_removed, 1);
+        assert!(bucket.manifest().await.shards.is_empty());
+        let kept = root.path().join("test-bucket/shard_0000000000000000");
+        assert!(kept.exists());
+
+        // New shards do not reuse the identifier of the kept shard
+        bucket.write("d", b"new", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        assert_eq!(bucket.manifest().await.shards[0].id, 1);
+        assert_eq!(bucket.snapshots().await.unwrap(), ["exp1"]);
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let mut old = Bucket::open_at("test-bucket".to_string(), provider, config, "exp1").await.unwrap();
+        assert_eq!(old.list("").await.unwrap(), ["a", "b", "c"]);
+        assert_eq!(old.read("b").await.unwrap(), sample);
+        assert!(old.write("e", b"e", None).await.is_err());
+        assert!(old.delete("a").await.is_err());
+        assert!(!old.refresh().await.unwrap());
+
+        assert_eq!(bucket.collect_garbage(Duration::ZERO).await.unwrap(), 0);
+        bucket.drop_snapshot("exp1").await.unwrap();
+        assert!(bucket.snapshots().await.unwrap().is_empty());
+        assert_eq!(bucket.collect_garbage(Duration::ZERO).await.unwrap(), 1);
+        assert!(!kept.exists());
+        assert_eq!(bucket.read("d").await.unwrap(), b"new"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_commits_atomically() {
+        let root = TempDir::new().unwrap();
+        let policy = ShardPolicy::default().with_max_size(16 * 1024);
+        let mut bucket = bucket_with_policy(&root, CompressionType::None, policy.clone()).await;
+        let sample = vec![9u8; 6 * 1024];
+        bucket.write("a", b"first", None).await.unwrap();
+        bucket.write("pending", b"flushed", None).await.unwrap();
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4).with_policy(policy);
+        let mut batch = bucket.begin_batch().await.unwrap();
+        for key in ["b", "c", "d", "e"] {
+            batch.write(key, &sample, None).await.unwrap();
+        }
+        assert!(batch.delete("a").await.unwrap());
+        assert!(batch.delete("b").await.unwrap());
+        assert!(!batch.delete("b").await.unwrap());
+        assert!(!batch.delete("missing").await.unwrap());
+        batch.write("a", b"second", None).await.unwrap();
+
+        // The batch has rolled over several shards, none of which is visible yet
+        let reader = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone()).await.unwrap();
+        assert_eq!(reader.list("").await.unwrap(), ["a", "pending"]);
+        assert_eq!(reader.manifest().await.shards.len(), 1);
+        assert!(!staged_objects(&root).is_empty());
+
+        batch.commit().await.unwrap();
+        assert!(staged_objects(&root).is_empty());
+        assert_eq!(bucket.list("").await.unwrap(), ["a", "c", "d", "e", "pending"]);
+        assert_eq!(bucket.read("a").await.unwrap(), b"second"[..]);
+        assert_eq!(bucket.history("a").await.unwrap().iter().map(|v| v.generation).collect::<Vec<_>>(), [2]);
+        assert!(reader.refresh().await.unwrap());
+        assert_eq!(reader.list("").await.unwrap(), ["a", "c", "d", "e", "pending"]);
+        assert_eq!(reader.read("e").await.unwrap(), sample);
+
+        // A commit interrupted after storing the manifest is completed by garbage collection
+        let info = bucket.manifest().await.shards.pop().unwrap();
+        provider.rename(&info.path, &bucket.get_batch_path(info.id)).await.unwrap();
+        let stale = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone()).await.unwrap();
+        assert!(stale.list("").await.is_err());
+        assert_eq!(bucket.collect_garbage(Duration::ZERO).await.unwrap(), 0);
+        assert!(staged_objects(&root).is_empty());
+        let reopened = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone()).await.unwrap();
+        assert_eq!(reopened.list("").await.unwrap(), ["a", "c", "d", "e", "pending"]);
+
+        // Aborted and dropped batches leave nothing visible and nothing to collect
+        let manifest = bucket.manifest().await;
+        let mut batch = bucket.begin_batch().await.unwrap();
+        for key in ["x", "y", "z"] {
+            batch.write(key, &sample, None).await.unwrap();
+        }
+        batch.delete("c").await.unwrap();
+        batch.abort().await.unwrap();
+        assert_eq!(bucket.manifest().await, manifest);
+        assert_eq!(bucket.collect_garbage(Duration::ZERO).await.unwrap(), 0);
+
+        let mut batch = bucket.begin_batch().await.unwrap();
+        for key in ["dropped", "lost"] {
+            batch.write(key, &sample, None).await.unwrap();
+        }
+        drop(batch);
+        bucket.write("f", b"kept", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        assert!(bucket.read("dropped").await.is_err());
+        assert_eq!(bucket.read("c").await.unwrap(), sample);
+        // The shards of the dropped batch are left to garbage collection
+        assert!(!staged_objects(&root).is_empty());
+        assert_eq!(bucket.collect_garbage(Duration::ZERO).await.unwrap(), 1);
+        assert!(staged_objects(&root).is_empty());
+
+        let reopened = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone()).await.unwrap();
+        assert_eq!(reopened.list("").await.unwrap(), ["a", "c", "d", "e", "f", "pending"]);
+
+        // A batch interrupted by a crash leaves a partial shard that recovery ignores
+        let mut crashed = Bucket::open("test-bucket".to_string(), Arc::clone(&provider), config.clone()).await.unwrap();
+        let mut batch = crashed.begin_batch().await.unwrap();
+        batch.write("crashed", b"lost", None).await.unwrap();
+        std::mem::forget(batch);
+        drop(crashed);
+        assert!(staged_objects(&root).iter().all(|name| name.ends_with(".partial")));
+        let recovered = Bucket::recover("test-bucket".to_string(), provider, config, |_| {}).await.unwrap();
+        assert!(!recovered.list("").await.unwrap().contains(&"crashed".to_string()));
+        assert_eq!(recovered.collect_garbage(Duration::ZERO).await.unwrap(), 1);
+        assert!(staged_objects(&root).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_generations_and_history() {
+        let root = TempDir::new().unwrap();
+        let mut bucket = bucket(&root, CompressionType::None).await;
+        bucket.write("labels/17", b"cat", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.write("labels/17", b"dog", None).await.unwrap();
+        bucket.write("labels/17", b"wolf", None).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        assert_eq!(bucket.read("labels/17").await.unwrap(), b"wolf"[..]);
+        assert_eq!(bucket.read_version("labels/17", 1).await.unwrap(), b"cat"[..]);
+        assert_eq!(bucket.read_version("labels/17", 2).await.unwrap(), b"dog"[..]);
+        assert!(bucket.read_version("labels/17", 4).await.is_err());
+        let history = bucket.history("labels/17").await.unwrap();
+        assert_eq!(history.iter().map(|version| version.generation).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(history.windows(2).all(|pair| pair[0].time() <= pair[1].time()));
+        assert!(history[0].time() > std::time::UNIX_EPOCH);
+
+        // Generations survive reopening, and continue after a delete
+        bucket.delete("labels/17").await.unwrap();
+        assert!(bucket.history("labels/17").await.is_err());
+        bucket.write("labels/17", b"fox", None).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let config = BucketConfig::new(CompressionType::None, 4);
+        let reopened = Bucket::open("test-bucket".to_string(), provider, config).await.unwrap();
+        assert_eq!(reopened.history("labels/17").await.unwrap()[0].generation, 4);
+        assert_eq!(reopened.read("labels/17").await.unwrap(), b"fox"[..]);
+        assert!(reopened.read_version("labels/17", 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shard_name_template() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let template = ShardNameTemplate::new("dataset-train-{id:06}.shardpack").unwrap();
+        let config = BucketConfig::new(CompressionType::None, 4).with_shard_name(template);
+        let mut bucket = Bucket::new("test-bucket".to_string(), Arc::new(provider), config);
+
+        bucket.write("key1", b"first", None).await.unwrap();
+        bucket.flush().await.unwrap();
+        bucket.write("key2", b"second", None).await.unwrap();
+        bucket.flush().await.unwrap();
+
+        let set = ShardSet::parse("test-bucket/dataset-train-{000000..000001}.shardpack").unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        set.validate(&provider).await.unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use crate::error::Error;
+use crate::types::Result;
+
+/// A structure with an explicit binary encoding.
+///
+/// Every on-disk structure is encoded field by field in declaration order, without padding:
+/// integers are fixed-width little-endian, strings and byte strings are prefixed with their
+/// length as a `u64`, sequences are prefixed with their element count as a `u64`, and
+/// fixed-size arrays are stored as-is. The bytes therefore do not depend on the platform or
+/// on any serialization library, and can be parsed by readers written in other languages.
+pub(crate) trait Encode {
+    /// Appends the encoding of `self` to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Returns the encoding of `self`.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+/// A structure that can be decoded from the encoding produced by its `Encode` implementation.
+pub(crate) trait Decode: Sized {
+    /// Decodes a value from the current position of `decoder`.
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self>;
+
+    /// Decodes a value that spans exactly `bytes`.
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(bytes);
+        let value = Self::decode(&mut decoder)?;
+        decoder.finish()?;
+        Ok(value)
+    }
+}
+
+/// Appends a `u32` in little-endian order.
+pub(crate) fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Appends a `u64` in little-endian order.
+pub(crate) fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Appends a byte string prefixed with its length.
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Appends a UTF-8 string prefixed with its length in bytes.
+pub(crate) fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_bytes(buf, value.as_bytes());
+}
+
+/// Appends a sequence prefixed with its element count.
+pub(crate) fn put_seq<T: Encode>(buf: &mut Vec<u8>, values: &[T]) {
+    put_u64(buf, values.len() as u64);
+    for value in values {
+        value.encode(buf);
+    }
+}
+
+/// Reads values encoded with the `put_*` functions from a byte slice.
+///
+/// Every read checks the remaining length, so truncated or corrupt input results in a
+/// `Error::Format` rather than a panic or an oversized allocation.
+pub(crate) struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a decoder reading `bytes` from the start.
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    /// Returns the number of bytes not read yet.
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    /// Checks that every byte has been read.
+    pub(crate) fn finish(&self) -> Result<()> {
+        match self.remaining() {
+            0 => Ok(()),
+            n => Err(Error::Format(format!("{} trailing bytes after encoded value", n))),
+        }
+    }
+
+    /// Reads the next `len` bytes.
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(Error::Format("Unexpected end of encoded value".into()));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Reads a fixed-size array.
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    /// Reads a little-endian `u32`.
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    /// Reads a little-endian `u64`.
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Reads a length-prefixed byte string.
+    pub(crate) fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Reads a length-prefixed UTF-8 string.
+    pub(crate) fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?)
+            .map_err(|_| Error::Format("Invalid UTF-8 in encoded string".into()))
+    }
+
+    /// Reads a sequence prefixed with its element count.
+    pub(crate) fn seq<T: Decode>(&mut self) -> Result<Vec<T>> {
+        let count = self.u64()?;
+        // Every element takes at least one byte; don't trust the count for the allocation
+        let mut values = Vec::with_capacity((count as usize).min(self.remaining()));
+        for _ in 0..count {
+            values.push(T::decode(self)?);
+        }
+        Ok(values)
+    }
+
+    /// Reads a `u64` length and checks that that many bytes remain.
+    fn len(&mut self) -> Result<usize> {
+        let len = self.u64()?;
+        usize::try_from(len).ok()
+            .filter(|&len| len <= self.remaining())
+            .ok_or_else(|| Error::Format("Encoded length exceeds input".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Pair {
+        name: String,
+        value: u64,
+    }
+
+    impl Encode for Pair {
+        fn encode(&self, buf: &mut Vec<u8>) {
+            put_str(buf, &self.name);
+            put_u64(buf, self.value);
+        }
+    }
+
+    impl Decode for Pair {
+        fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+            Ok(Self { name: decoder.string()?, value: decoder.u64()? })
+        }
+    }
+
+    #[test]
+    fn test_encoding_is_little_endian_and_length_prefixed() {
+        let mut buf = Vec::new();
+        put_u32(&mut buf, 7);
+        put_seq(&mut buf, &[Pair { name: "ab".into(), value: 0x0102 }]);
+        assert_eq!(buf, [
+            7, 0, 0, 0,
+            1, 0, 0, 0, 0, 0, 0, 0,
+            2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b',
+            2, 1, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        let mut decoder = Decoder::new(&buf);
+        assert_eq!(decoder.u32().unwrap(), 7);
+        let pairs: Vec<Pair> = decoder.seq().unwrap();
+        assert_eq!(pairs[0].name, "ab");
+        assert_eq!(pairs[0].value, 0x0102);
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn test_decoding_rejects_malformed_input() {
+        let pair = Pair { name: "key".into(), value: 1 }.to_bytes();
+        assert!(Pair::from_bytes(&pair[..pair.len() - 1]).is_err());
+
+        let mut trailing = pair.clone();
+        trailing.push(0);
+        assert!(Pair::from_bytes(&trailing).is_err());
+
+        // A length far beyond the input must not be trusted
+        let mut oversized = pair.clone();
+        oversized[0..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Pair::from_bytes(&oversized).is_err());
+
+        let mut invalid = pair;
+        invalid[8] = 0xff;
+        assert!(Pair::from_bytes(&invalid).is_err());
+    }
+}
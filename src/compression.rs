@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::types::Result;
+
+
+/// The encoding applied to the content of a file entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default)]
+pub enum CompressionType {
+   #[default]
+   None,
+   Gzip,
+   Lz4,
+   Zstd,
+   Snappy,
+}
+
+impl CompressionType {
+    /// Encodes `data` with this compression.
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionType::None => Ok(data.to_vec()),
+            CompressionType::Gzip => compress_gzip(data),
+            CompressionType::Lz4 => compress_lz4(data),
+            _ => Err(Error::Storage("Unsupported compression".into()))
+        }
+    }
+
+    /// Decodes `data` previously encoded with this compression.
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionType::None => Ok(data.to_vec()),
+            CompressionType::Gzip => decompress_gzip(data),
+            CompressionType::Lz4 => decompress_lz4(data),
+            _ => Err(Error::Storage("Unsupported compression".into()))
+        }
+    }
+}
+
+
+ fn compress_gzip(data: &[u8]) -> Result<Vec<u8>> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish().map_err(Error::from)
+}
+
+fn decompress_gzip(data: &[u8]) -> Result<Vec<u8>> {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let mut decoder = GzDecoder::new(data);
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+fn compress_lz4(data: &[u8]) -> Result<Vec<u8>> {
+    // Prepend the uncompressed size so decompression does not have to guess it
+    Ok(lz4_flex::block::compress_prepend_size(data))
+}
+ 
+ fn decompress_lz4(data: &[u8]) -> Result<Vec<u8>> {
+    lz4_flex::block::decompress_size_prepended(data)
+        .map_err(|e| Error::Storage(e.to_string()))
+ }
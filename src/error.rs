@@ -9,6 +9,14 @@ pub enum Error {
     Storage(String),
     #[error("Index error: {0}")]
     Index(String),
-    #[error("Serialization error: {0}")]
-    Serialization(#[from] bincode::Error),
+    #[error("Format error: {0}")]
+    Format(String),
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Shard size limit exceeded")]
+    ShardFull,
 }
@@ -1,25 +1,35 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 use futures::stream::{self, StreamExt};
-use tokio::sync::Mutex;
-use std::sync::Arc;
 
+use crate::codec::{put_seq, put_str, put_u64, Decode, Decoder, Encode};
 use crate::{Error, StorageProvider};
+use crate::index::entry::RecordEntry;
+use crate::index::generation::{decode_versions, Generation, RecordVersion};
+use crate::index::manifest::{ShardInfo, Tombstone};
+use crate::shard::format::FEATURE_CHUNKED;
+use crate::shard::record::ChunkInfo;
+use crate::shard::set::ShardNameTemplate;
+use crate::shard::{read_index, read_record_header};
 use crate::types::Result;
 
 
-/// Represents an index structure that maps shard identifiers to their respective entries and metadata.
+/// Represents an index structure that maps record keys to the generations of their records.
+///
+/// Record-level metadata is not held in the index; it is read from the record header.
 ///
 /// # Fields
 ///
-/// * `entries` - A hashmap mapping file keys to a vector of `IndexEntry` objects representing the shards.
-/// * `metadata` - A hashmap containing additional metadata for each file key.
-#[derive(Serialize, Deserialize)]
+/// * `entries` - A hashmap mapping file keys to their visible generations, oldest first.
+/// * `deleted` - A hashmap mapping deleted file keys to the latest generation deleted, so that
+///   new writes of a key never reuse a generation number.
+/// * `unloaded` - The shards whose records are not in `entries` yet, in the order they were
+///   sealed, loaded once a key within their key range is looked up.
 pub struct BucketIndex {
-    pub entries: HashMap<String, Vec<IndexEntry>>,
-    pub metadata: HashMap<String, Vec<u8>>,
+    pub entries: HashMap<String, Vec<Generation>>,
+    pub deleted: HashMap<String, u64>,
+    pub unloaded: Vec<ShardInfo>,
 }
 
 /// Represents an entry in the index corresponding to a shard within a file.
@@ -27,14 +37,14 @@ pub struct BucketIndex {
 /// # Fields
 ///
 /// * `shard_id` - A unique identifier for the shard.
-/// * `offset` - The offset of the shard within the file.
-/// * `size` - The size of the shard in bytes.
-/// * `checksum` - A 32-byte SHA-256 checksum of the shard data.
-#[derive(Serialize, Deserialize)]
+/// * `offset` - The offset of the record block within the shard.
+/// * `size` - The size of the record block in bytes.
+/// * `checksum` - A 32-byte SHA-256 checksum of the record block body.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct IndexEntry {
-    pub shard_id: usize,
-    pub offset: usize,  
-    pub size: usize,
+    pub shard_id: u64,
+    pub offset: u64,
+    pub size: u64,
     pub checksum: [u8; 32],
 }
 
@@ -44,20 +54,79 @@ impl IndexEntry {
     /// # Arguments
     ///
     /// * `shard_id` - A unique identifier for the shard.
-    /// * `offset` - The offset of the shard within the file.
-    /// * `size` - The size of the shard in bytes.
-    /// * `checksum` - A 32-byte SHA-256 checksum of the shard data.
+    /// * `offset` - The offset of the record block within the shard.
+    /// * `size` - The size of the record block in bytes.
+    /// * `checksum` - A 32-byte SHA-256 checksum of the record block body.
     ///
     /// # Returns
     ///
     /// A new `IndexEntry` instance with the specified properties.
-    pub fn new(shard_id: usize, offset: usize, size: usize, checksum: [u8; 32]) -> Self {
+    pub fn new(shard_id: u64, offset: u64, size: u64, checksum: [u8; 32]) -> Self {
         Self { shard_id, offset, size, checksum }
     }
 }
 
+impl Encode for IndexEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.shard_id);
+        put_u64(buf, self.offset);
+        put_u64(buf, self.size);
+        buf.extend_from_slice(&self.checksum);
+    }
+}
+
+impl Decode for IndexEntry {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            shard_id: decoder.u64()?,
+            offset: decoder.u64()?,
+            size: decoder.u64()?,
+            checksum: decoder.array()?,
+        })
+    }
+}
+
+/// The index is encoded as the count of keys followed by every key with its generations, then
+/// the count of deleted keys followed by every key with its latest deleted generation. Keys are
+/// sorted so that the same index always produces the same bytes. Shards not loaded yet are not
+/// encoded.
+impl Encode for BucketIndex {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut keys: Vec<_> = self.entries.keys().collect();
+        keys.sort();
+        put_u64(buf, keys.len() as u64);
+        for key in keys {
+            put_str(buf, key);
+            put_seq(buf, &self.entries[key]);
+        }
+
+        let mut keys: Vec<_> = self.deleted.keys().collect();
+        keys.sort();
+        put_u64(buf, keys.len() as u64);
+        for key in keys {
+            put_str(buf, key);
+            put_u64(buf, self.deleted[key]);
+        }
+    }
+}
+
+impl Decode for BucketIndex {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        let mut index = BucketIndex::default();
+        for _ in 0..decoder.u64()? {
+            let key = decoder.string()?;
+            index.entries.insert(key, decoder.seq()?);
+        }
+        for _ in 0..decoder.u64()? {
+            let key = decoder.string()?;
+            index.deleted.insert(key, decoder.u64()?);
+        }
+        Ok(index)
+    }
+}
+
 impl Default for BucketIndex {
-    /// Constructs a default `NativeIndex` with empty entries and metadata.
+    /// Constructs a default `NativeIndex` with empty entries.
     ///
     /// # Returns
     ///
@@ -65,70 +134,414 @@ impl Default for BucketIndex {
     fn default() -> Self {
         Self {
             entries: Default::default(),
-            metadata: Default::default()
+            deleted: Default::default(),
+            unloaded: Default::default(),
         }
     }
 }
 
+/// Progress of an index build, reported after every shard.
+///
+/// # Fields
+///
+/// * `shards` - The number of shards processed so far, including corrupt ones.
+/// * `total` - The number of shards to process.
+/// * `records` - The number of record blocks indexed so far.
+/// * `corrupt` - The number of shards that could not be read so far.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildProgress {
+    pub shards: usize,
+    pub total: usize,
+    pub records: u64,
+    pub corrupt: usize,
+}
+
+/// The record blocks of one shard, with the chunk each of them holds if the record is chunked
+/// and their version if the shard records versions.
+type ShardRecords = Vec<(RecordEntry, Option<ChunkInfo>, Option<RecordVersion>)>;
+
 impl BucketIndex {
-    /// Builds a new index by processing shards from the specified storage provider and bucket concurrently.
+    /// Returns the latest visible generation of `key`, if any.
+    pub fn latest(&self, key: &str) -> Option<&Generation> {
+        self.entries.get(key).and_then(|generations| generations.last())
+    }
+
+    /// Returns the generation `generation` of `key`, if it is visible.
+    pub fn generation(&self, key: &str, generation: u64) -> Option<&Generation> {
+        self.entries.get(key)?
+            .iter()
+            .find(|candidate| candidate.version.generation == generation)
+    }
+
+    /// Returns the number of the generation the next write of `key` creates.
+    pub fn next_generation(&self, key: &str) -> u64 {
+        let latest = self.latest(key).map_or(0, |generation| generation.version.generation);
+        latest.max(self.deleted.get(key).copied().unwrap_or(0)) + 1
+    }
+
+    /// Adds a generation of `key`, unless a tombstone already hides it.
+    ///
+    /// # Returns
+    /// * `Result<()>`, or `Error::Conflict` if a different record holds the same generation of
+    ///   the key, e.g. one written by another writer under the same number.
+    pub fn insert(&mut self, key: String, generation: Generation) -> Result<()> {
+        if generation.version.generation <= self.deleted.get(&key).copied().unwrap_or(0) {
+            return Ok(());
+        }
+        let generations = self.entries.entry(key).or_default();
+        match generations.binary_search_by_key(&generation.version.generation, |current| current.version.generation) {
+            Ok(position) if generations[position].entries != generation.entries => {
+                Err(Error::Conflict(format!(
+                    "Generation {} of a key is held by two records",
+                    generation.version.generation
+                )))
+            }
+            Ok(_) => Ok(()),
+            Err(position) => {
+                generations.insert(position, generation);
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes every generation with a record block in one of the shards `shard_ids`, and
+    /// the shards among them that are not loaded yet.
+    pub fn remove_shards(&mut self, shard_ids: &HashSet<u64>) {
+        self.unloaded.retain(|info| !shard_ids.contains(&info.id));
+        self.entries.retain(|_, generations| {
+            generations.retain(|generation| {
+                !generation.entries.iter().any(|entry| shard_ids.contains(&entry.shard_id))
+            });
+            !generations.is_empty()
+        });
+    }
+
+    /// Removes the generations hidden by `tombstones`.
+    ///
+    /// The latest generation deleted by every tombstone is kept, so that later writes of the
+    /// key continue its numbering.
+    pub fn apply_tombstones(&mut self, tombstones: &[Tombstone]) {
+        for tombstone in tombstones {
+            let mut latest = tombstone.generation;
+            if let Some(generations) = self.entries.get_mut(&tombstone.key) {
+                for generation in generations.iter().filter(|generation| tombstone.hides(generation)) {
+                    latest = latest.max(generation.version.generation);
+                }
+                generations.retain(|generation| !tombstone.hides(generation));
+                if generations.is_empty() {
+                    self.entries.remove(&tombstone.key);
+                }
+            }
+            let deleted = self.deleted.entry(tombstone.key.clone()).or_default();
+            *deleted = (*deleted).max(latest);
+        }
+    }
+
+    /// Merges the generations of an index built from other shards into this one.
+    pub fn merge(&mut self, other: BucketIndex) -> Result<()> {
+        for (key, generation) in other.deleted {
+            let deleted = self.deleted.entry(key).or_default();
+            *deleted = (*deleted).max(generation);
+        }
+        for (key, generations) in other.entries {
+            for generation in generations {
+                self.insert(key.clone(), generation)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the shards of the specified bucket, sorted by identifier.
+    ///
+    /// Objects whose name does not match `template`, such as the manifest or staging objects,
+    /// are skipped.
+    ///
+    /// # Returns
+    ///
+    /// The identifier and path of every object of the bucket named after `template`.
+    pub async fn list_shards<P: StorageProvider>(
+        provider: &P,
+        bucket: &str,
+        template: &ShardNameTemplate,
+    ) -> Result<Vec<(u64, PathBuf)>> {
+        let mut shards: Vec<_> = provider.list(bucket.as_ref()).await?
+            .into_iter()
+            .map(PathBuf::from)
+            .filter_map(|path| {
+                let id = template.id(path.file_name()?.to_str()?)?;
+                Some((id, path))
+            })
+            .collect();
+        shards.sort();
+        Ok(shards)
+    }
+
+    /// Builds a new index from the given shards.
+    ///
+    /// Only the footer and index of each shard are fetched, using ranged reads, so memory use
+    /// is bounded by `parallelism` shard indexes rather than by shard sizes.
+    ///
+    /// Shards are read concurrently but applied in the order given, which must be the order they
+    /// were sealed for shards that do not record the versions of their records. A corrupt shard
+    /// does not stop the build; every corrupt shard is reported once all shards have been
+    /// processed.
     ///
     /// # Arguments
     ///
     /// * `provider` - A reference to a storage provider that handles file operations.
-    /// * `bucket` - The name of the bucket containing files to process.
-    /// * `parallelism` - The number of concurrent tasks to use for processing shards.
+    /// * `shards` - The identifier and path of every shard, in the order they were sealed.
+    /// * `parallelism` - The number of shards read concurrently.
+    /// * `progress` - Called after every shard with the progress of the build.
     ///
     /// # Returns
     ///
-    /// A new `NativeIndex` instance containing entries and metadata from processed shards, or an error if any step fails.
+    /// A new `BucketIndex` instance containing entries from all shards, or an error naming every corrupt shard.
     pub async fn build<P: StorageProvider>(
         provider: &P,
-        bucket: &str,
+        shards: Vec<(u64, PathBuf)>,
         parallelism: usize,
+        progress: impl FnMut(&BuildProgress),
     ) -> Result<Self> {
-        let index = Arc::new(Mutex::new(BucketIndex::default()));
-        let files = provider.list(bucket.as_ref()).await?;
-        
-        stream::iter(files)
-            .map(|file| {
-                let provider = provider;
-                let index = Arc::clone(&index);
-                async move {
-                    let path  = PathBuf::from(file);
-                    let data = provider.read(&path).await?;
-                    let mut index = index.lock().await;
-                    // Process shard and update index
-                    Self::process_shard(&mut index, &data)?;
-                    Result::Ok(())
-                }
+        Ok(Self::build_shards(BucketIndex::default(), provider, shards, parallelism, progress, false).await?.unwrap_or_default())
+    }
+
+    /// Builds an index from some of the shards of a bucket, e.g. those that may hold a key.
+    ///
+    /// Records of shards that do not record their versions are numbered after the records of
+    /// their key in every shard sealed before, which need not be part of the build, so the
+    /// build stops at the first such shard. Records hidden by `tombstones` are left out as
+    /// they are read.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - A reference to a storage provider that handles file operations.
+    /// * `shards` - The identifier and path of every shard.
+    /// * `parallelism` - The number of shards read concurrently.
+    /// * `tombstones` - The tombstones of the bucket.
+    ///
+    /// # Returns
+    ///
+    /// A new `BucketIndex` instance containing entries from all shards, `None` if a shard does
+    /// not record the versions of its records, or an error naming every corrupt shard.
+    pub async fn build_versioned<P: StorageProvider>(
+        provider: &P,
+        shards: Vec<(u64, PathBuf)>,
+        parallelism: usize,
+        tombstones: &[Tombstone],
+    ) -> Result<Option<Self>> {
+        let mut index = BucketIndex::default();
+        index.apply_tombstones(tombstones);
+        Self::build_shards(index, provider, shards, parallelism, |_| {}, true).await
+    }
+
+    /// Builds an index as `build` does on top of `index`, stopping with `None` at the first
+    /// shard that does not record versions if `versioned` is set.
+    async fn build_shards<P: StorageProvider>(
+        mut index: BucketIndex,
+        provider: &P,
+        shards: Vec<(u64, PathBuf)>,
+        parallelism: usize,
+        mut progress: impl FnMut(&BuildProgress),
+        versioned: bool,
+    ) -> Result<Option<Self>> {
+        let mut status = BuildProgress { total: shards.len(), ..Default::default() };
+        let mut corrupt = Vec::new();
+
+        let mut results = stream::iter(shards)
+            .map(|(shard_id, path)| async move {
+                let records = Self::read_shard(provider, &path).await;
+                (shard_id, path, records)
             })
-            .buffer_unordered(parallelism)
-            .collect::<Vec<Result<()>>>()
-            .await
-            .into_iter()
-            .collect::<Result<Vec<()>>>()?;
+            .buffered(parallelism.max(1));
+        while let Some((shard_id, path, records)) = results.next().await {
+            match records {
+                Ok(records) if versioned && records.iter().any(|(_, _, version)| version.is_none()) => {
+                    return Ok(None);
+                }
+                Ok(records) => {
+                    status.records += records.len() as u64;
+                    Self::process_shard(&mut index, shard_id, records)?;
+                }
+                Err(err) => {
+                    status.corrupt += 1;
+                    corrupt.push(format!("{}: {}", path.display(), err));
+                }
+            }
+            status.shards += 1;
+            progress(&status);
+        }
 
-        let index = Arc::try_unwrap(index)
-            .map_err(|_| Error::Index("Failed to unwrap index".into()))?
-            .into_inner();
+        if !corrupt.is_empty() {
+            return Err(Error::Index(format!(
+                "{} of {} shards are corrupt: {}",
+                corrupt.len(),
+                status.total,
+                corrupt.join("; ")
+            )));
+        }
+        Ok(Some(index))
+    }
 
-        Ok(index)
+    /// Reads the index at the end of a shard.
+    ///
+    /// The versions of the records are read from the shard-level metadata. The record headers
+    /// are only read for shards holding chunked records, to find which chunk every record block
+    /// holds.
+    async fn read_shard<P: StorageProvider>(provider: &P, path: &Path) -> Result<ShardRecords> {
+        let (footer, shard) = read_index(provider, path).await?;
+        let chunked = footer.required_features & FEATURE_CHUNKED != 0;
+        let mut versions = decode_versions(&shard.metadata, shard.records.len())?.map(Vec::into_iter);
+        let mut records = Vec::with_capacity(shard.records.len());
+        for record in shard.records {
+            let chunk = match chunked {
+                true => read_record_header(provider, path, &record.key, record.offset, record.size).await?.0.chunk,
+                false => None,
+            };
+            let version = versions.as_mut().and_then(Iterator::next);
+            records.push((record, chunk, version));
+        }
+        Ok(records)
     }
 
-    /// Processes a single shard's data and updates the index entries and metadata accordingly.
+    /// Processes a single shard's records and updates the index entries accordingly.
+    ///
+    /// Every record adds a generation of its key, except for chunks following the first chunk
+    /// of a record, which are appended to the generation of that record. Records of shards that
+    /// do not record versions become the next generation of their key, written at the Unix
+    /// epoch.
     ///
     /// # Arguments
     ///
-    /// * `index` - A mutable reference to the `NativeIndex` being updated.
-    /// * `data` - The byte slice containing the shard data to process.
+    /// * `index` - A mutable reference to the `BucketIndex` being updated.
+    /// * `shard_id` - The identifier of the shard.
+    /// * `records` - The records read from the end of the shard.
     ///
     /// # Returns
     ///
-    /// An empty result indicating success or an error if processing fails.
-    fn process_shard(index: &mut BucketIndex, data: &[u8]) -> Result<()> {
-        // Process shard data and update index entries
-        // Parse shard header, entries, update hashmaps
+    /// An empty result indicating success or an error if a chunk does not follow the previous chunk of its record,
+    /// or if two records hold the same generation of a key.
+    fn process_shard(index: &mut BucketIndex, shard_id: u64, records: ShardRecords) -> Result<()> {
+        for (record, chunk, version) in records {
+            let entry = IndexEntry::new(shard_id, record.offset, record.size, record.checksum);
+            match chunk {
+                Some(chunk) if chunk.index > 0 => {
+                    let generations = index.entries.entry(record.key).or_default();
+                    let generation = match version {
+                        Some(version) => generations.iter_mut()
+                            .find(|generation| generation.version.generation == version.generation),
+                        None => generations.last_mut(),
+                    };
+                    match generation {
+                        Some(generation) if generation.entries.len() as u64 == chunk.index => {
+                            generation.entries.push(entry)
+                        }
+                        _ => {
+                            return Err(Error::Index(format!(
+                                "Chunk {} of a record in shard {} does not follow the previous chunk",
+                                chunk.index, shard_id
+                            )))
+                        }
+                    }
+                }
+                _ => {
+                    let version = version.unwrap_or(RecordVersion {
+                        generation: index.next_generation(&record.key),
+                        timestamp: 0,
+                    });
+                    index.insert(record.key, Generation { version, entries: vec![entry] })?;
+                }
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::shard::ShardWriter;
+    use crate::storage::LocalStorageProvider;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let mut index = BucketIndex::default();
+        let generation = |generation, entry| Generation {
+            version: RecordVersion { generation, timestamp: 1_700_000_000_000 },
+            entries: vec![entry],
+        };
+        index.insert("b".into(), generation(1, IndexEntry::new(1, 0, 64, [1; 32]))).unwrap();
+        index.insert("a".into(), generation(2, IndexEntry::new(0, 128, 32, [2; 32]))).unwrap();
+        index.insert("a".into(), generation(1, IndexEntry::new(0, 0, 128, [3; 32]))).unwrap();
+        index.deleted.insert("c".into(), 4);
+
+        // A generation is only inserted again by the same record, and never once deleted
+        index.insert("a".into(), generation(1, IndexEntry::new(0, 0, 128, [3; 32]))).unwrap();
+        assert!(matches!(
+            index.insert("a".into(), generation(1, IndexEntry::new(2, 0, 128, [4; 32]))),
+            Err(Error::Conflict(_))
+        ));
+        index.insert("c".into(), generation(4, IndexEntry::new(2, 0, 16, [5; 32]))).unwrap();
+        assert!(!index.entries.contains_key("c"));
+
+        let bytes = index.to_bytes();
+        let decoded = BucketIndex::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.entries, index.entries);
+        assert_eq!(decoded.deleted, index.deleted);
+        assert_eq!(index.latest("a").unwrap().version.generation, 2);
+        assert_eq!(index.generation("a", 1).unwrap().entries[0].size, 128);
+        assert_eq!(index.next_generation("c"), 5);
+
+        // Keys are written in sorted order, starting with the count of keys
+        assert_eq!(bytes[0..8], 2u64.to_le_bytes());
+        assert_eq!(bytes[8..17], *b"\x01\0\0\0\0\0\0\0a");
+        assert!(BucketIndex::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_from_footers() {
+        let root = TempDir::new().unwrap();
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let template = ShardNameTemplate::default();
+        for (id, keys) in [(0, ["a", "b"]), (1, ["b", "c"]), (2, ["d", "e"])] {
+            let path = Path::new("bucket").join(template.name(id));
+            let mut writer = ShardWriter::create(Arc::clone(&provider), path);
+            for key in keys {
+                writer.write(key, key.as_bytes(), None).await.unwrap();
+            }
+            writer.finalize().await.unwrap();
+        }
+        provider.write(Path::new("bucket/MANIFEST"), b"not a shard").await.unwrap();
+
+        let shards = BucketIndex::list_shards(&provider, "bucket", &template).await.unwrap();
+        assert_eq!(shards.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [0, 1, 2]);
+        let mut reports = Vec::new();
+        let index = BucketIndex::build(&provider, shards.clone(), 2, |progress| reports.push(progress.clone()))
+            .await
+            .unwrap();
+        assert_eq!(index.entries.len(), 5);
+        // Shards written without versions number the generations of a key in shard order
+        assert_eq!(index.entries["b"].len(), 2);
+        assert_eq!(index.latest("b").unwrap().shard_id(), 1);
+        assert_eq!(index.latest("b").unwrap().version.generation, 2);
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[2], BuildProgress { shards: 3, total: 3, records: 6, corrupt: 0 });
+        // Their generations depend on the shards before them, so they cannot be built on their own
+        assert!(BucketIndex::build_versioned(&provider, shards[1..].to_vec(), 2, &[]).await.unwrap().is_none());
+
+        // A corrupt shard is named in the error, after every shard has been processed
+        let path = root.path().join(&shards[1].1);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let mut reports = Vec::new();
+        let err = BucketIndex::build(&provider, shards, 2, |progress| reports.push(progress.clone()))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("1 of 3 shards are corrupt: bucket/shard_0000000000000001"), "{}", err);
+        assert_eq!(reports[2], BuildProgress { shards: 3, total: 3, records: 4, corrupt: 1 });
+    }
+}
@@ -0,0 +1,56 @@
+use crate::codec::{put_str, put_u64, Decode, Decoder, Encode};
+use crate::types::Result;
+
+/// Represents the location of a record block inside a shard, as stored in the shard index.
+///
+/// # Fields
+///
+/// * `key` - The key of the record stored in the block.
+/// * `offset` - The offset of the record block within the shard.
+/// * `size` - The size of the record block in bytes.
+/// * `checksum` - A 32-byte SHA-256 checksum of the record block body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordEntry {
+    pub key: String,
+    pub offset: u64,
+    pub size: u64,
+    pub checksum: [u8; 32],
+}
+
+impl RecordEntry {
+    /// Constructs a new `RecordEntry` with the given parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key of the record stored in the block.
+    /// * `offset` - The offset of the record block within the shard.
+    /// * `size` - The size of the record block in bytes.
+    /// * `checksum` - A 32-byte SHA-256 checksum of the record block body.
+    ///
+    /// # Returns
+    ///
+    /// A new `RecordEntry` instance with the specified properties.
+    pub fn new(key: String, offset: u64, size: u64, checksum: [u8; 32]) -> Self {
+        Self { key, offset, size, checksum }
+    }
+}
+
+impl Encode for RecordEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.key);
+        put_u64(buf, self.offset);
+        put_u64(buf, self.size);
+        buf.extend_from_slice(&self.checksum);
+    }
+}
+
+impl Decode for RecordEntry {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            key: decoder.string()?,
+            offset: decoder.u64()?,
+            size: decoder.u64()?,
+            checksum: decoder.array()?,
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::codec::{put_seq, put_u64, Decode, Decoder, Encode};
+use crate::error::Error;
+use crate::index::bucket::IndexEntry;
+use crate::types::Result;
+
+/// Magic number opening the shard-level metadata of bucket shards, which holds the version of
+/// every record of the shard.
+pub const VERSIONS_MAGIC: [u8; 8] = *b"SHRDVERS";
+
+/// Identifies one write of a key.
+///
+/// Every write of a key creates a new generation, numbered from 1 in write order. Buckets
+/// store the version of every record of a shard in the shard-level metadata, so that the
+/// generations of a bucket are known from the shard footers alone.
+///
+/// # Fields
+///
+/// * `generation` - The number of the write among the writes of the key.
+/// * `timestamp` - When the record was written, in milliseconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordVersion {
+    pub generation: u64,
+    pub timestamp: u64,
+}
+
+/// One generation of a key in the bucket index.
+///
+/// # Fields
+///
+/// * `version` - The generation number and write time of the record.
+/// * `entries` - The location of the record, one entry per chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Generation {
+    pub version: RecordVersion,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl RecordVersion {
+    /// Constructs the version `generation` of a key, written now.
+    pub fn now(generation: u64) -> Self {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Self { generation, timestamp }
+    }
+
+    /// Returns when the record was written.
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+}
+
+impl Generation {
+    /// Returns the identifier of the shard holding the record, or its first chunk.
+    pub fn shard_id(&self) -> u64 {
+        self.entries.first().map_or(0, |entry| entry.shard_id)
+    }
+}
+
+impl Encode for RecordVersion {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.generation);
+        put_u64(buf, self.timestamp);
+    }
+}
+
+impl Decode for RecordVersion {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self { generation: decoder.u64()?, timestamp: decoder.u64()? })
+    }
+}
+
+impl Encode for Generation {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.version.encode(buf);
+        put_seq(buf, &self.entries);
+    }
+}
+
+impl Decode for Generation {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self { version: RecordVersion::decode(decoder)?, entries: decoder.seq()? })
+    }
+}
+
+/// Encodes the versions of the records of a shard, in record order, as shard-level metadata.
+pub(crate) fn encode_versions(versions: &[RecordVersion]) -> Vec<u8> {
+    let mut buf = VERSIONS_MAGIC.to_vec();
+    put_seq(&mut buf, versions);
+    buf
+}
+
+/// Decodes the versions of the records of a shard from its shard-level metadata.
+///
+/// # Returns
+/// * `Result<Option<Vec<RecordVersion>>>` with the versions, `None` if the metadata does not
+///   hold versions, e.g. for shards written before versions were recorded, or an error if it
+///   does not hold one version per record.
+pub(crate) fn decode_versions(metadata: &[u8], records: usize) -> Result<Option<Vec<RecordVersion>>> {
+    let Some(body) = metadata.strip_prefix(&VERSIONS_MAGIC) else {
+        return Ok(None);
+    };
+    let mut decoder = Decoder::new(body);
+    let versions: Vec<RecordVersion> = decoder.seq()?;
+    decoder.finish()?;
+    if versions.len() != records {
+        return Err(Error::Format("Shard versions do not match its records".into()));
+    }
+    Ok(Some(versions))
+}
@@ -0,0 +1,430 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::checksum::{compute_checksum, verify_checksum};
+use crate::codec::{put_seq, put_str, put_u64, Decode, Decoder, Encode};
+use crate::error::Error;
+use crate::index::generation::Generation;
+use crate::shard::read_index;
+use crate::shard::writer::staging_path;
+use crate::storage::StorageProvider;
+use crate::types::Result;
+
+/// Name of the manifest object within a bucket.
+pub const MANIFEST_NAME: &str = "MANIFEST";
+
+/// Name of the directory within a bucket holding its snapshots, one manifest per snapshot.
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// Magic number opening every manifest.
+pub const MANIFEST_MAGIC: [u8; 8] = *b"SHRDMNFT";
+
+/// Version of the manifest encoding written by this crate.
+///
+/// Version 1 manifests lack tombstones, version 2 tombstones lack generations and version 3
+/// manifests lack retired shards; all of them are still read.
+pub const MANIFEST_VERSION: u64 = 4;
+
+/// Number of bytes fetched per ranged read when checksumming a whole shard.
+const CHECKSUM_READ_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The catalog of a bucket, listing every shard it is made of.
+///
+/// The manifest is the commit point of a bucket: a shard is part of the bucket once it is
+/// listed in the manifest, and not before. It is stored as a single object so that a bucket
+/// with any number of shards is opened with one read, and it is replaced as a whole, through
+/// a staging object, whenever shards are added.
+///
+/// # Fields
+///
+/// * `shards` - Every shard of the bucket, in the order they were sealed.
+/// * `tombstones` - The deleted keys whose records are still stored in the shards.
+/// * `retired` - The shards removed from the bucket whose objects are still kept in storage.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub shards: Vec<ShardInfo>,
+    pub tombstones: Vec<Tombstone>,
+    pub retired: Vec<RetiredShard>,
+}
+
+/// A shard removed from a bucket, e.g. rewritten by compaction, whose object is kept so that
+/// readers still holding an older manifest can read it until they refresh.
+///
+/// # Fields
+///
+/// * `path` - The path of the shard within the storage provider.
+/// * `retired_at` - When the shard was removed, in milliseconds since the Unix epoch.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetiredShard {
+    pub path: PathBuf,
+    pub retired_at: u64,
+}
+
+/// Marks a key as deleted without rewriting the shards holding it.
+///
+/// A tombstone hides the generations of `key` up to `generation`, the latest generation when
+/// the key was deleted. Later writes of the key create new generations, which are visible.
+/// Tombstones without a generation, written by older versions, hide the records of the key
+/// in shards up to `shard_id` instead.
+///
+/// # Fields
+///
+/// * `key` - The deleted key.
+/// * `shard_id` - The identifier of the last shard holding a deleted record of `key`.
+/// * `generation` - The latest deleted generation of `key`, 0 if unknown.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tombstone {
+    pub key: String,
+    pub shard_id: u64,
+    pub generation: u64,
+}
+
+/// Describes one shard of a bucket in its manifest.
+///
+/// # Fields
+///
+/// * `id` - The identifier of the shard within its bucket.
+/// * `path` - The path of the shard within the storage provider.
+/// * `records` - The number of record blocks in the shard.
+/// * `size` - The size of the whole shard file in bytes.
+/// * `checksum` - A 32-byte SHA-256 checksum of the whole shard file.
+/// * `min_key` - The smallest key stored in the shard, empty if the shard holds no records.
+/// * `max_key` - The largest key stored in the shard, empty if the shard holds no records.
+/// * `format_version` - The format version the shard was written with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShardInfo {
+    pub id: u64,
+    pub path: PathBuf,
+    pub records: u64,
+    pub size: u64,
+    pub checksum: [u8; 32],
+    pub min_key: String,
+    pub max_key: String,
+    pub format_version: u64,
+}
+
+impl Manifest {
+    /// Loads the manifest stored at `path`.
+    ///
+    /// # Returns
+    /// * `Result<Option<Self>>` with the manifest, `None` if there is no manifest at `path`, or
+    ///   an error if it cannot be read or is corrupt.
+    pub async fn load<P: StorageProvider>(provider: &P, path: &Path) -> Result<Option<Self>> {
+        match provider.read(path).await {
+            Ok(bytes) => Self::decode_checked(&bytes).map(Some),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Stores the manifest at `path`, atomically replacing the previous one.
+    ///
+    /// The manifest is written to a staging object first and then moved over `path`, so that
+    /// readers see either the previous or the new manifest, never a partial one. The staging
+    /// object is complete and persisted before it is moved, so a crash cannot leave `path`
+    /// pointing at a manifest whose bytes were lost.
+    pub async fn store<P: StorageProvider>(&self, provider: &P, path: &Path) -> Result<()> {
+        let staging = staging_path(path);
+        let mut sink = provider.open_writer(&staging).await?;
+        sink.write_all(&self.encode_checked()).await?;
+        sink.finish().await?;
+        provider.rename(&staging, path).await
+    }
+
+    /// Applies `update` to the manifest stored at `path` and stores the result.
+    ///
+    /// The lock of `path` is held from loading the stored manifest to storing the updated one,
+    /// so that writers sharing a bucket apply their changes one after another, each on top of
+    /// the changes of the others, rather than overwriting them.
+    ///
+    /// # Arguments
+    /// * `provider` - The storage provider holding the manifest.
+    /// * `path` - The path of the manifest; an empty manifest is updated if there is none yet.
+    /// * `update` - The change to apply, e.g. adding shards or tombstones, or an error to leave
+    ///   the stored manifest as it is, e.g. if the changes of other writers conflict with it.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the stored manifest, including the changes of other writers, or
+    ///   the error of `update`.
+    pub async fn update<P: StorageProvider>(
+        provider: &P,
+        path: &Path,
+        update: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<Self> {
+        let lock = provider.lock(path).await?;
+        let updated: Result<Self> = async {
+            let mut manifest = Self::load(provider, path).await?.unwrap_or_default();
+            update(&mut manifest)?;
+            manifest.store(provider, path).await?;
+            Ok(manifest)
+        }.await;
+        let released = lock.release().await;
+        let manifest = updated?;
+        released?;
+        Ok(manifest)
+    }
+
+    /// Returns the identifier following the largest shard identifier in the manifest.
+    pub fn next_shard_id(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.id + 1).max().unwrap_or(0)
+    }
+
+    /// Marks the generations of `key` up to `generation`, stored in shards up to `shard_id`,
+    /// as deleted, merging with any previous tombstone of the key, e.g. one added by another
+    /// writer.
+    pub fn add_tombstone(&mut self, key: &str, shard_id: u64, generation: u64) {
+        match self.tombstones.iter_mut().find(|tombstone| tombstone.key == key) {
+            Some(tombstone) => {
+                tombstone.shard_id = tombstone.shard_id.max(shard_id);
+                tombstone.generation = tombstone.generation.max(generation);
+            }
+            None => self.tombstones.push(Tombstone { key: key.to_string(), shard_id, generation }),
+        }
+    }
+
+    /// Returns the number of records in all shards of the manifest.
+    pub fn records(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.records).sum()
+    }
+
+    /// Encodes the manifest between its magic number and version and a checksum of both.
+    fn encode_checked(&self) -> Vec<u8> {
+        let mut buf = MANIFEST_MAGIC.to_vec();
+        put_u64(&mut buf, MANIFEST_VERSION);
+        self.encode(&mut buf);
+        let checksum = compute_checksum(&buf);
+        buf.extend_from_slice(&checksum);
+        buf
+    }
+
+    /// Decodes a manifest produced by `encode_checked`, verifying its checksum and version.
+    fn decode_checked(bytes: &[u8]) -> Result<Self> {
+        let body_len = bytes.len().checked_sub(32)
+            .filter(|&len| len >= MANIFEST_MAGIC.len() + 8 && bytes.starts_with(&MANIFEST_MAGIC))
+            .ok_or_else(|| Error::Format("Not a bucket manifest".into()))?;
+        let mut checksum = [0u8; 32];
+        checksum.copy_from_slice(&bytes[body_len..]);
+        verify_checksum(&bytes[..body_len], &checksum)?;
+
+        let mut decoder = Decoder::new(&bytes[MANIFEST_MAGIC.len()..body_len]);
+        let manifest = match decoder.u64()? {
+            1 => Self { shards: decoder.seq()?, ..Self::default() },
+            2 => {
+                let shards = decoder.seq()?;
+                let mut tombstones = Vec::new();
+                for _ in 0..decoder.u64()? {
+                    tombstones.push(Tombstone { key: decoder.string()?, shard_id: decoder.u64()?, generation: 0 });
+                }
+                Self { shards, tombstones, ..Self::default() }
+            }
+            3 => Self { shards: decoder.seq()?, tombstones: decoder.seq()?, ..Self::default() },
+            MANIFEST_VERSION => Self::decode(&mut decoder)?,
+            version => return Err(Error::UnsupportedFormat(format!("Manifest version {}", version))),
+        };
+        decoder.finish()?;
+        Ok(manifest)
+    }
+}
+
+impl ShardInfo {
+    /// Describes an existing shard, e.g. to list it in a manifest that was lost.
+    ///
+    /// The whole shard is read to compute its checksum, in ranged reads of bounded size.
+    ///
+    /// # Arguments
+    /// * `provider` - The storage provider holding the shard.
+    /// * `id` - The identifier of the shard within its bucket.
+    /// * `path` - The path of the shard within the storage provider.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the description of the shard, or an error if it has no valid footer.
+    pub async fn read<P: StorageProvider>(provider: &P, id: u64, path: PathBuf) -> Result<Self> {
+        let (footer, index) = read_index(provider, &path).await?;
+        let size = provider.stat(&path).await?.size;
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(CHECKSUM_READ_SIZE);
+            hasher.update(&provider.read_range(&path, offset, len).await?);
+            offset += len;
+        }
+
+        let keys = index.records.iter().map(|entry| entry.key.as_str());
+        Ok(Self {
+            id,
+            records: index.records.len() as u64,
+            size,
+            checksum: hasher.finalize().into(),
+            min_key: keys.clone().min().unwrap_or_default().to_string(),
+            max_key: keys.max().unwrap_or_default().to_string(),
+            format_version: footer.version,
+            path,
+        })
+    }
+
+    /// Returns `true` if `key` is within the key range of the shard, so that the shard may
+    /// hold a record of it.
+    pub fn may_hold(&self, key: &str) -> bool {
+        self.records > 0 && self.min_key.as_str() <= key && key <= self.max_key.as_str()
+    }
+
+    /// Returns `true` if the key range of the shard overlaps the keys starting with `prefix`.
+    pub fn may_hold_prefix(&self, prefix: &str) -> bool {
+        // A key past `prefix` that does not start with it is past every key that does
+        self.records > 0
+            && self.max_key.as_str() >= prefix
+            && (self.min_key.as_str() <= prefix || self.min_key.starts_with(prefix))
+    }
+}
+
+impl Encode for Manifest {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_seq(buf, &self.shards);
+        put_seq(buf, &self.tombstones);
+        put_seq(buf, &self.retired);
+    }
+}
+
+impl Decode for Manifest {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self { shards: decoder.seq()?, tombstones: decoder.seq()?, retired: decoder.seq()? })
+    }
+}
+
+impl RetiredShard {
+    /// Retires the shard at `path` now.
+    pub fn now(path: PathBuf) -> Self {
+        let retired_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Self { path, retired_at }
+    }
+
+    /// Returns when the shard was retired.
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.retired_at)
+    }
+}
+
+impl Encode for RetiredShard {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.path.to_string_lossy());
+        put_u64(buf, self.retired_at);
+    }
+}
+
+impl Decode for RetiredShard {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self { path: PathBuf::from(decoder.string()?), retired_at: decoder.u64()? })
+    }
+}
+
+impl Tombstone {
+    /// Returns `true` if the tombstone hides `generation` of its key.
+    pub fn hides(&self, generation: &Generation) -> bool {
+        match self.generation {
+            0 => generation.shard_id() <= self.shard_id,
+            deleted => generation.version.generation <= deleted,
+        }
+    }
+}
+
+impl Encode for Tombstone {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.key);
+        put_u64(buf, self.shard_id);
+        put_u64(buf, self.generation);
+    }
+}
+
+impl Decode for Tombstone {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self { key: decoder.string()?, shard_id: decoder.u64()?, generation: decoder.u64()? })
+    }
+}
+
+impl Encode for ShardInfo {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.id);
+        put_str(buf, &self.path.to_string_lossy());
+        put_u64(buf, self.records);
+        put_u64(buf, self.size);
+        buf.extend_from_slice(&self.checksum);
+        put_str(buf, &self.min_key);
+        put_str(buf, &self.max_key);
+        put_u64(buf, self.format_version);
+    }
+}
+
+impl Decode for ShardInfo {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            id: decoder.u64()?,
+            path: PathBuf::from(decoder.string()?),
+            records: decoder.u64()?,
+            size: decoder.u64()?,
+            checksum: decoder.array()?,
+            min_key: decoder.string()?,
+            max_key: decoder.string()?,
+            format_version: decoder.u64()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::LocalStorageProvider;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_store_and_load() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = Path::new("bucket").join(MANIFEST_NAME);
+        assert_eq!(Manifest::load(&provider, &path).await.unwrap(), None);
+
+        let manifest = Manifest {
+            shards: vec![ShardInfo {
+                id: 3,
+                path: PathBuf::from("bucket/shard_0000000000000003"),
+                records: 2,
+                size: 4096,
+                checksum: [7; 32],
+                min_key: "a".into(),
+                max_key: "z".into(),
+                format_version: 2,
+            }],
+            tombstones: vec![Tombstone { key: "b".into(), shard_id: 3, generation: 2 }],
+            retired: vec![RetiredShard { path: PathBuf::from("bucket/shard_0000000000000001"), retired_at: 5 }],
+        };
+        manifest.store(&provider, &path).await.unwrap();
+        assert_eq!(Manifest::load(&provider, &path).await.unwrap(), Some(manifest.clone()));
+        assert_eq!(manifest.next_shard_id(), 4);
+        let info = &manifest.shards[0];
+        assert!(info.may_hold("a") && info.may_hold("m") && info.may_hold("z"));
+        assert!(!info.may_hold("za") && !info.may_hold("A"));
+        let info = ShardInfo { min_key: "images/1".into(), max_key: "labels/9".into(), ..info.clone() };
+        assert!(info.may_hold_prefix("") && info.may_hold_prefix("images/") && info.may_hold_prefix("labels/"));
+        assert!(!info.may_hold_prefix("h") && !info.may_hold_prefix("m"));
+        assert!(!root.path().join(staging_path(&path)).exists());
+
+        // Version 1 manifests have no tombstones
+        let mut v1 = MANIFEST_MAGIC.to_vec();
+        put_u64(&mut v1, 1);
+        put_seq(&mut v1, &manifest.shards);
+        let checksum = compute_checksum(&v1);
+        v1.extend_from_slice(&checksum);
+        let decoded = Manifest::decode_checked(&v1).unwrap();
+        assert_eq!(decoded.shards, manifest.shards);
+        assert!(decoded.tombstones.is_empty());
+        assert!(decoded.retired.is_empty());
+
+        let mut bytes = std::fs::read(root.path().join(&path)).unwrap();
+        bytes[20] ^= 1;
+        std::fs::write(root.path().join(&path), &bytes).unwrap();
+        assert!(Manifest::load(&provider, &path).await.is_err());
+    }
+}
@@ -1,2 +1,4 @@
-mod entry;
-pub mod bucket;
\ No newline at end of file
+pub mod entry;
+pub mod bucket;
+pub mod generation;
+pub mod manifest;
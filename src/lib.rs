@@ -1,14 +1,29 @@
 mod bucket;
 mod checksum;
+mod codec;
+mod compression;
 mod storage;
 mod shard;
 mod error;
 mod index;
 mod types;
 
-pub use bucket::Bucket;
+pub use bucket::{Batch, Bucket, BucketConfig, CompactionPolicy, CompactionReport, CompressionType};
 pub use error::Error;
-pub use storage::StorageProvider;
+pub use index::bucket::BuildProgress;
+pub use index::generation::RecordVersion;
+pub use index::manifest::{Manifest, RetiredShard, ShardInfo, Tombstone, MANIFEST_NAME, SNAPSHOTS_DIR};
+pub use shard::config::{ShardPolicy, DEFAULT_SHARD_SIZE};
+pub use shard::format::{
+    FEATURE_ALIGNED_BLOCKS, FEATURE_ALIGNED_ENTRIES, FEATURE_CHUNKED, FEATURE_GZIP, FEATURE_LZ4, FEATURE_SHA256,
+    FEATURE_SNAPPY, FEATURE_ZSTD, FORMAT_VERSION, SUPPORTED_FEATURES,
+};
+pub use bytes::Bytes;
+pub use shard::{
+    ChunkInfo, EntryInfo, FileEntry, MmapShardReader, Record, RecordBuilder, ShardNameTemplate,
+    ShardReader, ShardSet, ShardSetReader, ShardWriter,
+};
+pub use storage::{LocalStorageLock, LocalStorageProvider, ObjectStat, StorageLock, StorageProvider, StorageSink};
 
 
 
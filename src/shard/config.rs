@@ -1,7 +1,200 @@
-const SHARD_SIZE: usize = 256 * 1024 * 1024; // 256MB
+use std::path::Path;
+use std::time::Duration;
 
+use crate::error::Error;
+use crate::types::Result;
 
-pub fn shard_size() -> usize {
-    // TODO: check env
-    SHARD_SIZE
-}
\ No newline at end of file
+/// Size a shard is filled up to when no other size is configured.
+pub const DEFAULT_SHARD_SIZE: usize = 256 * 1024 * 1024; // 256MB
+
+/// Environment variable overriding `ShardPolicy::max_size`, in bytes.
+pub const ENV_SHARD_SIZE: &str = "SHARDPACK_SHARD_SIZE";
+
+/// Environment variable overriding `ShardPolicy::max_records`.
+pub const ENV_MAX_RECORDS: &str = "SHARDPACK_SHARD_MAX_RECORDS";
+
+/// Environment variable overriding `ShardPolicy::max_open`, in seconds.
+pub const ENV_MAX_OPEN_SECS: &str = "SHARDPACK_SHARD_MAX_OPEN_SECS";
+
+/// Decides when the open shard of a bucket is sealed and a new one is started.
+///
+/// A shard is sealed as soon as any of the limits is reached. Sealing makes the records of the
+/// shard visible to readers, so streaming ingest sets `max_open` to bound how long records stay
+/// invisible, while batch jobs set `max_records` to get shards of a predictable size to split
+/// between workers.
+///
+/// The policy is built in code, or read from a config file or the environment:
+///
+/// ```text
+/// # shardpack.conf
+/// shard_size = 67108864
+/// max_records = 10000
+/// max_open_secs = 30
+/// ```
+///
+/// The environment variables `SHARDPACK_SHARD_SIZE`, `SHARDPACK_SHARD_MAX_RECORDS` and
+/// `SHARDPACK_SHARD_MAX_OPEN_SECS` take the same values.
+///
+/// # Fields
+///
+/// * `max_size` - The number of bytes of record blocks a shard may hold.
+/// * `max_records` - The number of records after which a shard is sealed, if limited.
+/// * `max_open` - How long a shard stays open after it was started, if limited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardPolicy {
+    pub max_size: usize,
+    pub max_records: Option<usize>,
+    pub max_open: Option<Duration>,
+}
+
+impl Default for ShardPolicy {
+    /// Constructs a policy filling shards up to `DEFAULT_SHARD_SIZE`, without record count or
+    /// time limits.
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_SHARD_SIZE,
+            max_records: None,
+            max_open: None,
+        }
+    }
+}
+
+impl ShardPolicy {
+    /// Sets the number of bytes of record blocks a shard may hold.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Seals shards once they hold `max_records` records.
+    pub fn with_max_records(mut self, max_records: usize) -> Self {
+        self.max_records = Some(max_records);
+        self
+    }
+
+    /// Seals shards once they have been open for `max_open`.
+    pub fn with_max_open(mut self, max_open: Duration) -> Self {
+        self.max_open = Some(max_open);
+        self
+    }
+
+    /// Reads the default policy overridden by the environment variables that are set.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the policy, or `Error::Config` if a variable has an invalid value.
+    pub fn from_env() -> Result<Self> {
+        Self::default().with_env()
+    }
+
+    /// Reads the default policy overridden by the settings of the config file at `path`.
+    ///
+    /// The file holds one `name = value` setting per line. Blank lines and lines starting
+    /// with `#` are ignored.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the policy, or an error if the file cannot be read or holds an
+    ///   unknown setting or an invalid value.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        Self::default().with_config(&contents)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Overrides the limits of the policy with the environment variables that are set, e.g. to
+    /// let the environment take precedence over a config file.
+    pub fn with_env(self) -> Result<Self> {
+        self.with_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Overrides the limits of the policy with the variables returned by `lookup`.
+    fn with_vars(mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        for (name, setting) in [
+            (ENV_SHARD_SIZE, "shard_size"),
+            (ENV_MAX_RECORDS, "max_records"),
+            (ENV_MAX_OPEN_SECS, "max_open_secs"),
+        ] {
+            if let Some(value) = lookup(name) {
+                self.set(setting, &value)
+                    .map_err(|err| Error::Config(format!("{}: {}", name, err)))?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Overrides the limits of the policy with the settings of a config file.
+    fn with_config(mut self, contents: &str) -> Result<Self> {
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once('=')
+                .ok_or_else(|| Error::Config(format!("line {}: expected `name = value`", number + 1)))?;
+            self.set(name.trim(), value.trim())
+                .map_err(|err| Error::Config(format!("line {}: {}", number + 1, err)))?;
+        }
+        Ok(self)
+    }
+
+    /// Sets the limit named `name` from its textual `value`.
+    fn set(&mut self, name: &str, value: &str) -> std::result::Result<(), String> {
+        let number = value.parse::<u64>()
+            .map_err(|_| format!("invalid value `{}` for {}", value, name))?;
+        match name {
+            "shard_size" if number == 0 => return Err("shard_size must not be zero".into()),
+            "shard_size" => self.max_size = number as usize,
+            "max_records" => self.max_records = Some(number as usize).filter(|&count| count > 0),
+            "max_open_secs" => self.max_open = Some(Duration::from_secs(number)).filter(|open| !open.is_zero()),
+            _ => return Err(format!("unknown setting `{}`", name)),
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if a shard holding `records` records that has been open for `open` must
+    /// be sealed.
+    pub fn is_due(&self, records: usize, open: Duration) -> bool {
+        self.max_records.is_some_and(|max_records| records >= max_records)
+            || self.max_open.is_some_and(|max_open| open >= max_open)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_policy_from_config_and_env() {
+        let policy = ShardPolicy::default()
+            .with_config("# streaming\nshard_size = 1024\n\nmax_open_secs = 30\n")
+            .unwrap();
+        assert_eq!(policy.max_size, 1024);
+        assert_eq!(policy.max_records, None);
+        assert_eq!(policy.max_open, Some(Duration::from_secs(30)));
+
+        let vars = HashMap::from([(ENV_MAX_RECORDS, "100"), (ENV_MAX_OPEN_SECS, "0")]);
+        let policy = policy.with_vars(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!(policy.max_size, 1024);
+        assert_eq!(policy.max_records, Some(100));
+        assert_eq!(policy.max_open, None);
+
+        assert!(policy.is_due(100, Duration::ZERO));
+        assert!(!policy.is_due(99, Duration::from_secs(3600)));
+
+        assert!(ShardPolicy::default().with_config("shard_size = 0").is_err());
+        assert!(ShardPolicy::default().with_config("shard_size = 1MB").is_err());
+        assert!(ShardPolicy::default().with_config("max_bytes = 1").is_err());
+        assert!(ShardPolicy::default().with_config("max_records").is_err());
+    }
+
+    #[test]
+    fn test_policy_from_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("shardpack.conf");
+        std::fs::write(&path, "max_records = 10\n").unwrap();
+        assert_eq!(ShardPolicy::from_file(&path).unwrap(), ShardPolicy::default().with_max_records(10));
+        assert!(ShardPolicy::from_file(dir.path().join("missing.conf")).is_err());
+    }
+}
@@ -0,0 +1,590 @@
+use bytes::Bytes;
+
+use crate::checksum::{compute_checksum, verify_checksum};
+use crate::codec::{put_bytes, put_seq, put_str, put_u32, put_u64, Decode, Decoder, Encode};
+use crate::compression::CompressionType;
+use crate::error::Error;
+use crate::index::entry::RecordEntry;
+use crate::shard::record::{ChunkInfo, EntryInfo, FileEntry, Record};
+use crate::types::Result;
+
+/// Magic number closing every finalized shard.
+pub const MAGIC: [u8; 8] = *b"SHRDPACK";
+
+/// Size of the fixed prefix of a record block: the block size (`u64`) followed by the
+/// SHA-256 checksum of the rest of the block.
+pub const BLOCK_PREFIX_SIZE: usize = 8 + 32;
+
+/// Marker opening the index section. It takes the place of a block size, which is never
+/// zero, so that a sequential reader knows it has reached the end of the record blocks.
+pub const INDEX_MARKER: u64 = 0;
+
+/// Size of the footer closing a shard written with `FORMAT_VERSION`: block alignment, index
+/// offset, index length, required and optional feature sets and format version (`u64` each),
+/// followed by the magic number. Footers of older versions are never larger.
+pub const FOOTER_SIZE: usize = 6 * 8 + MAGIC.len();
+
+/// Size of the footer of format version 1, which has no block alignment.
+const FOOTER_V1_SIZE: usize = 5 * 8 + MAGIC.len();
+
+/// Version of the shard format written by this crate.
+pub const FORMAT_VERSION: u64 = 2;
+
+/// Oldest shard format version this crate can still read.
+pub const MIN_FORMAT_VERSION: u64 = 1;
+
+/// Required feature: record blocks and file entries carry SHA-256 checksums.
+pub const FEATURE_SHA256: u64 = 1 << 0;
+
+/// Required feature: some file entries are compressed with gzip.
+pub const FEATURE_GZIP: u64 = 1 << 1;
+
+/// Required feature: some file entries are compressed with LZ4.
+pub const FEATURE_LZ4: u64 = 1 << 2;
+
+/// Required feature: some file entries are compressed with Zstandard.
+pub const FEATURE_ZSTD: u64 = 1 << 3;
+
+/// Required feature: some file entries are compressed with Snappy.
+pub const FEATURE_SNAPPY: u64 = 1 << 4;
+
+/// Required feature: some records are chunks of a record split across shards.
+pub const FEATURE_CHUNKED: u64 = 1 << 5;
+
+/// Optional feature: every record block starts at a multiple of the footer's `alignment`.
+pub const FEATURE_ALIGNED_BLOCKS: u64 = 1 << 0;
+
+/// Optional feature: the content of every file entry also starts at a multiple of the
+/// footer's `alignment`.
+pub const FEATURE_ALIGNED_ENTRIES: u64 = 1 << 1;
+
+/// Required features this crate knows how to read. A shard requiring any other feature is
+/// refused, while unknown optional features are ignored.
+pub const SUPPORTED_FEATURES: u64 = FEATURE_SHA256 | FEATURE_GZIP | FEATURE_LZ4 | FEATURE_CHUNKED;
+
+/// Returns the required feature a reader needs to decode entries stored with `encoding`.
+pub fn compression_feature(encoding: CompressionType) -> u64 {
+    match encoding {
+        CompressionType::None => 0,
+        CompressionType::Gzip => FEATURE_GZIP,
+        CompressionType::Lz4 => FEATURE_LZ4,
+        CompressionType::Zstd => FEATURE_ZSTD,
+        CompressionType::Snappy => FEATURE_SNAPPY,
+    }
+}
+
+/// Size of the fixed part of a record block preceding its header: the block prefix followed
+/// by the length of the serialized `RecordHeader` (`u64`).
+pub const RECORD_HEADER_OFFSET: usize = BLOCK_PREFIX_SIZE + 8;
+
+/// Header opening the body of a record block, acting as the directory of its file entries.
+///
+/// # Fields
+///
+/// * `key` - The key identifying the record.
+/// * `metadata` - Record-level metadata, opaque to the shard format.
+/// * `entries` - The directory of file entries whose content follows the header.
+/// * `chunk` - The position of the record within a record split across shards, if any.
+#[derive(Clone, Debug)]
+pub struct RecordHeader {
+    pub key: String,
+    pub metadata: Vec<u8>,
+    pub entries: Vec<EntryHeader>,
+    pub chunk: Option<ChunkInfo>,
+}
+
+/// Directory entry describing the content of one file entry within a record block.
+///
+/// # Fields
+///
+/// * `name` - The name of the file entry, e.g. `left.jpg`.
+/// * `content_type` - The content type of the entry, e.g. `image/jpeg`.
+/// * `encoding` - The compression applied to the stored content.
+/// * `offset` - The offset of the stored content from the end of the record header.
+/// * `size` - The size of the stored (encoded) file content in bytes.
+/// * `raw_size` - The size of the decoded file content in bytes.
+/// * `checksum` - A 32-byte SHA-256 checksum of the stored content.
+#[derive(Clone, Debug)]
+pub struct EntryHeader {
+    pub name: String,
+    pub content_type: String,
+    pub encoding: CompressionType,
+    pub offset: u64,
+    pub size: u64,
+    pub raw_size: u64,
+    pub checksum: [u8; 32],
+}
+
+impl RecordHeader {
+    /// Returns the directory entry of the file entry named `name`, if any.
+    pub fn entry(&self, name: &str) -> Option<&EntryHeader> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+}
+
+impl From<&EntryHeader> for EntryInfo {
+    fn from(entry: &EntryHeader) -> Self {
+        Self {
+            name: entry.name.clone(),
+            content_type: entry.content_type.clone(),
+            encoding: entry.encoding,
+            size: entry.raw_size,
+            stored_size: entry.size,
+        }
+    }
+}
+
+/// Returns the required features a reader needs to decode `record`.
+pub fn record_features(record: &Record) -> u64 {
+    let chunked = if record.chunk().is_some() { FEATURE_CHUNKED } else { 0 };
+    record.entries().iter()
+        .fold(FEATURE_SHA256 | chunked, |features, entry| features | compression_feature(entry.encoding()))
+}
+
+/// The index written at the end of a shard, after all record blocks.
+///
+/// # Fields
+///
+/// * `records` - The location of every record block, in write order.
+/// * `metadata` - Shard-level metadata, opaque to the shard format.
+#[derive(Default)]
+pub struct ShardIndex {
+    pub records: Vec<RecordEntry>,
+    pub metadata: Vec<u8>,
+}
+
+/// The chunk position is only encoded for chunks, after the entries. Headers are delimited
+/// by their length, so a header of a whole record simply ends after the entries.
+impl Encode for RecordHeader {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.key);
+        put_bytes(buf, &self.metadata);
+        put_seq(buf, &self.entries);
+        if let Some(chunk) = &self.chunk {
+            chunk.encode(buf);
+        }
+    }
+}
+
+impl Decode for RecordHeader {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            key: decoder.string()?,
+            metadata: decoder.bytes()?,
+            entries: decoder.seq()?,
+            chunk: match decoder.remaining() {
+                0 => None,
+                _ => Some(ChunkInfo::decode(decoder)?),
+            },
+        })
+    }
+}
+
+/// Chunk positions are encoded as the index of the chunk followed by a `u64` of flags, of
+/// which bit 0 marks the last chunk.
+impl Encode for ChunkInfo {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.index);
+        put_u64(buf, self.last as u64);
+    }
+}
+
+impl Decode for ChunkInfo {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        let index = decoder.u64()?;
+        let flags = decoder.u64()?;
+        if flags & !1 != 0 {
+            return Err(Error::Format("Unknown chunk flags".into()));
+        }
+        Ok(Self { index, last: flags & 1 != 0 })
+    }
+}
+
+impl Encode for EntryHeader {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.name);
+        put_str(buf, &self.content_type);
+        self.encoding.encode(buf);
+        put_u64(buf, self.offset);
+        put_u64(buf, self.size);
+        put_u64(buf, self.raw_size);
+        buf.extend_from_slice(&self.checksum);
+    }
+}
+
+impl Decode for EntryHeader {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            name: decoder.string()?,
+            content_type: decoder.string()?,
+            encoding: CompressionType::decode(decoder)?,
+            offset: decoder.u64()?,
+            size: decoder.u64()?,
+            raw_size: decoder.u64()?,
+            checksum: decoder.array()?,
+        })
+    }
+}
+
+/// Compression types are encoded as a `u32` tag, in declaration order starting at 0.
+impl Encode for CompressionType {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let tag = match self {
+            CompressionType::None => 0,
+            CompressionType::Gzip => 1,
+            CompressionType::Lz4 => 2,
+            CompressionType::Zstd => 3,
+            CompressionType::Snappy => 4,
+        };
+        put_u32(buf, tag);
+    }
+}
+
+impl Decode for CompressionType {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        match decoder.u32()? {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Gzip),
+            2 => Ok(CompressionType::Lz4),
+            3 => Ok(CompressionType::Zstd),
+            4 => Ok(CompressionType::Snappy),
+            tag => Err(Error::Format(format!("Unknown compression type {}", tag))),
+        }
+    }
+}
+
+impl Encode for ShardIndex {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_seq(buf, &self.records);
+        put_bytes(buf, &self.metadata);
+    }
+}
+
+impl Decode for ShardIndex {
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            records: decoder.seq()?,
+            metadata: decoder.bytes()?,
+        })
+    }
+}
+
+
+/// The footer closing a shard, pointing back at the index.
+///
+/// # Fields
+///
+/// * `alignment` - The boundary record blocks are aligned to, 1 if they are not aligned.
+/// * `index_offset` - The offset of the index section within the shard, which is where the
+///   record blocks end.
+/// * `index_len` - The size of the index section in bytes.
+/// * `required_features` - Features a reader must support to read the shard.
+/// * `optional_features` - Features a reader may ignore.
+/// * `version` - The format version the shard was written with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Footer {
+    pub alignment: u64,
+    pub index_offset: u64,
+    pub index_len: u64,
+    pub required_features: u64,
+    pub optional_features: u64,
+    pub version: u64,
+}
+
+impl Footer {
+    /// Encodes the footer as `alignment | index_offset | index_len | required_features |
+    /// optional_features | version | MAGIC`, integers little-endian.
+    ///
+    /// The footer is always encoded with the layout of `FORMAT_VERSION`; `version` should
+    /// match it.
+    pub fn to_bytes(&self) -> [u8; FOOTER_SIZE] {
+        let mut bytes = [0u8; FOOTER_SIZE];
+        bytes[0..8].copy_from_slice(&self.alignment.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.index_offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.index_len.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.required_features.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.optional_features.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.version.to_le_bytes());
+        bytes[48..].copy_from_slice(&MAGIC);
+        bytes
+    }
+
+    /// Decodes the footer at the end of `bytes`, checking the magic number, the format version
+    /// and the required features.
+    ///
+    /// Every version keeps the version and the magic number in the last 16 bytes and only adds
+    /// fields in front of the previous layout, so the version tells how large the footer is.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The end of a shard, holding at least its footer. The last `FOOTER_SIZE` bytes
+    ///   always suffice.
+    ///
+    /// # Returns
+    ///
+    /// The decoded `Footer`, a format error if `bytes` does not end in a footer, or
+    /// `Error::UnsupportedFormat` if the shard was written with a version or required feature
+    /// this crate cannot read.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < FOOTER_V1_SIZE || !bytes.ends_with(&MAGIC) {
+            return Err(Error::Format("Missing shard footer magic".into()));
+        }
+
+        let version = read_u64(&bytes[bytes.len() - 16..]);
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(Error::UnsupportedFormat(format!(
+                "Shard format version {} is not supported (supported: {} to {})",
+                version, MIN_FORMAT_VERSION, FORMAT_VERSION,
+            )));
+        }
+        let size = footer_size(version);
+        if bytes.len() < size {
+            return Err(Error::Format("Truncated shard footer".into()));
+        }
+
+        let bytes = &bytes[bytes.len() - size..];
+        let (alignment, fields) = match version {
+            1 => (1, bytes),
+            _ => (read_u64(bytes), &bytes[8..]),
+        };
+        let footer = Self {
+            alignment,
+            index_offset: read_u64(&fields[0..8]),
+            index_len: read_u64(&fields[8..16]),
+            required_features: read_u64(&fields[16..24]),
+            optional_features: read_u64(&fields[24..32]),
+            version,
+        };
+
+        let unsupported = footer.required_features & !SUPPORTED_FEATURES;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFormat(format!(
+                "Shard requires unsupported features {:#x}",
+                unsupported,
+            )));
+        }
+        Ok(footer)
+    }
+
+    /// Returns the size of the encoded footer, which depends on its version.
+    pub fn size(&self) -> usize {
+        footer_size(self.version)
+    }
+}
+
+/// Returns the size of a footer written with format `version`.
+fn footer_size(version: u64) -> usize {
+    match version {
+        1 => FOOTER_V1_SIZE,
+        _ => FOOTER_SIZE,
+    }
+}
+
+/// Encodes the index section: the `INDEX_MARKER` followed by the encoded `ShardIndex`.
+pub fn encode_index(index: &ShardIndex) -> Vec<u8> {
+    let mut bytes = INDEX_MARKER.to_le_bytes().to_vec();
+    index.encode(&mut bytes);
+    bytes
+}
+
+/// Decodes an index section produced by `encode_index`.
+pub fn decode_index(bytes: &[u8]) -> Result<ShardIndex> {
+    if bytes.len() < 8 || read_u64(bytes) != INDEX_MARKER {
+        return Err(Error::Format("Missing shard index marker".into()));
+    }
+    ShardIndex::from_bytes(&bytes[8..])
+}
+
+/// Reads a little-endian `u64` from the first eight bytes of `bytes`.
+pub(crate) fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Encodes a complete record block.
+///
+/// The block starts with its total size and the checksum of everything after the checksum,
+/// followed by the length of the `RecordHeader`, the header itself and the content of every
+/// file entry, encoded with the entry's compression. The header lists where each entry's
+/// content lives so that a single entry can be read without the rest of the block.
+///
+/// The block is padded with zeros to a multiple of `alignment`, so that the next block of the
+/// shard starts on the boundary too. The padding is part of the block and covered by its size
+/// and checksum, so readers skip it without knowing the alignment.
+///
+/// # Arguments
+///
+/// * `record` - The record to encode.
+/// * `alignment` - The boundary to pad the block to, 1 for none.
+/// * `align_entries` - Whether to also pad the content of every file entry so that it starts
+///   at a multiple of `alignment` from the start of the block.
+///
+/// # Returns
+///
+/// The encoded block together with the checksum stored in its prefix.
+pub fn encode_record_block(record: &Record, alignment: u64, align_entries: bool) -> Result<(Vec<u8>, [u8; 32])> {
+    let alignment = alignment.max(1) as usize;
+    let mut contents = Vec::with_capacity(record.entries().len());
+    let mut entries = Vec::with_capacity(record.entries().len());
+    for entry in record.entries() {
+        let data = entry.encoding().compress(entry.data())?;
+        entries.push(EntryHeader {
+            name: entry.name().to_string(),
+            content_type: entry.content_type().to_string(),
+            encoding: entry.encoding(),
+            offset: 0,
+            size: data.len() as u64,
+            raw_size: entry.data().len() as u64,
+            checksum: compute_checksum(&data),
+        });
+        contents.push(data);
+    }
+
+    // Offsets are fixed-width, so the header size does not depend on them
+    let mut header = RecordHeader {
+        key: record.key().to_string(),
+        metadata: record.metadata().to_vec(),
+        entries,
+        chunk: record.chunk(),
+    };
+    let payload_start = RECORD_HEADER_OFFSET + header.to_bytes().len();
+    let mut payload = Vec::new();
+    for (entry, data) in header.entries.iter_mut().zip(&contents) {
+        if align_entries {
+            payload.resize(padded_len(payload_start + payload.len(), alignment) - payload_start, 0);
+        }
+        entry.offset = payload.len() as u64;
+        payload.extend_from_slice(data);
+    }
+    let header = header.to_bytes();
+
+    // Reserve the prefix and fill it in once the body is known
+    let block_len = padded_len(payload_start + payload.len(), alignment);
+    let mut block = Vec::with_capacity(block_len);
+    block.resize(BLOCK_PREFIX_SIZE, 0);
+    block.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    block.extend_from_slice(&header);
+    block.extend_from_slice(&payload);
+    block.resize(block_len, 0);
+
+    let checksum = compute_checksum(&block[BLOCK_PREFIX_SIZE..]);
+    let block_size = block.len() as u64;
+    block[0..8].copy_from_slice(&block_size.to_le_bytes());
+    block[8..BLOCK_PREFIX_SIZE].copy_from_slice(&checksum);
+    Ok((block, checksum))
+}
+
+/// Rounds `len` up to a multiple of `alignment`.
+fn padded_len(len: usize, alignment: usize) -> usize {
+    len.div_ceil(alignment) * alignment
+}
+
+/// Decodes the header of a record block from the start of the block.
+///
+/// # Arguments
+///
+/// * `bytes` - The first bytes of a record block, at least up to the end of its header.
+///
+/// # Returns
+///
+/// The decoded `RecordHeader` and the offset of the entry content within the block, or
+/// `None` if `bytes` does not reach the end of the header yet.
+pub fn decode_record_header(bytes: &[u8]) -> Result<Option<(RecordHeader, usize)>> {
+    if bytes.len() < RECORD_HEADER_OFFSET {
+        return Ok(None);
+    }
+
+    let header_end = record_header_end(bytes)? as usize;
+    if bytes.len() < header_end {
+        return Ok(None);
+    }
+
+    let header = RecordHeader::from_bytes(&bytes[RECORD_HEADER_OFFSET..header_end])?;
+    Ok(Some((header, header_end)))
+}
+
+/// Returns the offset of the end of the record header within its block.
+///
+/// # Arguments
+///
+/// * `bytes` - The first bytes of a record block, at least up to `header_len`.
+///
+/// # Returns
+///
+/// The end of the header, or `Error::Format` if `header_len` puts it past the end of the block.
+pub(crate) fn record_header_end(bytes: &[u8]) -> Result<u64> {
+    read_u64(&bytes[BLOCK_PREFIX_SIZE..])
+        .checked_add(RECORD_HEADER_OFFSET as u64)
+        .filter(|&end| end <= read_u64(bytes))
+        .ok_or_else(|| Error::Format("Record header exceeds record block".into()))
+}
+
+/// Decodes the stored content of a file entry and verifies it against its directory entry.
+///
+/// # Arguments
+///
+/// * `entry` - The directory entry of the file entry.
+/// * `data` - The stored content of the file entry. Uncompressed content is returned as-is,
+///   without copying.
+pub fn decode_entry(entry: &EntryHeader, data: Bytes) -> Result<FileEntry> {
+    verify_checksum(&data, &entry.checksum)?;
+    let data = match entry.encoding {
+        CompressionType::None => data,
+        encoding => Bytes::from(encoding.decompress(&data)?),
+    };
+    if data.len() as u64 != entry.raw_size {
+        return Err(Error::Format("File entry size mismatch".into()));
+    }
+    Ok(FileEntry::new(&entry.name, &entry.content_type, data).with_encoding(entry.encoding))
+}
+
+/// Decodes a complete record block produced by `encode_record_block`.
+///
+/// The size stored in the prefix must match the length of `block`, and the body must match
+/// the checksum stored in the prefix. When the index is known, the stored checksum must also
+/// match the one recorded there.
+///
+/// # Arguments
+///
+/// * `block` - The bytes of exactly one record block. Uncompressed entries of the record share
+///   this buffer.
+/// * `expected_checksum` - The checksum of the block recorded in the index, if any.
+///
+/// # Returns
+///
+/// The decoded `Record`, or an error if the block is truncated, corrupt or malformed.
+pub fn decode_record_block(block: Bytes, expected_checksum: Option<&[u8; 32]>) -> Result<Record> {
+    if block.len() < BLOCK_PREFIX_SIZE || read_u64(&block[0..8]) != block.len() as u64 {
+        return Err(Error::Format("Record block size mismatch".into()));
+    }
+
+    let mut checksum = [0u8; 32];
+    checksum.copy_from_slice(&block[8..BLOCK_PREFIX_SIZE]);
+    if expected_checksum.is_some_and(|expected| *expected != checksum) {
+        return Err(Error::Storage("Checksum mismatch".into()));
+    }
+    verify_checksum(&block[BLOCK_PREFIX_SIZE..], &checksum)?;
+
+    let (header, payload_start) = decode_record_header(&block)?
+        .ok_or_else(|| Error::Format("Truncated record header".into()))?;
+    let payload = block.slice(payload_start..);
+
+    let mut entries = Vec::with_capacity(header.entries.len());
+    for entry in &header.entries {
+        let data = entry_range(entry)
+            .filter(|range| range.end <= payload.len())
+            .map(|range| payload.slice(range))
+            .ok_or_else(|| Error::Format("File entry exceeds record block".into()))?;
+        entries.push(decode_entry(entry, data)?);
+    }
+
+    Ok(Record::new(header.key, header.metadata, entries).with_chunk(header.chunk))
+}
+
+/// Returns the range of the stored content of `entry`, relative to the end of the header.
+fn entry_range(entry: &EntryHeader) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(entry.offset).ok()?;
+    let end = start.checked_add(usize::try_from(entry.size).ok()?)?;
+    Some(start..end)
+}
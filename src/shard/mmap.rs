@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use memmap2::Mmap;
+
+use crate::error::Error;
+use crate::index::entry::RecordEntry;
+use crate::shard::format::{
+    decode_entry, decode_index, decode_record_block, decode_record_header, Footer, RecordHeader, ShardIndex,
+    FOOTER_SIZE,
+};
+use crate::shard::record::{EntryInfo, FileEntry, Record};
+use crate::types::Result;
+
+/// Represents a zero-copy reader over a memory-mapped local shard.
+///
+/// A `MmapShardReader` maps a finalized shard once and decodes its footer and index. Record
+/// blocks and uncompressed file entries are returned as slices of the mapping, so reading them
+/// copies nothing; only compressed entries are decoded into new buffers. Cloning the reader is
+/// cheap and shares the mapping, so one reader can serve many tasks at once.
+///
+/// The mapped file must not be modified while the reader or any slice of it is alive. Shards
+/// are never modified in place: `ShardWriter::reopen` and `ShardReader::recover` replace the
+/// file as a whole, which leaves existing mappings untouched.
+#[derive(Clone)]
+pub struct MmapShardReader {
+    /// The mapped shard.
+    data: Bytes,
+
+    /// The footer read from the end of the shard.
+    footer: Footer,
+
+    /// The index read from the end of the shard.
+    index: Arc<ShardIndex>,
+
+    /// Maps record keys to their position in `index.records`.
+    keys: Arc<HashMap<String, usize>>,
+}
+
+impl MmapShardReader {
+    /// Maps the shard at `path` and reads its footer and index.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the shard file on the local file system.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with a reader ready for random access, `Error::UnsupportedFormat` if the
+    ///   shard was written with an unknown version or required feature, or an error if the
+    ///   file cannot be mapped or has no valid footer.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: shards are immutable once finalized and only ever replaced as a whole, so
+        // the mapped bytes do not change underneath the slices handed out by the reader.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let data = Bytes::from_owner(mmap);
+
+        let footer = Footer::from_bytes(&data[data.len().saturating_sub(FOOTER_SIZE)..])?;
+        let footer_offset = data.len() - footer.size();
+        if footer.index_offset.checked_add(footer.index_len) != Some(footer_offset as u64) {
+            return Err(Error::Format("Shard index does not end at the footer".into()));
+        }
+        let index = decode_index(&data[footer.index_offset as usize..footer_offset])?;
+
+        let keys = index.records.iter()
+            .enumerate()
+            .map(|(position, entry)| (entry.key.clone(), position))
+            .collect();
+
+        Ok(Self { data, footer, index: Arc::new(index), keys: Arc::new(keys) })
+    }
+
+    /// Returns the format version the shard was written with.
+    pub fn format_version(&self) -> u64 {
+        self.footer.version
+    }
+
+    /// Returns the boundary the record blocks of the shard are aligned to, 1 if unaligned.
+    pub fn alignment(&self) -> u64 {
+        self.footer.alignment
+    }
+
+    /// Returns the number of records in the shard.
+    pub fn len(&self) -> usize {
+        self.index.records.len()
+    }
+
+    /// Returns `true` if the shard holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.index.records.is_empty()
+    }
+
+    /// Returns the location of every record block, in write order.
+    pub fn entries(&self) -> &[RecordEntry] {
+        &self.index.records
+    }
+
+    /// Returns the shard-level metadata stored in the index.
+    pub fn metadata(&self) -> &[u8] {
+        &self.index.metadata
+    }
+
+    /// Returns the raw record block at position `index`, as a slice of the mapping.
+    pub fn record_block(&self, index: usize) -> Result<Bytes> {
+        let entry = self.index.records.get(index)
+            .ok_or_else(|| Error::Index(format!("Record {} out of range", index)))?;
+        self.block(entry)
+    }
+
+    /// Reads the record at position `index` in the shard.
+    ///
+    /// # Returns
+    /// * `Result<Record>` with the decoded record, whose uncompressed entries share the mapping,
+    ///   or an error if `index` is out of range or the record block is corrupt.
+    pub fn read_record(&self, index: usize) -> Result<Record> {
+        let entry = self.index.records.get(index)
+            .ok_or_else(|| Error::Index(format!("Record {} out of range", index)))?;
+
+        let record = decode_record_block(self.block(entry)?, Some(&entry.checksum))?;
+        if record.key() != entry.key {
+            return Err(Error::Format("Record key does not match shard index".into()));
+        }
+        Ok(record)
+    }
+
+    /// Reads the record stored under `key`.
+    pub fn read_record_by_key(&self, key: &str) -> Result<Record> {
+        let index = *self.keys.get(key)
+            .ok_or_else(|| Error::Storage("Key not found".into()))?;
+        self.read_record(index)
+    }
+
+    /// Describes the file entries of the record stored under `key`.
+    pub fn describe_record(&self, key: &str) -> Result<Vec<EntryInfo>> {
+        let (header, _, _) = self.record_header(key)?;
+        Ok(header.entries.iter().map(EntryInfo::from).collect())
+    }
+
+    /// Reads a single file entry of the record stored under `key`.
+    ///
+    /// Only the checksum of the requested entry is verified, and uncompressed content is
+    /// returned as a slice of the mapping.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the record.
+    /// * `name` - The name of the file entry, e.g. `meta.json`.
+    pub fn read_entry(&self, key: &str, name: &str) -> Result<FileEntry> {
+        let (header, block, payload_start) = self.record_header(key)?;
+        let entry = header.entry(name)
+            .ok_or_else(|| Error::Storage(format!("Entry {} not found", name)))?;
+
+        let start = payload_start as u64 + entry.offset;
+        let end = start.checked_add(entry.size)
+            .filter(|&end| end <= block.len() as u64)
+            .ok_or_else(|| Error::Format("File entry exceeds record block".into()))?;
+        decode_entry(entry, block.slice(start as usize..end as usize))
+    }
+
+    /// Decodes the header of the record stored under `key`, returning it together with the
+    /// record block and the offset of the entry content within the block.
+    fn record_header(&self, key: &str) -> Result<(RecordHeader, Bytes, usize)> {
+        let index = *self.keys.get(key)
+            .ok_or_else(|| Error::Storage("Key not found".into()))?;
+        let block = self.block(&self.index.records[index])?;
+
+        let (header, payload_start) = decode_record_header(&block)?
+            .ok_or_else(|| Error::Format("Truncated record header".into()))?;
+        if header.key != key {
+            return Err(Error::Index("Index entry does not point at the key".into()));
+        }
+        Ok((header, block, payload_start))
+    }
+
+    /// Returns the record block described by `entry`, checking that it lies within the
+    /// record data of the shard.
+    fn block(&self, entry: &RecordEntry) -> Result<Bytes> {
+        let end = entry.offset.checked_add(entry.size)
+            .filter(|&end| end <= self.footer.index_offset)
+            .ok_or_else(|| Error::Format("Record block exceeds shard data".into()))?;
+        Ok(self.data.slice(entry.offset as usize..end as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::compression::CompressionType;
+    use crate::shard::writer::ShardWriter;
+    use crate::storage::LocalStorageProvider;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    async fn write_shard(root: &TempDir) -> PathBuf {
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let mut writer = ShardWriter::create(provider, "shard").with_metadata(b"shard-meta".to_vec());
+        writer.write_record(&Record::builder("images17/image194")
+            .entry("left.jpg", "image/jpeg", vec![7u8; 4096])
+            .entry_with_encoding("meta.json", "application/json", CompressionType::Gzip, b"{}".to_vec())
+            .build()).await.unwrap();
+        writer.write("key2", b"more_data", Some(b"metadata")).await.unwrap();
+        writer.finalize().await.unwrap();
+        root.path().join("shard")
+    }
+
+    #[tokio::test]
+    async fn test_read_records_without_copy() {
+        let root = TempDir::new().unwrap();
+        let reader = MmapShardReader::open(write_shard(&root).await).unwrap();
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.metadata(), b"shard-meta");
+
+        let mapping = reader.data.as_ptr_range();
+        let record = reader.read_record_by_key("images17/image194").unwrap();
+        let left = record.entry("left.jpg").unwrap();
+        assert_eq!(left.data(), &[7u8; 4096]);
+        assert!(mapping.contains(&left.data().as_ptr()));
+        assert_eq!(record.entry("meta.json").unwrap().data(), b"{}");
+
+        let entry = reader.read_entry("images17/image194", "left.jpg").unwrap();
+        assert!(mapping.contains(&entry.data().as_ptr()));
+        assert_eq!(reader.read_entry("images17/image194", "meta.json").unwrap().data(), b"{}");
+        assert_eq!(reader.describe_record("images17/image194").unwrap()[1].encoding, CompressionType::Gzip);
+        assert_eq!(reader.read_record(1).unwrap().metadata(), b"metadata");
+        assert!(reader.read_record(2).is_err());
+        assert!(reader.read_entry("missing", "left.jpg").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shared_across_tasks() {
+        let root = TempDir::new().unwrap();
+        let reader = MmapShardReader::open(write_shard(&root).await).unwrap();
+
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let reader = reader.clone();
+                tokio::spawn(async move { reader.read_record(i % 2).unwrap().key().to_string() })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), reader.entries()[i % 2].key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_unfinalized_shard() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(MmapShardReader::open(&path).is_err());
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(MmapShardReader::open(&path).is_err());
+    }
+}
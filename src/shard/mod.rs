@@ -1,5 +1,17 @@
+mod mmap;
 mod reader;
-mod writer;
+pub(crate) mod writer;
 
 pub mod config;
-pub mod shard;
\ No newline at end of file
+pub mod format;
+pub mod record;
+pub mod set;
+#[allow(clippy::module_inception)]
+pub mod shard;
+
+pub(crate) use reader::{read_entry_content, read_index, read_record_header};
+pub use mmap::MmapShardReader;
+pub use reader::{ShardReader};
+pub use record::{ChunkInfo, EntryInfo, FileEntry, Record, RecordBuilder};
+pub use set::{ShardNameTemplate, ShardSet, ShardSetReader};
+pub use writer::ShardWriter;
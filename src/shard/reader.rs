@@ -1,27 +1,919 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use serde::{Deserialize, Serialize};
+use bytes::Bytes;
+use futures::stream::{self, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::StorageProvider;
+use crate::error::Error;
+use crate::index::entry::RecordEntry;
+use crate::shard::format::{
+    decode_entry, decode_index, decode_record_block, decode_record_header, read_u64, record_header_end,
+    EntryHeader, Footer, RecordHeader, ShardIndex, BLOCK_PREFIX_SIZE, FOOTER_SIZE, INDEX_MARKER, RECORD_HEADER_OFFSET,
+};
+use crate::shard::record::{EntryInfo, FileEntry, Record};
+use crate::shard::writer::ShardWriter;
 use crate::types::Result;
 
-#[derive(Serialize, Deserialize)]
+/// Represents a reader providing random access to the records of a finalized shard.
+///
+/// A `ShardReader` is opened from the footer and index at the end of a shard and
+/// fetches individual record blocks on demand, so that reading one record never
+/// requires loading the rest of the shard.
 pub struct ShardReader<W: StorageProvider> {
+    /// The storage provider holding the shard.
     reader: W,
-    path: PathBuf
+
+    /// The path of the shard within the storage provider.
+    path: PathBuf,
+
+    /// The footer read from the end of the shard.
+    footer: Footer,
+
+    /// The index read from the end of the shard.
+    index: ShardIndex,
+
+    /// Maps record keys to their position in `index.records`.
+    keys: HashMap<String, usize>,
 }
 
 impl<W: StorageProvider> Default for ShardReader<W> {
     fn default() -> Self {
-        Self { reader: Default::default(), path: Default::default() }
+        Self {
+            reader: Default::default(),
+            path: Default::default(),
+            footer: Default::default(),
+            index: Default::default(),
+            keys: Default::default(),
+        }
     }
 }
 
 impl<W: StorageProvider> ShardReader<W> {
-    
-    async fn read_all(&self) -> Result<Vec<u8>> {
+    /// Opens a finalized shard by reading its footer and index.
+    ///
+    /// # Arguments
+    /// * `reader` - The storage provider holding the shard.
+    /// * `path` - The path of the shard within the storage provider.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with a reader ready for random access, `Error::UnsupportedFormat` if the
+    ///   shard was written with an unknown version or required feature, or an error if the
+    ///   shard has no valid footer or its index cannot be decoded.
+    pub async fn open(reader: W, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let (footer, index) = read_index(&reader, &path).await?;
+
+        let keys = index.records.iter()
+            .enumerate()
+            .map(|(position, entry)| (entry.key.clone(), position))
+            .collect();
+
+        Ok(Self { reader, path, footer, index, keys })
+    }
+
+    /// Opens a shard, repairing it first if its writer died before finalizing it.
+    ///
+    /// A shard without a valid footer or index is scanned from the start, and every record
+    /// block up to the first one that is truncated or fails its checksum is kept. The kept
+    /// blocks are rewritten with a fresh index and footer to a staging object, which then
+    /// replaces the shard; everything after the last valid block is dropped, and so is the
+    /// shard-level metadata, which lived in the lost index. The alignment of the shard, also
+    /// lost with its footer, is inferred from the padding of the kept blocks. A shard that is
+    /// already finalized is opened as-is.
+    ///
+    /// # Arguments
+    /// * `reader` - The storage provider holding the shard.
+    /// * `path` - The path of the shard within the storage provider.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with a reader on the repaired shard, or an error if the shard cannot be
+    ///   read or rewritten. Shards written with an unsupported format are never rewritten.
+    pub async fn recover(reader: W, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        match read_index(&reader, &path).await {
+            Ok(_) => return Self::open(reader, path).await,
+            Err(Error::Format(_)) => {}
+            Err(err) => return Err(err),
+        }
+
+        let size = reader.stat(&path).await?.size;
+        let mut writer = ShardWriter::create_staged(reader, path.clone());
+        let mut layout = BlockLayout::default();
+        let mut offset = 0;
+        while let Some((record, block)) = read_valid_block(writer.provider(), &path, offset, size).await? {
+            let mut checksum = [0u8; 32];
+            checksum.copy_from_slice(&block[8..BLOCK_PREFIX_SIZE]);
+            layout.add(&block)?;
+            writer.write_block(&record, &block, checksum).await?;
+            offset += block.len() as u64;
+        }
+
+        // Blocks are copied as-is, so the alignment only needs to be known for the footer
+        let (alignment, align_entries) = layout.alignment();
+        let writer = writer.with_alignment(alignment, align_entries);
+        let (_, _, reader) = writer.finalize_into_provider().await?;
+        Self::open(reader, path).await
+    }
+
+    /// Returns the path of the shard within the storage provider.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns `true` if a record is stored under `key` in the shard.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    /// Returns the format version the shard was written with.
+    pub fn format_version(&self) -> u64 {
+        self.footer.version
+    }
+
+    /// Returns the features required to read the shard, as `FEATURE_*` bits.
+    pub fn required_features(&self) -> u64 {
+        self.footer.required_features
+    }
+
+    /// Returns the optional features the shard was written with.
+    pub fn optional_features(&self) -> u64 {
+        self.footer.optional_features
+    }
+
+    /// Returns the boundary the record blocks of the shard are aligned to, 1 if unaligned.
+    pub fn alignment(&self) -> u64 {
+        self.footer.alignment
+    }
+
+    /// Returns the number of records in the shard.
+    pub fn len(&self) -> usize {
+        self.index.records.len()
+    }
+
+    /// Returns `true` if the shard holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.index.records.is_empty()
+    }
+
+    /// Returns the location of every record block, in write order.
+    pub fn entries(&self) -> &[RecordEntry] {
+        &self.index.records
+    }
+
+    /// Returns the shard-level metadata stored in the index.
+    pub fn metadata(&self) -> &[u8] {
+        &self.index.metadata
+    }
+
+    /// Reads the record at position `index` in the shard.
+    ///
+    /// # Arguments
+    /// * `index` - The position of the record, in write order.
+    ///
+    /// # Returns
+    /// * `Result<Record>` with the decoded record, or an error if `index` is out of range or
+    ///   the record block is corrupt.
+    pub async fn read_record(&self, index: usize) -> Result<Record> {
+        let entry = self.index.records.get(index)
+            .ok_or_else(|| Error::Index(format!("Record {} out of range", index)))?;
+
+        entry.offset.checked_add(entry.size)
+            .filter(|&end| end <= self.footer.index_offset)
+            .ok_or_else(|| Error::Format("Record block exceeds shard data".into()))?;
+
+        let block = self.fetch(entry.offset, entry.size).await?;
+        let record = decode_record_block(block, Some(&entry.checksum))?;
+        if record.key() != entry.key {
+            return Err(Error::Format("Record key does not match shard index".into()));
+        }
+        Ok(record)
+    }
+
+    /// Reads the record stored under `key`.
+    ///
+    /// If the key was written more than once, the most recent record is returned.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the record.
+    ///
+    /// # Returns
+    /// * `Result<Record>` with the decoded record, or an error if the key is not in the shard.
+    pub async fn read_record_by_key(&self, key: &str) -> Result<Record> {
+        let index = *self.keys.get(key)
+            .ok_or_else(|| Error::Storage("Key not found".into()))?;
+        self.read_record(index).await
+    }
+
+    /// Returns the index entry of the record stored under `key`.
+    fn entry_by_key(&self, key: &str) -> Result<&RecordEntry> {
+        self.keys.get(key)
+            .map(|&index| &self.index.records[index])
+            .ok_or_else(|| Error::Storage("Key not found".into()))
+    }
+
+    /// Describes the file entries of the record stored under `key`, reading only its header.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the record.
+    ///
+    /// # Returns
+    /// * `Result<Vec<EntryInfo>>` with the name, content type and sizes of every entry.
+    pub async fn describe_record(&self, key: &str) -> Result<Vec<EntryInfo>> {
+        let entry = self.entry_by_key(key)?;
+        let (header, _) = read_record_header(&self.reader, &self.path, key, entry.offset, entry.size).await?;
+        Ok(header.entries.iter().map(EntryInfo::from).collect())
+    }
+
+    /// Reads a single file entry of the record stored under `key`.
+    ///
+    /// Only the record header and the content of the requested entry are fetched.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the record.
+    /// * `name` - The name of the file entry, e.g. `meta.json`.
+    ///
+    /// # Returns
+    /// * `Result<FileEntry>` with the decoded entry, or an error if the key or entry is missing.
+    pub async fn read_entry(&self, key: &str, name: &str) -> Result<FileEntry> {
+        let entry = self.entry_by_key(key)?;
+        read_entry_at(&self.reader, &self.path, key, entry.offset, entry.size, name).await
+    }
+
+    /// Reads the whole shard, including its index and footer.
+    pub async fn read_all(&self) -> Result<Bytes> {
         self.reader.read(&self.path).await
     }
 
+    /// Fetches `len` bytes of the shard starting at `offset`.
+    async fn fetch(&self, offset: u64, len: u64) -> Result<Bytes> {
+        self.reader.read_range(&self.path, offset, len).await
+    }
+
+    /// Streams the records of a shard front to back from `reader`, without using its index.
+    ///
+    /// Record blocks are decoded one after another until the index section or the end of the
+    /// input is reached, so that a shard can be consumed from a pipe or while it is still being
+    /// produced or downloaded. A block cut short by the end of the input is reported as an error.
+    ///
+    /// # Arguments
+    /// * `reader` - The shard bytes, starting at the first record block.
+    ///
+    /// # Returns
+    /// * A stream yielding every record in write order, ending after the last record block.
+    pub fn stream<R>(reader: R) -> impl Stream<Item = Result<Record>> + Send
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        stream::try_unfold(reader, |mut reader| async move {
+            match read_block(&mut reader).await? {
+                Some(block) => Ok(Some((decode_record_block(Bytes::from(block), None)?, reader))),
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+/// Reads the footer and index of the shard at `path` with two ranged reads.
+///
+/// # Arguments
+/// * `provider` - The storage provider holding the shard.
+/// * `path` - The path of the shard within the storage provider.
+///
+/// # Returns
+/// * `Result<(Footer, ShardIndex)>` with the decoded footer, whose `index_offset` is where
+///   record data ends, and the decoded index, or an error if the shard has no valid footer.
+pub(crate) async fn read_index<P: StorageProvider>(provider: &P, path: &Path) -> Result<(Footer, ShardIndex)> {
+    let size = provider.stat(path).await?.size;
+    let tail_len = size.min(FOOTER_SIZE as u64);
+    let tail = provider.read_range(path, size - tail_len, tail_len).await?;
+    let footer = Footer::from_bytes(&tail)?;
+    let footer_offset = size - footer.size() as u64;
+
+    if footer.index_offset.checked_add(footer.index_len) != Some(footer_offset) {
+        return Err(Error::Format("Shard index does not end at the footer".into()));
+    }
+    let index = provider.read_range(path, footer.index_offset, footer.index_len).await?;
+    let index = decode_index(&index)?;
+
+    Ok((footer, index))
+}
+
+/// Number of bytes fetched when reading a record header, enough for the directory of
+/// most records so that the header takes a single ranged read.
+const HEADER_READ_SIZE: u64 = 4096;
+
+/// Reads the header of the record block at `offset` without fetching its entry content.
+///
+/// # Arguments
+/// * `provider` - The storage provider holding the shard.
+/// * `path` - The path of the shard within the storage provider.
+/// * `key` - The key the record block is expected to hold.
+/// * `offset` - The offset of the record block within the shard.
+/// * `size` - The size of the record block in bytes.
+///
+/// # Returns
+/// * `Result<(RecordHeader, u64)>` with the decoded header and the offset within the shard
+///   where the content of the entries starts.
+pub(crate) async fn read_record_header<P: StorageProvider>(
+    provider: &P,
+    path: &Path,
+    key: &str,
+    offset: u64,
+    size: u64,
+) -> Result<(RecordHeader, u64)> {
+    let bytes = provider.read_range(path, offset, size.min(HEADER_READ_SIZE)).await?;
+    if bytes.len() < RECORD_HEADER_OFFSET || read_u64(&bytes) != size {
+        return Err(Error::Format("Record block size does not match shard index".into()));
+    }
+
+    let decoded = match decode_record_header(&bytes)? {
+        Some(decoded) => decoded,
+        None => {
+            // The header is larger than the first read; fetch the rest of it
+            let header_end = record_header_end(&bytes)?;
+            let fetched = bytes.len() as u64;
+            let mut header = bytes.to_vec();
+            header.extend_from_slice(&provider.read_range(path, offset + fetched, header_end - fetched).await?);
+            decode_record_header(&header)?
+                .ok_or_else(|| Error::Format("Truncated record header".into()))?
+        }
+    };
+
+    let (header, header_end) = decoded;
+    if header.key != key {
+        return Err(Error::Index("Index entry does not point at the key".into()));
+    }
+    Ok((header, offset + header_end as u64))
+}
+
+/// Reads the file entry `name` of the record block at `offset`, fetching only the record
+/// header and the content of that entry.
+pub(crate) async fn read_entry_at<P: StorageProvider>(
+    provider: &P,
+    path: &Path,
+    key: &str,
+    offset: u64,
+    size: u64,
+    name: &str,
+) -> Result<FileEntry> {
+    let (header, payload_offset) = read_record_header(provider, path, key, offset, size).await?;
+    let entry = header.entry(name)
+        .ok_or_else(|| Error::Storage(format!("Entry {} not found", name)))?;
+    read_entry_content(provider, path, offset + size, payload_offset, entry).await
+}
+
+/// Reads and decodes the content of the file entry described by `entry`.
+///
+/// # Arguments
+/// * `provider` - The storage provider holding the shard.
+/// * `path` - The path of the shard within the storage provider.
+/// * `block_end` - The offset within the shard where the record block ends.
+/// * `payload_offset` - The offset within the shard where the content of the entries starts,
+///   as returned by `read_record_header`.
+/// * `entry` - The directory entry of the file entry.
+pub(crate) async fn read_entry_content<P: StorageProvider>(
+    provider: &P,
+    path: &Path,
+    block_end: u64,
+    payload_offset: u64,
+    entry: &EntryHeader,
+) -> Result<FileEntry> {
+    let start = payload_offset.checked_add(entry.offset);
+    if start.and_then(|start| start.checked_add(entry.size)).is_none_or(|end| end > block_end) {
+        return Err(Error::Format("File entry exceeds record block".into()));
+    }
+    let data = provider.read_range(path, payload_offset + entry.offset, entry.size).await?;
+    decode_entry(entry, data)
+}
+
+/// Collects the layout of the record blocks of a shard during recovery, to infer the alignment
+/// it was written with.
+///
+/// Aligned writers pad every block with zeros up to a multiple of the alignment, while
+/// unaligned blocks end with the content of their last entry. The alignment is therefore only
+/// inferred if some block is padded, as the greatest common divisor of the block sizes. In the
+/// same way, entries are only considered aligned if some entry is preceded by padding.
+#[derive(Default)]
+struct BlockLayout {
+    /// The greatest common divisor of the block sizes.
+    block_gcd: u64,
+
+    /// The greatest common divisor of the offsets of the entry contents within their blocks.
+    entry_gcd: u64,
+
+    /// Whether some block extends past the content of its entries.
+    padded: bool,
+
+    /// Whether some entry content starts past the end of the header or previous entry.
+    entries_padded: bool,
+}
+
+impl BlockLayout {
+    /// Adds a valid record block to the layout.
+    fn add(&mut self, block: &[u8]) -> Result<()> {
+        let (header, header_end) = decode_record_header(block)?
+            .ok_or_else(|| Error::Format("Truncated record header".into()))?;
+        let mut content_end = header_end as u64;
+        for entry in &header.entries {
+            let start = header_end as u64 + entry.offset;
+            self.entries_padded |= start > content_end;
+            content_end = content_end.max(start + entry.size);
+            self.entry_gcd = gcd(self.entry_gcd, start);
+        }
+        self.padded |= (block.len() as u64) > content_end;
+        self.block_gcd = gcd(self.block_gcd, block.len() as u64);
+        Ok(())
+    }
+
+    /// Returns the alignment of the blocks, 1 if they are not aligned, and whether the content
+    /// of every entry is aligned as well.
+    fn alignment(&self) -> (u64, bool) {
+        if !self.padded || self.block_gcd <= 1 {
+            return (1, false);
+        }
+        match gcd(self.block_gcd, self.entry_gcd) {
+            alignment if self.entries_padded && alignment > 1 => (alignment, true),
+            _ => (self.block_gcd, false),
+        }
+    }
+}
+
+/// Returns the greatest common divisor of `a` and `b`, with `gcd(0, b) == b`.
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Reads the record block at `offset` during recovery.
+///
+/// # Returns
+/// * `Result<Option<(Record, Bytes)>>` with the decoded record and its block, or `None` if
+///   there is no complete, valid block at `offset`.
+async fn read_valid_block<P: StorageProvider>(
+    provider: &P,
+    path: &Path,
+    offset: u64,
+    size: u64,
+) -> Result<Option<(Record, Bytes)>> {
+    if size.saturating_sub(offset) < RECORD_HEADER_OFFSET as u64 {
+        return Ok(None);
+    }
+    let block_size = read_u64(&provider.read_range(path, offset, 8).await?);
+    if block_size < RECORD_HEADER_OFFSET as u64 || block_size > size - offset {
+        return Ok(None);
+    }
+
+    let block = provider.read_range(path, offset, block_size).await?;
+    match decode_record_block(block.clone(), None) {
+        Ok(record) => Ok(Some((record, block))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads the next record block from `reader`, or `None` at the index section or end of input.
+async fn read_block<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut size = [0u8; 8];
+    let mut filled = 0;
+    while filled < size.len() {
+        let read = reader.read(&mut size[filled..]).await?;
+        if read == 0 && filled == 0 {
+            return Ok(None);
+        }
+        if read == 0 {
+            return Err(Error::Format("Truncated record block".into()));
+        }
+        filled += read;
+    }
+
+    let block_size = read_u64(&size);
+    if block_size == INDEX_MARKER {
+        return Ok(None);
+    }
+    if block_size < BLOCK_PREFIX_SIZE as u64 {
+        return Err(Error::Format("Invalid record block size".into()));
+    }
+
+    // Grow the buffer as bytes arrive rather than trusting the size up front
+    let mut block = size.to_vec();
+    (&mut *reader).take(block_size - size.len() as u64).read_to_end(&mut block).await?;
+    if block.len() as u64 != block_size {
+        return Err(Error::Format("Truncated record block".into()));
+    }
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::compression::CompressionType;
+    use crate::shard::format::{
+        encode_index, FEATURE_ALIGNED_BLOCKS, FEATURE_ALIGNED_ENTRIES, FEATURE_GZIP, FEATURE_SHA256, FORMAT_VERSION,
+    };
+    use crate::shard::writer::{staging_path, ShardWriter, DEFAULT_ENTRY_NAME};
+    use crate::storage::LocalStorageProvider;
+    use futures::{StreamExt, TryStreamExt};
+    use byte_counter::counter::ByteCounter;
+    use tempfile::TempDir;
+
+    async fn write_shard(root: &TempDir) -> PathBuf {
+        let id = ByteCounter::default();
+        let path = PathBuf::from(id.to_string());
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+
+        let mut writer = ShardWriter::new(id, provider).with_metadata(b"shard-meta".to_vec());
+        writer.write("key1", b"some_data", None).await.unwrap();
+        writer.write("key2", b"more_data", Some(b"metadata")).await.unwrap();
+        writer.finalize().await.unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_open_reads_index() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.metadata(), b"shard-meta");
+        assert_eq!(reader.entries()[0].key, "key1");
+        assert_eq!(reader.entries()[1].offset, reader.entries()[0].size);
+    }
+
+    #[tokio::test]
+    async fn test_read_record() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+
+        let record = reader.read_record(1).await.unwrap();
+        assert_eq!(record.key(), "key2");
+        assert_eq!(record.metadata(), b"metadata");
+        assert_eq!(record.entries().len(), 1);
+        assert_eq!(record.entries()[0].data(), b"more_data");
+
+        let record = reader.read_record_by_key("key1").await.unwrap();
+        assert_eq!(record.metadata(), b"");
+        assert_eq!(record.entries()[0].data(), b"some_data");
+
+        assert!(reader.read_record(2).await.is_err());
+        assert!(reader.read_record_by_key("missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_record_shares_block_buffer() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let mut writer = ShardWriter::create(provider, "shard");
+        writer.write_record(&Record::builder("images17/image194")
+            .entry("left.jpg", "image/jpeg", b"left".to_vec())
+            .entry("right.jpg", "image/jpeg", b"right".to_vec())
+            .build()).await.unwrap();
+        writer.finalize().await.unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, "shard").await.unwrap();
+        let record = reader.read_record(0).await.unwrap();
+
+        // Both entries are slices of the one buffer the block was read into
+        let left = record.entry("left.jpg").unwrap().bytes();
+        let right = record.entry("right.jpg").unwrap().bytes();
+        assert_eq!(right.as_ptr(), left.as_ptr().wrapping_add(left.len()));
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_missing_footer() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        provider.write(Path::new("not-a-shard"), &[0u8; 64]).await.unwrap();
+
+        let result = ShardReader::open(provider, "not-a-shard").await;
+        assert!(matches!(result, Err(Error::Format(_))));
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_rejects_corrupt_header_len() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+
+        // Overwrite `header_len` of the first block with values past the end of the block
+        let full_path = root.path().join(&path);
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+        for header_len in [u64::MAX, u64::MAX - RECORD_HEADER_OFFSET as u64 + 1, reader.entries()[0].size] {
+            let mut data = std::fs::read(&full_path).unwrap();
+            data[BLOCK_PREFIX_SIZE..RECORD_HEADER_OFFSET].copy_from_slice(&header_len.to_le_bytes());
+            std::fs::write(&full_path, &data).unwrap();
+
+            assert!(matches!(reader.read_entry("key1", DEFAULT_ENTRY_NAME).await, Err(Error::Format(_))));
+            assert!(matches!(reader.read_record(0).await, Err(Error::Format(_) | Error::Storage(_))));
+            assert!(reader.read_entry("key2", DEFAULT_ENTRY_NAME).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_record_rejects_corrupt_entry() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+        let full_path = root.path().join(&path);
+        let original = std::fs::read(&full_path).unwrap();
+        let footer = Footer::from_bytes(&original[original.len() - FOOTER_SIZE..]).unwrap();
+        let (start, end) = (footer.index_offset as usize, (footer.index_offset + footer.index_len) as usize);
+
+        // Point the first index entry past the record data, overflowing in the first cases
+        for (offset, size) in [(u64::MAX, 1), (1, u64::MAX), (0, footer.index_offset + 1)] {
+            let mut index = decode_index(&original[start..end]).unwrap();
+            index.records[0].offset = offset;
+            index.records[0].size = size;
+            let mut data = original.clone();
+            data.splice(start..end, encode_index(&index));
+            std::fs::write(&full_path, &data).unwrap();
+
+            let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+            let reader = ShardReader::open(provider, path.clone()).await.unwrap();
+            assert!(matches!(reader.read_record(0).await, Err(Error::Format(_))));
+            assert!(reader.read_record(1).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_record_detects_corruption() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+
+        let full_path = root.path().join(&path);
+        let mut data = std::fs::read(&full_path).unwrap();
+        data[60] ^= 0xff;
+        std::fs::write(&full_path, data).unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+        assert!(reader.read_record(0).await.is_err());
+        assert!(reader.read_record(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stream_records() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+
+        let file = tokio::fs::File::open(root.path().join(&path)).await.unwrap();
+        let records: Vec<Record> = ShardReader::<LocalStorageProvider>::stream(file).try_collect().await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].key(), "key1");
+        assert_eq!(records[1].metadata(), b"metadata");
+        assert_eq!(records[1].entries()[0].data(), b"more_data");
+    }
+
+    #[tokio::test]
+    async fn test_stream_records_without_index() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+
+        let data = std::fs::read(root.path().join(&path)).unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+        let records_end = reader.footer.index_offset as usize;
+
+        // A shard still being written ends cleanly after its last complete record block
+        let records: Vec<Record> = ShardReader::<LocalStorageProvider>::stream(&data[..records_end]).try_collect().await.unwrap();
+        assert_eq!(records.len(), 2);
 
-}
\ No newline at end of file
+        // A block cut short is an error, after the records before it
+        let mut stream = Box::pin(ShardReader::<LocalStorageProvider>::stream(&data[..records_end - 1]));
+        assert_eq!(stream.next().await.unwrap().unwrap().key(), "key1");
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_entry_with_large_header() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = PathBuf::from("large_header");
+
+        // Enough entries for the directory to outgrow the speculative header read
+        let mut builder = Record::builder("frames");
+        for i in 0..200 {
+            builder = builder.entry(format!("frame_{:04}.png", i), "image/png", vec![i as u8; 16]);
+        }
+        let mut writer = ShardWriter::create(provider, path.clone());
+        writer.write_record(&builder.build()).await.unwrap();
+        writer.finalize().await.unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+        let entries = reader.describe_record("frames").await.unwrap();
+        assert_eq!(entries.len(), 200);
+        assert_eq!(entries[199].name, "frame_0199.png");
+
+        let entry = reader.read_entry("frames", "frame_0150.png").await.unwrap();
+        assert_eq!(entry.data(), &[150u8; 16]);
+        assert!(reader.read_entry("frames", "frame_0200.png").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_reads_version_and_features() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = PathBuf::from("features");
+
+        let record = Record::builder("key1")
+            .entry_with_encoding("data", "text/plain", CompressionType::Gzip, b"some_data".to_vec())
+            .build();
+        let mut writer = ShardWriter::create(provider, path.clone());
+        writer.write_record(&record).await.unwrap();
+        writer.finalize().await.unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+        assert_eq!(reader.format_version(), FORMAT_VERSION);
+        assert_eq!(reader.required_features(), FEATURE_SHA256 | FEATURE_GZIP);
+        assert_eq!(reader.optional_features(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_open_checks_version_and_features() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+        let file = root.path().join(&path);
+        let original = std::fs::read(&file).unwrap();
+        let footer_start = original.len() - FOOTER_SIZE;
+
+        let open_with = |field: usize, value: u64| {
+            let mut data = original.clone();
+            let start = footer_start + field * 8;
+            data[start..start + 8].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&file, data).unwrap();
+            let root = root.path().to_path_buf();
+            let path = path.clone();
+            async move {
+                let provider = LocalStorageProvider::new(root).await.unwrap();
+                ShardReader::open(provider, path).await
+            }
+        };
+
+        // Unknown optional features are ignored
+        assert!(open_with(4, 1 << 63).await.is_ok());
+        // Unknown required features and newer versions are refused
+        assert!(matches!(open_with(3, FEATURE_SHA256 | 1 << 63).await, Err(Error::UnsupportedFormat(_))));
+        assert!(matches!(open_with(5, FORMAT_VERSION + 1).await, Err(Error::UnsupportedFormat(_))));
+        assert!(matches!(open_with(5, 0).await, Err(Error::UnsupportedFormat(_))));
+    }
+
+    #[tokio::test]
+    async fn test_open_reads_version_1_footer() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+        let file = root.path().join(&path);
+
+        // Version 1 footers have no alignment in front of the index offset
+        let mut data = std::fs::read(&file).unwrap();
+        let footer_start = data.len() - FOOTER_SIZE;
+        data.drain(footer_start..footer_start + 8);
+        let version = data.len() - 16;
+        data[version..version + 8].copy_from_slice(&1u64.to_le_bytes());
+        std::fs::write(&file, data).unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path).await.unwrap();
+        assert_eq!(reader.format_version(), 1);
+        assert_eq!(reader.alignment(), 1);
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.read_record(1).await.unwrap().metadata(), b"metadata");
+    }
+
+    #[tokio::test]
+    async fn test_aligned_records_and_entries() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = PathBuf::from("aligned");
+
+        let record = Record::builder("key1")
+            .entry("a.bin", "application/octet-stream", vec![1; 100])
+            .entry("b.bin", "application/octet-stream", vec![2; 700])
+            .build();
+        let mut writer = ShardWriter::create(provider, path.clone()).with_alignment(512, true);
+        writer.write_record(&record).await.unwrap();
+        writer.write("key2", b"more_data", None).await.unwrap();
+        writer.finalize().await.unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path.clone()).await.unwrap();
+        assert_eq!(reader.alignment(), 512);
+        assert_eq!(reader.optional_features(), FEATURE_ALIGNED_BLOCKS | FEATURE_ALIGNED_ENTRIES);
+        for entry in reader.entries() {
+            assert_eq!(entry.offset % 512, 0);
+            assert_eq!(entry.size % 512, 0);
+        }
+        assert_eq!(reader.read_record(0).await.unwrap(), record);
+        assert_eq!(reader.read_entry("key1", "b.bin").await.unwrap().data(), &[2; 700]);
+
+        // Entry content starts on the boundary within the shard
+        let data = std::fs::read(root.path().join(&path)).unwrap();
+        let b_start = data.windows(700).position(|window| window == [2; 700]).unwrap();
+        assert_eq!(b_start % 512, 0);
+
+        // Sequential readers skip the padding
+        let records: Vec<Record> = ShardReader::<LocalStorageProvider>::stream(data.as_slice()).try_collect().await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].key(), "key2");
+    }
+
+    #[tokio::test]
+    async fn test_recover_unfinalized_shard() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+        let file = root.path().join(&path);
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path.clone()).await.unwrap();
+        let records_end = reader.footer.index_offset as usize;
+        let first_end = reader.entries()[0].size as usize;
+
+        // A writer died halfway through the second block and left garbage behind
+        let mut data = std::fs::read(&file).unwrap()[..records_end - 3].to_vec();
+        data.extend_from_slice(b"garbage");
+        std::fs::write(&file, &data).unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        assert!(ShardReader::open(provider, path.clone()).await.is_err());
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::recover(provider, path.clone()).await.unwrap();
+        assert_eq!(reader.len(), 1);
+        assert_eq!(reader.alignment(), 1);
+        assert_eq!(reader.footer.index_offset as usize, first_end);
+        assert_eq!(reader.read_record_by_key("key1").await.unwrap().entries()[0].data(), b"some_data");
+        assert!(!root.path().join(staging_path(&path)).exists());
+
+        // The repaired shard opens without recovery
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        assert_eq!(ShardReader::open(provider, path).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recover_keeps_alignment() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = PathBuf::from("aligned");
+        let mut writer = ShardWriter::create(provider, path.clone()).with_alignment(512, true);
+        writer.write_record(&Record::builder("key1")
+            .entry("a.bin", "application/octet-stream", vec![1; 100])
+            .entry("b.bin", "application/octet-stream", vec![2; 700])
+            .build()).await.unwrap();
+        writer.write("key2", b"more_data", None).await.unwrap();
+        writer.finalize().await.unwrap();
+
+        // Drop the footer
+        let file = root.path().join(&path);
+        let mut data = std::fs::read(&file).unwrap();
+        data.truncate(data.len() - 1);
+        std::fs::write(&file, &data).unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::recover(provider, path).await.unwrap();
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.alignment(), 512);
+        assert_eq!(reader.optional_features(), FEATURE_ALIGNED_BLOCKS | FEATURE_ALIGNED_ENTRIES);
+        assert_eq!(reader.read_entry("key1", "b.bin").await.unwrap().data(), &[2; 700]);
+    }
+
+    #[tokio::test]
+    async fn test_recover_stops_at_corrupt_block() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+        let file = root.path().join(&path);
+
+        // Corrupt the first block and drop the footer
+        let mut data = std::fs::read(&file).unwrap();
+        data[60] ^= 0xff;
+        data.truncate(data.len() - 1);
+        std::fs::write(&file, &data).unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::recover(provider, path).await.unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), reader.footer.index_len + FOOTER_SIZE as u64);
+    }
+
+    #[tokio::test]
+    async fn test_recover_keeps_finalized_shard() {
+        let root = TempDir::new().unwrap();
+        let path = write_shard(&root).await;
+        let before = std::fs::read(root.path().join(&path)).unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::recover(provider, path.clone()).await.unwrap();
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.metadata(), b"shard-meta");
+        assert_eq!(std::fs::read(root.path().join(&path)).unwrap(), before);
+    }
+}
@@ -0,0 +1,365 @@
+use bytes::{Bytes, BytesMut};
+
+use crate::compression::CompressionType;
+use crate::error::Error;
+use crate::types::Result;
+
+/// Content type of entries written without an explicit one.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// A single file entry of a record, such as an image or an annotation.
+///
+/// The content is always held decoded; `encoding` is the compression applied to it when it
+/// is stored in a shard. Content read from a shard without compression shares the buffer it
+/// was read into rather than being copied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileEntry {
+    name: String,
+    content_type: String,
+    encoding: CompressionType,
+    data: Bytes,
+}
+
+impl FileEntry {
+    /// Creates a new file entry stored without compression.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the file entry, e.g. `left.jpg`.
+    /// * `content_type` - The content type of the entry, e.g. `image/jpeg`.
+    /// * `data` - The content of the file entry.
+    pub fn new(name: impl Into<String>, content_type: impl Into<String>, data: impl Into<Bytes>) -> Self {
+        Self {
+            name: name.into(),
+            content_type: content_type.into(),
+            encoding: CompressionType::None,
+            data: data.into(),
+        }
+    }
+
+    /// Sets the compression applied to the entry when it is stored.
+    pub fn with_encoding(mut self, encoding: CompressionType) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Returns the name of the file entry.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the content type of the file entry.
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Returns the compression applied to the entry when it is stored.
+    pub fn encoding(&self) -> CompressionType {
+        self.encoding
+    }
+
+    /// Returns the decoded content of the file entry.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the decoded content of the file entry as a shared buffer.
+    pub fn bytes(&self) -> Bytes {
+        self.data.clone()
+    }
+
+    /// Consumes the entry, returning its decoded content.
+    pub fn into_data(self) -> Bytes {
+        self.data
+    }
+}
+
+/// Describes a file entry of a stored record without its content.
+///
+/// # Fields
+///
+/// * `name` - The name of the file entry, e.g. `left.jpg`.
+/// * `content_type` - The content type of the entry, e.g. `image/jpeg`.
+/// * `encoding` - The compression applied to the stored content.
+/// * `size` - The size of the decoded content in bytes.
+/// * `stored_size` - The size of the stored (encoded) content in bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub name: String,
+    pub content_type: String,
+    pub encoding: CompressionType,
+    pub size: u64,
+    pub stored_size: u64,
+}
+
+/// Position of a chunk within a record split across several shards.
+///
+/// A record too large for a single shard is stored as a sequence of chunk records under the
+/// same key. Laid end to end, the file entries of the chunks hold the content of the entries
+/// of the record, in order: an entry cut by a chunk boundary continues as the first entry of
+/// the next chunk. Record-level metadata is only stored in the first chunk.
+///
+/// # Fields
+///
+/// * `index` - The position of the chunk within the record, starting at 0.
+/// * `last` - Whether this is the final chunk of the record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub index: u64,
+    pub last: bool,
+}
+
+/// A single record of a shard: one key holding one or more named file entries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    key: String,
+    metadata: Vec<u8>,
+    entries: Vec<FileEntry>,
+    chunk: Option<ChunkInfo>,
+}
+
+impl Record {
+    /// Creates a new record from its parts.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key identifying the record.
+    /// * `metadata` - Record-level metadata, empty for none.
+    /// * `entries` - The file entries of the record, in order.
+    pub fn new(key: impl Into<String>, metadata: Vec<u8>, entries: Vec<FileEntry>) -> Self {
+        Self { key: key.into(), metadata, entries, chunk: None }
+    }
+
+    /// Starts building a record stored under `key`.
+    pub fn builder(key: impl Into<String>) -> RecordBuilder {
+        RecordBuilder { record: Record::new(key, Vec::new(), Vec::new()) }
+    }
+
+    /// Returns the key identifying the record.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the record-level metadata, empty when none was written.
+    pub fn metadata(&self) -> &[u8] {
+        &self.metadata
+    }
+
+    /// Returns the file entries of the record, in write order.
+    pub fn entries(&self) -> &[FileEntry] {
+        &self.entries
+    }
+
+    /// Returns the file entry named `name`, if any.
+    pub fn entry(&self, name: &str) -> Option<&FileEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Consumes the record, returning its file entries.
+    pub fn into_entries(self) -> Vec<FileEntry> {
+        self.entries
+    }
+
+    /// Returns the position of the record within a record split across shards, or `None` if
+    /// the record is stored whole.
+    pub fn chunk(&self) -> Option<ChunkInfo> {
+        self.chunk
+    }
+
+    /// Marks the record as a chunk of a larger record.
+    pub(crate) fn with_chunk(mut self, chunk: Option<ChunkInfo>) -> Self {
+        self.chunk = chunk;
+        self
+    }
+
+    /// Returns the size of the content of all file entries together, in bytes.
+    pub fn content_size(&self) -> usize {
+        self.entries.iter().map(|entry| entry.data.len()).sum()
+    }
+
+    /// Returns a chunk of the record holding at most `max_size` bytes of entry content.
+    ///
+    /// The content of the entries is taken end to end, and the chunk holds the part of it
+    /// starting at `start`. Entries are sliced without copying their content.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The position of the chunk within the record.
+    /// * `start` - The offset of the chunk within the content of the record.
+    /// * `max_size` - The maximum number of content bytes in the chunk.
+    pub(crate) fn split_chunk(&self, index: u64, start: usize, max_size: usize) -> Record {
+        let end = start.saturating_add(max_size).min(self.content_size());
+        let last = end == self.content_size();
+
+        let mut entries = Vec::new();
+        let mut position = 0;
+        for entry in &self.entries {
+            let (entry_start, entry_end) = (position, position + entry.data.len());
+            position = entry_end;
+
+            // Empty entries go to the chunk their position falls into
+            let included = if entry_start == entry_end {
+                entry_start >= start && (entry_start < end || last)
+            } else {
+                entry_start < end && entry_end > start
+            };
+            if included {
+                let range = entry_start.max(start) - entry_start..entry_end.min(end) - entry_start;
+                entries.push(FileEntry { data: entry.data.slice(range), ..entry.clone() });
+            }
+        }
+
+        let metadata = if index == 0 { self.metadata.clone() } else { Vec::new() };
+        Record::new(self.key.clone(), metadata, entries).with_chunk(Some(ChunkInfo { index, last }))
+    }
+
+    /// Reassembles a record from its chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunks` - Every chunk of the record, in order, or the record itself if it is
+    ///   stored whole.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Record>` with the whole record, or an error if a chunk is missing, out of
+    ///   order or belongs to another key.
+    pub fn join(chunks: Vec<Record>) -> Result<Record> {
+        let mut chunks = chunks.into_iter();
+        let first = chunks.next()
+            .ok_or_else(|| Error::Format("Record has no chunks".into()))?;
+        if first.chunk.is_none() {
+            return match chunks.next() {
+                None => Ok(first),
+                Some(_) => Err(Error::Format("Whole record followed by chunks".into())),
+            };
+        }
+
+        let key = first.key.clone();
+        let metadata = first.metadata.clone();
+        let mut last = false;
+        let mut parts: Vec<(FileEntry, Vec<Bytes>)> = Vec::new();
+        for (expected, chunk) in std::iter::once(first).chain(chunks).enumerate() {
+            if chunk.key != key || last || chunk.chunk.is_none_or(|info| info.index != expected as u64) {
+                return Err(Error::Format(format!("Chunk {} of record {} is out of order", expected, key)));
+            }
+            last = chunk.chunk.is_some_and(|info| info.last);
+
+            for (position, entry) in chunk.entries.into_iter().enumerate() {
+                match parts.last_mut() {
+                    // The first entry of a chunk continues an entry cut by the chunk boundary
+                    Some((previous, data)) if position == 0 && previous.name == entry.name => data.push(entry.data),
+                    _ => {
+                        let data = vec![entry.data.clone()];
+                        parts.push((entry, data));
+                    }
+                }
+            }
+        }
+        if !last {
+            return Err(Error::Format(format!("Record {} is missing its last chunk", key)));
+        }
+
+        let entries = parts.into_iter()
+            .map(|(entry, data)| FileEntry { data: concat(data), ..entry })
+            .collect();
+        Ok(Record::new(key, metadata, entries))
+    }
+}
+
+/// Concatenates `parts` into one buffer, without copying when there is a single part.
+pub(crate) fn concat(mut parts: Vec<Bytes>) -> Bytes {
+    if parts.len() == 1 {
+        return parts.pop().unwrap_or_default();
+    }
+    let mut data = BytesMut::with_capacity(parts.iter().map(Bytes::len).sum());
+    for part in parts {
+        data.extend_from_slice(&part);
+    }
+    data.freeze()
+}
+
+/// Builds a `Record` entry by entry.
+///
+/// ```
+/// use shardpack::{CompressionType, Record};
+///
+/// let record = Record::builder("images17/image194")
+///     .entry("left.jpg", "image/jpeg", vec![0xff, 0xd8])
+///     .entry("right.jpg", "image/jpeg", vec![0xff, 0xd8])
+///     .entry_with_encoding("meta.json", "application/json", CompressionType::Gzip, b"{}".to_vec())
+///     .build();
+/// assert_eq!(record.entry("meta.json").unwrap().content_type(), "application/json");
+/// ```
+pub struct RecordBuilder {
+    record: Record,
+}
+
+impl RecordBuilder {
+    /// Sets the record-level metadata.
+    pub fn metadata(mut self, metadata: Vec<u8>) -> Self {
+        self.record.metadata = metadata;
+        self
+    }
+
+    /// Appends a file entry stored without compression.
+    pub fn entry(self, name: impl Into<String>, content_type: impl Into<String>, data: impl Into<Bytes>) -> Self {
+        self.file_entry(FileEntry::new(name, content_type, data))
+    }
+
+    /// Appends a file entry stored with the given compression.
+    pub fn entry_with_encoding(
+        self,
+        name: impl Into<String>,
+        content_type: impl Into<String>,
+        encoding: CompressionType,
+        data: impl Into<Bytes>,
+    ) -> Self {
+        self.file_entry(FileEntry::new(name, content_type, data).with_encoding(encoding))
+    }
+
+    /// Appends an existing file entry.
+    pub fn file_entry(mut self, entry: FileEntry) -> Self {
+        self.record.entries.push(entry);
+        self
+    }
+
+    /// Returns the built record.
+    pub fn build(self) -> Record {
+        self.record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_join_chunks() {
+        let record = Record::builder("images17/image194")
+            .metadata(b"stereo".to_vec())
+            .entry("left.jpg", "image/jpeg", b"0123456789".to_vec())
+            .entry("empty", "text/plain", Vec::new())
+            .entry_with_encoding("meta.json", "application/json", CompressionType::Gzip, b"{}".to_vec())
+            .build();
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while chunks.last().is_none_or(|chunk: &Record| chunk.chunk().is_some_and(|chunk| !chunk.last)) {
+            let chunk = record.split_chunk(chunks.len() as u64, start, 4);
+            start += chunk.content_size();
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].metadata(), b"stereo");
+        assert_eq!(chunks[1].metadata(), b"");
+        assert_eq!(chunks[2].entries().len(), 3);
+        assert_eq!(chunks[2].entries()[0].data(), b"89");
+        assert_eq!(chunks[2].entries()[2].encoding(), CompressionType::Gzip);
+
+        assert_eq!(Record::join(chunks.clone()).unwrap(), record);
+        assert!(Record::join(chunks[..2].to_vec()).is_err());
+        assert!(Record::join(vec![chunks[1].clone(), chunks[0].clone()]).is_err());
+        assert_eq!(Record::join(vec![record.clone()]).unwrap(), record);
+    }
+}
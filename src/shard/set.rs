@@ -0,0 +1,422 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use futures::future::try_join_all;
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::StorageProvider;
+use crate::error::Error;
+use crate::shard::reader::{read_index, ShardReader};
+use crate::shard::record::Record;
+use crate::storage::ObjectStat;
+use crate::types::Result;
+
+/// Largest number of shard names a single pattern may expand to.
+const MAX_EXPANSION: usize = 1_000_000;
+
+/// An ordered set of shards making up one dataset, such as `data/train-{000000..000973}.shardpack`.
+///
+/// Shard sets are usually named with brace notation: `{000000..000973}` expands to every
+/// number of the range, padded to the width of its bounds, and `{train,val}` to each of the
+/// listed alternatives. A pattern may hold several groups, which expand left to right, e.g.
+/// `{train,val}-{0..1}.shardpack` names `train-0`, `train-1`, `val-0` and `val-1`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardSet {
+    paths: Vec<PathBuf>,
+}
+
+impl ShardSet {
+    /// Parses a shard set from a pattern in brace notation.
+    ///
+    /// # Arguments
+    /// * `pattern` - The pattern naming the shards, relative to the root of the storage provider.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the shards named by the pattern, in order, or `Error::Config` if the
+    ///   pattern is malformed.
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let names = expand(pattern)
+            .map_err(|err| Error::Config(format!("Invalid shard pattern `{}`: {}", pattern, err)))?;
+        Ok(Self::from_paths(names))
+    }
+
+    /// Creates a shard set from the paths of its shards, in order.
+    pub fn from_paths<T: Into<PathBuf>>(paths: impl IntoIterator<Item = T>) -> Self {
+        Self { paths: paths.into_iter().map(Into::into).collect() }
+    }
+
+    /// Returns the paths of the shards of the set, in order.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Returns the number of shards in the set.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Returns `true` if the set names no shards.
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Looks up every shard of the set in `provider`.
+    ///
+    /// # Returns
+    /// * `Result<Vec<ObjectStat>>` with the size of every shard, in order, or an error naming
+    ///   the first shard that does not exist.
+    pub async fn list<P: StorageProvider>(&self, provider: &P) -> Result<Vec<ObjectStat>> {
+        try_join_all(self.paths.iter().map(|path| async move {
+            provider.stat(path).await
+                .map_err(|err| Error::Storage(format!("{}: {}", path.display(), err)))
+        }))
+        .await
+    }
+
+    /// Checks that every shard of the set exists and is a finalized shard this crate can read.
+    ///
+    /// Only the footer and index of each shard are read.
+    ///
+    /// # Returns
+    /// * `Result<()>`, or an error listing every shard that is missing or invalid.
+    pub async fn validate<P: StorageProvider>(&self, provider: &P) -> Result<()> {
+        let results = futures::future::join_all(self.paths.iter().map(|path| read_index(provider, path))).await;
+        let invalid: Vec<String> = self.paths.iter()
+            .zip(results)
+            .filter_map(|(path, result)| result.err().map(|err| format!("{}: {}", path.display(), err)))
+            .collect();
+
+        if invalid.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Storage(format!(
+            "{} of {} shards are invalid: {}",
+            invalid.len(),
+            self.paths.len(),
+            invalid.join("; ")
+        )))
+    }
+
+    /// Opens every shard of the set as one logical dataset.
+    ///
+    /// # Arguments
+    /// * `provider` - The storage provider holding the shards, cloned for every shard; pass an
+    ///   `Arc` to share one provider.
+    pub async fn open<P: StorageProvider + Clone>(&self, provider: P) -> Result<ShardSetReader<P>> {
+        let shards = try_join_all(self.paths.iter().map(|path| ShardReader::open(provider.clone(), path.clone())))
+            .await?;
+        Ok(ShardSetReader::new(shards))
+    }
+}
+
+impl std::str::FromStr for ShardSet {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        Self::parse(pattern)
+    }
+}
+
+/// Random and sequential access to the records of a `ShardSet`, as if it were a single shard.
+///
+/// Records are numbered across the set in shard order, so the first record of the second
+/// shard follows the last record of the first one.
+pub struct ShardSetReader<W: StorageProvider> {
+    /// The readers of the shards of the set, in order.
+    shards: Vec<ShardReader<W>>,
+
+    /// The number of records in all shards before each shard.
+    starts: Vec<usize>,
+}
+
+impl<W: StorageProvider> ShardSetReader<W> {
+    /// Combines the readers of the shards of a set, in order.
+    pub fn new(shards: Vec<ShardReader<W>>) -> Self {
+        let starts = shards.iter()
+            .scan(0, |start, shard| {
+                let current = *start;
+                *start += shard.len();
+                Some(current)
+            })
+            .collect();
+        Self { shards, starts }
+    }
+
+    /// Returns the readers of the shards of the set, in order.
+    pub fn shards(&self) -> &[ShardReader<W>] {
+        &self.shards
+    }
+
+    /// Returns the number of records in all shards of the set.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(ShardReader::len).sum()
+    }
+
+    /// Returns `true` if no shard of the set holds a record.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the record at position `index` across the set.
+    pub async fn read_record(&self, index: usize) -> Result<Record> {
+        // The last shard starting at or before `index`; empty shards share their start
+        let shard = self.starts.partition_point(|&start| start <= index).saturating_sub(1);
+        match self.shards.get(shard) {
+            Some(reader) if index - self.starts[shard] < reader.len() => {
+                reader.read_record(index - self.starts[shard]).await
+            }
+            _ => Err(Error::Index(format!("Record {} out of range", index))),
+        }
+    }
+
+    /// Reads the record stored under `key`.
+    ///
+    /// If the key is stored in more than one shard, the record of the last of them is
+    /// returned.
+    pub async fn read_record_by_key(&self, key: &str) -> Result<Record> {
+        let reader = self.shards.iter()
+            .rev()
+            .find(|reader| reader.contains_key(key))
+            .ok_or_else(|| Error::Storage("Key not found".into()))?;
+        reader.read_record_by_key(key).await
+    }
+
+    /// Returns the path of the shard holding the record stored under `key`, if any.
+    pub fn shard_of(&self, key: &str) -> Option<&Path> {
+        self.shards.iter()
+            .rev()
+            .find(|reader| reader.contains_key(key))
+            .map(ShardReader::path)
+    }
+
+    /// Streams every record of the set in order, reading one record at a time.
+    pub fn records(&self) -> impl Stream<Item = Result<Record>> + '_ {
+        stream::iter(&self.shards)
+            .flat_map(|reader| stream::iter(0..reader.len()).then(move |index| reader.read_record(index)))
+    }
+}
+
+/// Template for the names of the shards of a bucket, such as `dataset-train-{id:06}.shardpack`.
+///
+/// The template holds a single `{id}` placeholder, replaced by the identifier of the shard.
+/// The placeholder may be zero-padded to a width as in `{id:06}`, and formatted in hexadecimal
+/// as in `{id:016x}`. The default template is `shard_{id:016x}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardNameTemplate {
+    prefix: String,
+    suffix: String,
+    width: usize,
+    hex: bool,
+}
+
+impl Default for ShardNameTemplate {
+    fn default() -> Self {
+        Self { prefix: "shard_".into(), suffix: String::new(), width: 16, hex: true }
+    }
+}
+
+impl ShardNameTemplate {
+    /// Parses a name template.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with the template, or `Error::Config` if it does not hold exactly one
+    ///   valid `{id}` placeholder.
+    pub fn new(template: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::Config(format!("Invalid shard name template `{}`: {}", template, reason));
+
+        let start = template.find("{id").ok_or_else(|| invalid("missing {id} placeholder"))?;
+        let end = start + template[start..].find('}').ok_or_else(|| invalid("unclosed placeholder"))?;
+        let (prefix, suffix) = (&template[..start], &template[end + 1..]);
+        if [prefix, suffix].iter().any(|part| part.contains(['{', '}'])) {
+            return Err(invalid("more than one placeholder"));
+        }
+
+        let (width, hex) = match &template[start + 3..end] {
+            "" => (0, false),
+            spec => {
+                let spec = spec.strip_prefix(":0").ok_or_else(|| invalid("expected {id:0N} or {id:0Nx}"))?;
+                let (digits, hex) = match spec.strip_suffix('x') {
+                    Some(digits) => (digits, true),
+                    None => (spec, false),
+                };
+                (digits.parse().map_err(|_| invalid("invalid width"))?, hex)
+            }
+        };
+        Ok(Self { prefix: prefix.into(), suffix: suffix.into(), width, hex })
+    }
+
+    /// Returns the name of the shard with identifier `id`.
+    pub fn name(&self, id: u64) -> String {
+        let id = match self.hex {
+            true => format!("{:0width$x}", id, width = self.width),
+            false => format!("{:0width$}", id, width = self.width),
+        };
+        format!("{}{}{}", self.prefix, id, self.suffix)
+    }
+
+    /// Returns the identifier of the shard named `name`, or `None` if the name does not match
+    /// the template.
+    pub fn id(&self, name: &str) -> Option<u64> {
+        let digits = name.strip_prefix(&self.prefix)?.strip_suffix(&self.suffix)?;
+        let id = match self.hex {
+            true => u64::from_str_radix(digits, 16).ok()?,
+            false => digits.parse().ok()?,
+        };
+        // Only the canonical name of an identifier matches, e.g. not `+1` or `1` for `{id:02}`
+        Some(id).filter(|&id| self.name(id) == name)
+    }
+
+    /// Returns the shard set holding the shards with identifiers `ids` in directory `dir`.
+    pub fn shard_set(&self, dir: impl AsRef<Path>, ids: std::ops::Range<u64>) -> ShardSet {
+        ShardSet::from_paths(ids.map(|id| dir.as_ref().join(self.name(id))))
+    }
+}
+
+impl fmt::Display for ShardNameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let spec = match (self.width, self.hex) {
+            (0, false) => String::new(),
+            (width, hex) => format!(":0{}{}", width, if hex { "x" } else { "" }),
+        };
+        write!(f, "{}{{id{}}}{}", self.prefix, spec, self.suffix)
+    }
+}
+
+/// Expands the brace groups of `pattern`, left to right.
+fn expand(pattern: &str) -> std::result::Result<Vec<String>, String> {
+    let Some(open) = pattern.find('{') else {
+        if pattern.contains('}') {
+            return Err("unmatched `}`".into());
+        }
+        return Ok(vec![pattern.to_string()]);
+    };
+    let prefix = &pattern[..open];
+    if prefix.contains('}') {
+        return Err("unmatched `}`".into());
+    }
+    let close = open + pattern[open..].find('}').ok_or("unclosed `{`")?;
+    let group = &pattern[open + 1..close];
+    if group.contains('{') {
+        return Err("nested braces are not supported".into());
+    }
+
+    let alternatives = expand_group(group)?;
+    let rest = expand(&pattern[close + 1..])?;
+    if alternatives.len().saturating_mul(rest.len()) > MAX_EXPANSION {
+        return Err(format!("expands to more than {} shards", MAX_EXPANSION));
+    }
+
+    Ok(alternatives.iter()
+        .flat_map(|alternative| rest.iter().map(move |rest| format!("{}{}{}", prefix, alternative, rest)))
+        .collect())
+}
+
+/// Expands a single brace group, either a numeric range `a..b` or a list `a,b,c`.
+fn expand_group(group: &str) -> std::result::Result<Vec<String>, String> {
+    if let Some((start, end)) = group.split_once("..") {
+        let first: u64 = start.parse().map_err(|_| format!("invalid range start `{}`", start))?;
+        let last: u64 = end.parse().map_err(|_| format!("invalid range end `{}`", end))?;
+        if first.abs_diff(last) >= MAX_EXPANSION as u64 {
+            return Err(format!("expands to more than {} shards", MAX_EXPANSION));
+        }
+
+        // Bounds written with leading zeros pad every number to the widest bound
+        let padded = [start, end].iter().any(|bound| bound.len() > 1 && bound.starts_with('0'));
+        let width = if padded { start.len().max(end.len()) } else { 0 };
+        let numbers: Box<dyn Iterator<Item = u64>> = match first <= last {
+            true => Box::new(first..=last),
+            false => Box::new((last..=first).rev()),
+        };
+        return Ok(numbers.map(|number| format!("{:0width$}", number, width = width)).collect());
+    }
+    if group.contains(',') {
+        return Ok(group.split(',').map(str::to_string).collect());
+    }
+    Err(format!("invalid brace group `{{{}}}`", group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::shard::writer::ShardWriter;
+    use crate::storage::LocalStorageProvider;
+    use futures::TryStreamExt;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_brace_patterns() {
+        let set = ShardSet::parse("data/train-{000000..000002}.shardpack").unwrap();
+        assert_eq!(set.paths(), [
+            PathBuf::from("data/train-000000.shardpack"),
+            PathBuf::from("data/train-000001.shardpack"),
+            PathBuf::from("data/train-000002.shardpack"),
+        ]);
+
+        let set: ShardSet = "{train,val}-{9..10}".parse().unwrap();
+        let names: Vec<_> = set.paths().iter().map(|path| path.to_str().unwrap()).collect();
+        assert_eq!(names, ["train-9", "train-10", "val-9", "val-10"]);
+        assert_eq!(ShardSet::parse("{2..0}").unwrap().len(), 3);
+        assert_eq!(ShardSet::parse("single.shardpack").unwrap().len(), 1);
+
+        for pattern in ["{0..", "0..1}", "{a{b}}", "{x}", "{0..z}", "{0..99999999}"] {
+            assert!(ShardSet::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_name_templates() {
+        assert_eq!(ShardNameTemplate::default().name(26), "shard_000000000000001a");
+        let template = ShardNameTemplate::new("dataset-train-{id:06}.shardpack").unwrap();
+        assert_eq!(template.name(973), "dataset-train-000973.shardpack");
+        assert_eq!(template.to_string(), "dataset-train-{id:06}.shardpack");
+        assert_eq!(ShardNameTemplate::new("part-{id}").unwrap().name(7), "part-7");
+        assert_eq!(template.id("dataset-train-000973.shardpack"), Some(973));
+        assert_eq!(template.id("dataset-train-973.shardpack"), None);
+        assert_eq!(ShardNameTemplate::default().id("shard_000000000000001a"), Some(26));
+        assert_eq!(ShardNameTemplate::default().id("shard_000000000000001a.staging"), None);
+        assert_eq!(ShardNameTemplate::default().id("MANIFEST"), None);
+        let set = ShardSet::parse("data/dataset-train-{000000..000001}.shardpack").unwrap();
+        assert_eq!(template.shard_set("data", 0..2), set);
+
+        for template in ["shard", "{id:6}", "{id}-{id}", "{id:0z}", "{id"] {
+            assert!(ShardNameTemplate::new(template).is_err(), "{}", template);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_shard_set() {
+        let root = TempDir::new().unwrap();
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let template = ShardNameTemplate::new("train-{id:03}.shardpack").unwrap();
+        for (id, keys) in [(0, &["a", "b"][..]), (1, &[][..]), (2, &["c", "a"][..])] {
+            let mut writer = ShardWriter::create(Arc::clone(&provider), Path::new("data").join(template.name(id)));
+            for key in keys {
+                writer.write(key, format!("{}{}", key, id).as_bytes(), None).await.unwrap();
+            }
+            writer.finalize().await.unwrap();
+        }
+
+        let set = ShardSet::parse("data/train-{000..002}.shardpack").unwrap();
+        assert_eq!(set.list(&provider).await.unwrap().len(), 3);
+        set.validate(&provider).await.unwrap();
+
+        let reader = set.open(Arc::clone(&provider)).await.unwrap();
+        assert_eq!(reader.len(), 4);
+        assert_eq!(reader.read_record(1).await.unwrap().key(), "b");
+        assert_eq!(reader.read_record(2).await.unwrap().key(), "c");
+        assert!(reader.read_record(4).await.is_err());
+        assert_eq!(reader.read_record_by_key("a").await.unwrap().entries()[0].data(), b"a2");
+        assert_eq!(reader.shard_of("b"), Some(Path::new("data/train-000.shardpack")));
+
+        let keys: Vec<String> = reader.records().map_ok(|record| record.key().to_string()).try_collect().await.unwrap();
+        assert_eq!(keys, ["a", "b", "c", "a"]);
+
+        let missing = ShardSet::parse("data/train-{000..003}.shardpack").unwrap();
+        assert!(missing.list(&provider).await.is_err());
+        let err = missing.validate(&provider).await.unwrap_err().to_string();
+        assert!(err.contains("1 of 4 shards") && err.contains("train-003"), "{}", err);
+        assert!(missing.open(Arc::clone(&provider)).await.is_err());
+    }
+}
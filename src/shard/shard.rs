@@ -1,37 +1,172 @@
-use byte_counter::counter::ByteCounter;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use crate::StorageProvider;
+use crate::index::entry::RecordEntry;
+use crate::index::generation::{encode_versions, RecordVersion};
+use crate::index::manifest::ShardInfo;
+use crate::shard::format::ShardIndex;
+use crate::shard::record::Record;
+use crate::types::Result;
 
-use super::{reader::ShardReader, writer::ShardWriter};
+use super::writer::ShardWriter;
 
 
-/// A `Shard` represents a segment of data that can be read from and written to.
+/// A `Shard` represents one shard file of a bucket.
 ///
-/// The generic type `S` is expected to be some form of mutable reference to a binary file,
-/// such as `&mut std::fs::File`. This allows the `ShardWriter` and `ShardReader` to operate
-/// on the underlying file, keeping track of byte counts through `ByteCounter`.
+/// While the shard is open it holds the `ShardWriter` streaming records into it, together
+/// with the version of every record, which is stored as the shard-level metadata once the
+/// shard is sealed. A sealed shard is immutable and only keeps its identifier.
 pub struct Shard<S: StorageProvider> {
-    id: ByteCounter,
-    writer: ShardWriter<S>,
-    reader: ShardReader<S>,
+    id: u64,
+    writer: Option<ShardWriter<S>>,
+    versions: Vec<RecordVersion>,
+    generations: HashMap<String, u64>,
+    opened: Instant,
 }
 
-impl<S: StorageProvider> Default for Shard<S> {
-    fn default() -> Self {
-        Shard {
-            id: ByteCounter::default(),
-            writer: ShardWriter::default(),
-            reader: ShardReader::default(),
+impl<S: StorageProvider> Shard<S> {
+    /// Opens a new shard writing to `path` through `provider`, holding at most `max_size` bytes
+    /// of record blocks.
+    pub fn new(id: u64, path: PathBuf, provider: S, max_size: usize) -> Self {
+        Self {
+            id,
+            writer: Some(ShardWriter::create(provider, path).with_max_size(max_size)),
+            versions: Vec::new(),
+            generations: HashMap::new(),
+            opened: Instant::now(),
         }
     }
-}
 
-impl<S: StorageProvider> Shard<S> {
-    pub fn new() -> Self {
-        Self { 
-            id: ByteCounter::default(), 
-            writer: ShardWriter::default(), 
-            reader: ShardReader::default() 
+    /// Returns the identifier of the shard within its bucket.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns `true` once the shard has been sealed.
+    pub fn is_sealed(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /// Returns the number of records written to the open shard, 0 once it is sealed.
+    pub fn len(&self) -> usize {
+        self.writer.as_ref().map_or(0, |writer| writer.len())
+    }
+
+    /// Returns how long ago the shard was opened.
+    pub fn age(&self) -> Duration {
+        self.opened.elapsed()
+    }
+
+    /// Returns `true` if the shard is open and no record has been written to it.
+    pub fn is_empty(&self) -> bool {
+        self.writer.as_ref().is_some_and(|writer| writer.is_empty())
+    }
+
+    /// Returns the latest generation of `key` written to the open shard, if any.
+    pub fn generation(&self, key: &str) -> Option<u64> {
+        self.generations.get(key).copied()
+    }
+
+    /// Returns the keys of the records written to the open shard.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.generations.keys().map(String::as_str)
+    }
+
+    /// Moves the generations of `key` written to the open shard after generation `after` past
+    /// generation `floor`, keeping their order, e.g. past generations of the key that other
+    /// writers published meanwhile.
+    pub fn renumber(&mut self, key: &str, after: u64, floor: u64) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let mut versions: Vec<&mut RecordVersion> = writer.entries().iter()
+            .zip(&mut self.versions)
+            .filter(|(entry, version)| entry.key == key && version.generation > after)
+            .map(|(_, version)| version)
+            .collect();
+        let Some(first) = versions.iter().map(|version| version.generation).min().filter(|&first| first <= floor) else {
+            return;
+        };
+        for version in &mut versions {
+            version.generation += floor + 1 - first;
+        }
+        if let Some(latest) = self.generations.get_mut(key) {
+            *latest += floor + 1 - first;
+        }
+    }
+
+    /// Writes a record to the open shard.
+    ///
+    /// # Returns
+    /// * `Result<RecordEntry>` with the location of the record, `Error::ShardFull` if it does not
+    ///   fit, or an error if the shard is sealed or writing fails.
+    pub async fn write(&mut self, record: &Record, version: RecordVersion) -> Result<RecordEntry> {
+        let writer = self.writer.as_mut()
+            .ok_or_else(|| crate::Error::Storage("Shard is sealed".into()))?;
+        let entry = writer.write_record(record).await?;
+        self.add_version(record.key(), version);
+        Ok(entry)
+    }
+
+    /// Copies a record block read from another shard into the open shard.
+    ///
+    /// # Arguments
+    /// * `record` - The record decoded from `block`.
+    /// * `block` - The record block, copied as-is.
+    /// * `checksum` - The checksum stored in the prefix of `block`.
+    /// * `version` - The version of the record, kept as is.
+    ///
+    /// # Returns
+    /// * `Result<RecordEntry>` with the location of the copied block, `Error::ShardFull` if it
+    ///   does not fit, or an error if the shard is sealed or writing fails.
+    pub async fn copy(
+        &mut self,
+        record: &Record,
+        block: &[u8],
+        checksum: [u8; 32],
+        version: RecordVersion,
+    ) -> Result<RecordEntry> {
+        let writer = self.writer.as_mut()
+            .ok_or_else(|| crate::Error::Storage("Shard is sealed".into()))?;
+        let entry = writer.copy_block(record, block, checksum).await?;
+        self.add_version(record.key(), version);
+        Ok(entry)
+    }
+
+    /// Records the version of the record just written to the open shard.
+    fn add_version(&mut self, key: &str, version: RecordVersion) {
+        self.versions.push(version);
+        let generation = self.generations.entry(key.to_string()).or_default();
+        *generation = (*generation).max(version.generation);
+    }
+
+    /// Seals the shard by finalizing its writer, storing the versions of its records as the
+    /// shard-level metadata.
+    ///
+    /// # Returns
+    /// * `Result<Option<(ShardIndex, ShardInfo)>>` with the index written to the shard and its
+    ///   description for the bucket manifest, or `None` if the shard was already sealed.
+    pub async fn seal(&mut self) -> Result<Option<(ShardIndex, ShardInfo)>> {
+        match self.writer.take() {
+            Some(writer) => {
+                let metadata = encode_versions(&std::mem::take(&mut self.versions));
+                self.generations.clear();
+                let (index, info, _) = writer.with_metadata(metadata).finalize_into_provider().await?;
+                Ok(Some((index, ShardInfo { id: self.id, ..info })))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Discards the open shard and everything written to it.
+    pub async fn abort(&mut self) -> Result<()> {
+        self.versions.clear();
+        self.generations.clear();
+        match self.writer.take() {
+            Some(writer) => writer.abort().await,
+            None => Ok(()),
         }
     }
 }
@@ -1,47 +1,111 @@
-use crate::{checksum::compute_checksum, index::bucket::IndexEntry};
+use crate::index::entry::RecordEntry;
+use crate::index::manifest::ShardInfo;
 use crate::StorageProvider;
 use crate::error::Error;
-use crate::shard::config::shard_size;
+use crate::shard::config::DEFAULT_SHARD_SIZE;
+use crate::shard::format::{
+    encode_index, encode_record_block, record_features, Footer, ShardIndex, FEATURE_ALIGNED_BLOCKS,
+    FEATURE_ALIGNED_ENTRIES, FEATURE_SHA256, FORMAT_VERSION,
+};
+use crate::shard::reader::read_index;
+use crate::shard::record::{FileEntry, Record, DEFAULT_CONTENT_TYPE};
+use crate::storage::StorageSink;
 use crate::types::Result;
 
 use byte_counter::counter::ByteCounter;
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Name of the single file entry written by `ShardWriter::write`.
+pub const DEFAULT_ENTRY_NAME: &str = "data";
+
+/// Size of the chunks in which `ShardWriter::reopen` copies existing record blocks.
+const COPY_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Returns the path a shard is staged at while it is rewritten, next to the shard itself.
+pub(crate) fn staging_path(path: &Path) -> PathBuf {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".staging");
+    PathBuf::from(staging)
+}
 
 /// Represents a writer for writing data to a shard.
 ///
 /// A `ShardWriter` is responsible for managing the writing of data into a shard,
 /// keeping track of its size and maintaining an index of entries. It uses a
 /// generic storage provider that implements the `StorageProvider` trait.
-#[derive(Serialize, Deserialize)]
+///
+/// Records are laid out as record blocks as specified in `docs/format.md` and streamed
+/// into a `StorageSink` opened on the first write. `finalize` appends the index and
+/// footer and completes the shard.
 pub struct ShardWriter<W: StorageProvider> {
-    /// A `ByteCounter` used to generate unique identifiers for data written to the shard.
-    id: ByteCounter,
-
     /// The storage provider responsible for persisting data to the shard.
     provider: W,
 
+    /// The path of the shard within the storage provider.
+    path: PathBuf,
+
+    /// The path the shard is written to before being moved to `path` by `finalize`, if staged.
+    staging: Option<PathBuf>,
+
+    /// The sink streaming record blocks into the shard, opened on the first write.
+    sink: Option<Box<dyn StorageSink>>,
+
     /// The current size of the shard in bytes.
     current_size: usize,
 
-    /// A vector containing index entries, each representing a piece of data stored in the shard.
-    entries: Vec<IndexEntry>,
+    /// A vector containing index entries, each representing a record block stored in the shard.
+    entries: Vec<RecordEntry>,
+
+    /// Shard-level metadata stored in the index.
+    metadata: Vec<u8>,
+
+    /// The features a reader needs to read the records written so far.
+    required_features: u64,
+
+    /// The boundary record blocks are padded to, 1 for none.
+    alignment: u64,
+
+    /// Whether the content of every file entry is aligned to `alignment` as well.
+    align_entries: bool,
+
+    /// The number of bytes of record blocks the shard may hold at most.
+    max_size: usize,
+
+    /// The checksum of every byte streamed into the shard so far.
+    hasher: Sha256,
 }
 
 /// Default implementation for `ShardWriter`.
 ///
 /// This provides default values for all fields:
-/// - `id`: An empty `ByteCounter` instance.
 /// - `provider`: The default value of the storage provider type.
+/// - `path`: An empty path.
+/// - `staging`: None, as the shard is written in place.
+/// - `sink`: None, as the sink is only opened on the first write.
 /// - `current_size`: 0, indicating that the shard is initially empty.
 /// - `entries`: An empty vector, as there are no entries when a writer is first created.
+/// - `metadata`: Empty.
+/// - `required_features`: Only `FEATURE_SHA256`, which every record block relies on.
+/// - `alignment`: 1, as record blocks are not aligned by default.
+/// - `align_entries`: false.
+/// - `max_size`: `DEFAULT_SHARD_SIZE`.
+/// - `hasher`: A checksum over no bytes yet.
 impl<W: StorageProvider> Default for ShardWriter<W> {
     fn default() -> Self {
         Self {
-            id: ByteCounter::default(),
             provider: Default::default(),
+            path: Default::default(),
+            staging: None,
+            sink: None,
             current_size: Default::default(),
-            entries: Default::default()
+            entries: Default::default(),
+            metadata: Default::default(),
+            required_features: FEATURE_SHA256,
+            alignment: 1,
+            align_entries: false,
+            max_size: DEFAULT_SHARD_SIZE,
+            hasher: Sha256::new(),
         }
     }
 }
@@ -50,31 +114,147 @@ impl<W: StorageProvider> ShardWriter<W> {
     /// Creates a new instance of `ShardWriter` with the provided writer.
     ///
     /// # Arguments
-    /// * `writer`: The writer to use for output operations. This can be any type that implements
-    ///   the `Write` trait, such as file handles or network streams.
+    /// * `id`: The identifier of the shard, used as its path.
+    /// * `writer`: The storage provider the shard is written to.
     ///
     /// # Returns
     /// A new `ShardWriter` instance initialized with the provided writer and default values:
     /// - `current_size` set to 0.
-    /// - `entries` initialized as an empty vector of `IndexEntry`.
+    /// - `entries` initialized as an empty vector of `RecordEntry`.
     pub fn new(id: ByteCounter, writer: W)  -> Self  {
+        Self::create(writer, id.to_string())
+     }
+
+    /// Creates a new instance of `ShardWriter` writing the shard to `path`.
+    ///
+    /// # Arguments
+    /// * `writer`: The storage provider the shard is written to.
+    /// * `path`: The path of the shard within the storage provider.
+    pub fn create(writer: W, path: impl Into<PathBuf>) -> Self {
         Self {
-            id,
             provider: writer,
+            path: path.into(),
+            staging: None,
+            sink: None,
             current_size: 0,
             entries: Vec::new(),
-         }
-     }
+            metadata: Vec::new(),
+            required_features: FEATURE_SHA256,
+            alignment: 1,
+            align_entries: false,
+            max_size: DEFAULT_SHARD_SIZE,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Creates a `ShardWriter` replacing the shard at `path` as a whole.
+    ///
+    /// The shard is written to a staging object next to `path` and only moved over `path` by
+    /// `finalize`, so the existing shard stays intact until the new one is complete.
+    pub(crate) fn create_staged(writer: W, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let staging = staging_path(&path);
+        Self { staging: Some(staging), ..Self::create(writer, path) }
+    }
 
-    /// Writes data to the shard with an associated key and optional metadata.
+    /// Reopens a finalized shard to append more records to it.
     ///
-    /// This method takes a `key`, `data`, and optional `metadata` as arguments. It performs several steps:
-    /// 1. Calculates the size of the data to determine if adding it would exceed the shard's size limit.
-    /// 2. Computes the SHA-256 checksum of the provided data for integrity verification.
-    /// 3. Creates an `IndexEntry` containing metadata about the stored data, including its offset and length.
-    /// 4. Writes the data to the underlying writer using the StorageProvider API.
-    /// 5. If metadata is provided, it also writes the metadata immediately after the data.
-    /// 6. Updates the current size of the shard to reflect the added data.
+    /// The record blocks of the shard are copied to a staging object next to it, after which
+    /// new records are appended as usual. `finalize` writes an index covering both the existing
+    /// and the new records and only then replaces the shard with the staging object, so the
+    /// shard keeps its old index and footer until the merged shard is complete. A crash or an
+    /// `abort` leaves the shard as it was.
+    ///
+    /// # Arguments
+    /// * `writer`: The storage provider holding the shard.
+    /// * `path`: The path of the shard within the storage provider.
+    ///
+    /// # Returns
+    /// * `Result<Self>` with a writer positioned after the last record block of the shard, or an
+    ///   error if the shard cannot be read.
+    pub async fn reopen(writer: W, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        // Record blocks are laid out the same in every supported version, so they are copied
+        // as-is and the shard is rewritten with the current version
+        let (footer, index) = read_index(&writer, &path).await?;
+
+        let mut shard = Self::create_staged(writer, path);
+        let mut offset = 0;
+        while offset < footer.index_offset {
+            let len = COPY_CHUNK_SIZE.min(footer.index_offset - offset);
+            let chunk = shard.provider.read_range(&shard.path, offset, len).await?;
+            shard.write_all(&chunk).await?;
+            offset += len;
+        }
+
+        shard.current_size = footer.index_offset as usize;
+        shard.entries = index.records;
+        shard.metadata = index.metadata;
+        shard.required_features = footer.required_features;
+        shard.alignment = footer.alignment;
+        shard.align_entries = footer.optional_features & FEATURE_ALIGNED_ENTRIES != 0;
+        Ok(shard)
+    }
+
+    /// Aligns every record block written from now on to `alignment` bytes, e.g. 512 or 4096.
+    ///
+    /// Record blocks are padded with zeros so that each one starts at a multiple of
+    /// `alignment`. With `align_entries`, the content of every file entry is padded to start at
+    /// a multiple of `alignment` as well, so it can be read with direct I/O or mapped without
+    /// copying. The alignment is recorded in the footer. It must be set before the first
+    /// record is written, and a reopened shard keeps the alignment it was written with.
+    pub fn with_alignment(mut self, alignment: u64, align_entries: bool) -> Self {
+        self.alignment = alignment.max(1);
+        self.align_entries = align_entries;
+        self
+    }
+
+    /// Limits the record blocks of the shard to `max_size` bytes, instead of `DEFAULT_SHARD_SIZE`.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Sets the shard-level metadata stored in the index by `finalize`.
+    pub fn with_metadata(mut self, metadata: Vec<u8>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Returns the storage provider the shard is written to.
+    pub(crate) fn provider(&self) -> &W {
+        &self.provider
+    }
+
+    /// Returns the path of the shard within the storage provider.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Returns the number of bytes of record blocks written so far.
+    pub fn size(&self) -> usize {
+        self.current_size
+    }
+
+    /// Returns the number of records written so far.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no record has been written yet.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the index entries of the records written so far, in write order.
+    pub(crate) fn entries(&self) -> &[RecordEntry] {
+        &self.entries
+    }
+
+    /// Writes a record to the shard with an associated key and optional metadata.
+    ///
+    /// The record holds `data` as a single file entry named `DEFAULT_ENTRY_NAME`, stored
+    /// uncompressed with the `DEFAULT_CONTENT_TYPE`, and `metadata` as record-level metadata.
     ///
     /// # Arguments
     /// * `key` - A string slice representing the key associated with the data being stored.
@@ -82,58 +262,211 @@ impl<W: StorageProvider> ShardWriter<W> {
     /// * `metadata` - An optional byte slice that can hold additional information about the data.
     ///
     /// # Returns
-    /// * `Result<()>` indicating success or an error if writing fails, such as exceeding the shard size limit or I/O errors during write operations.
-    pub async fn write(&mut self, key: &str, data: &[u8], metadata: Option<&[u8]>) -> Result<()> {
-        let mut data_len = data.len();
-        if metadata.is_some() {
-            data_len += metadata.unwrap().len();
-        }
+    /// * `Result<RecordEntry>` as returned by `write_record`.
+    pub async fn write(&mut self, key: &str, data: &[u8], metadata: Option<&[u8]>) -> Result<RecordEntry> {
+        let record = Record::new(
+            key,
+            metadata.unwrap_or_default().to_vec(),
+            vec![FileEntry::new(DEFAULT_ENTRY_NAME, DEFAULT_CONTENT_TYPE, data.to_vec())],
+        );
+        self.write_record(&record).await
+    }
+
+    /// Writes a record with any number of file entries to the shard.
+    ///
+    /// This method performs several steps:
+    /// 1. Encodes a record block holding the key, record-level metadata and file entries of
+    ///    `record`, compressing each entry with its own encoding.
+    /// 2. Checks that adding the block would not exceed the shard's size limit.
+    /// 3. Creates a `RecordEntry` containing the key, offset, size and checksum of the block.
+    /// 4. Streams the block into the shard and updates its current size.
+    ///
+    /// # Arguments
+    /// * `record` - The record to write.
+    ///
+    /// # Returns
+    /// * `Result<RecordEntry>` with the location of the written block, `Error::ShardFull` if the
+    ///   block would exceed the shard size limit, or an error if writing fails.
+    pub async fn write_record(&mut self, record: &Record) -> Result<RecordEntry> {
+        let (block, checksum) = encode_record_block(record, self.alignment, self.align_entries)?;
 
-        if self.current_size + data_len > shard_size() {
-            return Err(Error::Storage("Shard size limit exceeded".into()));
+        if self.current_size + block.len() > self.max_size {
+            return Err(Error::ShardFull);
         }
 
-        // Compute the SHA-256 checksum of the data
-        let checksum = compute_checksum(data);
+        self.write_block(record, &block, checksum).await
+    }
 
-        // Determine the offset for this entry and create a new IndexEntry
-        let offset = self.current_size;
-        let entry = IndexEntry::new(
-            self.entries.len(),
-            offset,
-            data_len,
+    /// Appends an already encoded record block, without checking the shard size limit.
+    ///
+    /// # Arguments
+    /// * `record` - The record encoded in `block`.
+    /// * `block` - The record block, as produced by `encode_record_block`.
+    /// * `checksum` - The checksum stored in the prefix of `block`.
+    pub(crate) async fn write_block(&mut self, record: &Record, block: &[u8], checksum: [u8; 32]) -> Result<RecordEntry> {
+        // Determine the offset for this record and create a new RecordEntry
+        let entry = RecordEntry::new(
+            record.key().to_string(),
+            self.current_size as u64,
+            block.len() as u64,
             checksum,
         );
 
-        // Write the data to the underlying writer using the StorageProvider API
-        let path = PathBuf::from(self.id.to_string());
-        self.provider.write(&path, data).await?;
+        self.write_all(block).await?;
+
+        // Record this entry in our list of entries and update current size
+        self.current_size += block.len();
+        self.entries.push(entry.clone());
+        self.required_features |= record_features(record);
+
+        Ok(entry)
+    }
 
-        // If metadata is provided, write it after the data
-        if let Some(meta) = metadata {
-            self.provider.write(&path, meta).await?;
+    /// Appends a record block read from another shard as-is, without re-encoding it.
+    ///
+    /// # Returns
+    /// * `Result<RecordEntry>` with the location of the copied block, `Error::ShardFull` if the
+    ///   shard is not empty and the block would exceed its size limit, or an error if writing
+    ///   fails.
+    pub(crate) async fn copy_block(&mut self, record: &Record, block: &[u8], checksum: [u8; 32]) -> Result<RecordEntry> {
+        if !self.is_empty() && self.current_size + block.len() > self.max_size {
+            return Err(Error::ShardFull);
         }
+        self.write_block(record, block, checksum).await
+    }
 
-        // Record this entry in our list of entries and update current size
-        self.entries.push(entry);
-        self.current_size += data_len;
+    /// Finalizes the shard by appending the index and footer and completing the sink.
+    ///
+    /// The index lists the location of every record block together with the shard-level
+    /// metadata. It opens with the `INDEX_MARKER` and is followed by a fixed-size `Footer`
+    /// ending in the magic number so that readers can locate the index from the end of the
+    /// shard. The footer records the format version and the features needed to read the
+    /// records, such as the compressions in use.
+    ///
+    /// # Returns
+    /// * `Result<ShardIndex>` with the index written to the shard, or an error if persisting fails.
+    pub async fn finalize(self) -> Result<ShardIndex> {
+        self.finalize_into_provider().await.map(|(index, _, _)| index)
+    }
+
+    /// Finalizes the shard like `finalize`, also describing the finalized shard for a bucket
+    /// manifest and handing back the storage provider. The identifier of the returned
+    /// `ShardInfo` is left for the caller to fill in.
+    pub(crate) async fn finalize_into_provider(mut self) -> Result<(ShardIndex, ShardInfo, W)> {
+        let index = ShardIndex {
+            records: std::mem::take(&mut self.entries),
+            metadata: std::mem::take(&mut self.metadata),
+        };
+
+        let index_bytes = encode_index(&index);
+        let mut optional_features = 0;
+        if self.alignment > 1 {
+            optional_features |= FEATURE_ALIGNED_BLOCKS;
+            if self.align_entries {
+                optional_features |= FEATURE_ALIGNED_ENTRIES;
+            }
+        }
+        let footer = Footer {
+            alignment: self.alignment,
+            index_offset: self.current_size as u64,
+            index_len: index_bytes.len() as u64,
+            required_features: self.required_features,
+            optional_features,
+            version: FORMAT_VERSION,
+        };
+
+        self.write_all(&index_bytes).await?;
+        self.write_all(&footer.to_bytes()).await?;
+
+        if let Some(sink) = self.sink.take() {
+            sink.finish().await?;
+        }
+        if let Some(staging) = &self.staging {
+            self.provider.rename(staging, &self.path).await?;
+        }
+
+        let keys = index.records.iter().map(|entry| entry.key.as_str());
+        let info = ShardInfo {
+            id: 0,
+            path: self.path,
+            records: index.records.len() as u64,
+            size: (self.current_size + index_bytes.len() + footer.size()) as u64,
+            checksum: self.hasher.finalize().into(),
+            min_key: keys.clone().min().unwrap_or_default().to_string(),
+            max_key: keys.max().unwrap_or_default().to_string(),
+            format_version: footer.version,
+        };
+        Ok((index, info, self.provider))
+    }
 
-        Ok(())
+    /// Discards the shard, removing everything written so far.
+    pub async fn abort(mut self) -> Result<()> {
+        match self.sink.take() {
+            Some(sink) => sink.abort().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Streams `bytes` into the shard, adding them to the checksum of the whole shard.
+    async fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        self.sink().await?.write_all(bytes).await
+    }
+
+    /// Returns the sink streaming into the shard, opening it on first use.
+    async fn sink(&mut self) -> Result<&mut Box<dyn StorageSink>> {
+        let sink = match self.sink.take() {
+            Some(sink) => sink,
+            None => self.provider.open_writer(self.staging.as_ref().unwrap_or(&self.path)).await?,
+        };
+        Ok(self.sink.insert(sink))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use async_trait::async_trait;
+    use bytes::Bytes;
     use sha2::{Sha256, Digest};
+    use crate::shard::format::{decode_index, BLOCK_PREFIX_SIZE, FOOTER_SIZE, MAGIC};
 
     use super::*;
     
     use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use crate::shard::ShardReader;
+    use crate::storage::{LocalStorageProvider, ObjectStat, StorageLock, StorageSink};
     use mockall::mock;
     use mockall::predicate::*;
     use byte_counter::counter::ByteCounter;
 
+    /// Bytes streamed into a `MemorySink`, and whether the sink was finished.
+    #[derive(Clone, Default)]
+    struct SinkState {
+        data: Arc<Mutex<Vec<u8>>>,
+        finished: Arc<Mutex<bool>>,
+    }
+
+    struct MemorySink(SinkState);
+
+    #[async_trait]
+    impl StorageSink for MemorySink {
+        async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+            self.0.data.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        async fn finish(self: Box<Self>) -> Result<()> {
+            *self.0.finished.lock().unwrap() = true;
+            Ok(())
+        }
+
+        async fn abort(self: Box<Self>) -> Result<()> {
+            self.0.data.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+
     // Mocking StorageProvider for testing purposes
     mock! {
         pub FakeStorageProvider {}
@@ -144,9 +477,15 @@ mod tests {
             async fn delete_bucket(&self, name: &str) -> Result<()>;
             async fn bucket_exists(&self, name: &str) -> Result<bool>;
             async fn write(&self, path: &Path, data: &[u8]) -> Result<()>;
-            async fn read(&self, path: &Path) -> Result<Vec<u8>>;
+            async fn open_writer(&self, path: &Path) -> Result<Box<dyn StorageSink>>;
+            async fn read(&self, path: &Path) -> Result<Bytes>;
+            async fn read_range(&self, path: &Path, offset: u64, len: u64) -> Result<Bytes>;
+            async fn stat(&self, path: &Path) -> Result<ObjectStat>;
+            async fn rename(&self, from: &Path, to: &Path) -> Result<()>;
             async fn delete(&self, path: &Path) -> Result<()>;
             async fn list(&self, prefix: &Path) -> Result<Vec<String>>;
+            async fn lock(&self, path: &Path) -> Result<Box<dyn StorageLock>>;
+            async fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn StorageLock>>>;
         }
     }
 
@@ -173,7 +512,7 @@ mod tests {
         mock.expect_read()
             .with(eq(Path::new("test/path")))
             .times(1)
-            .returning(|_| Ok(vec![1, 2, 3]));
+            .returning(|_| Ok(Bytes::from_static(&[1, 2, 3])));
 
         mock.expect_list()
             .with(eq(Path::new("test/")))
@@ -210,25 +549,42 @@ mod tests {
         assert!(writer.entries.is_empty());
     }
 
+    fn block_size(key: &str, data: &[u8], metadata: &[u8]) -> usize {
+        let entry = FileEntry::new(DEFAULT_ENTRY_NAME, DEFAULT_CONTENT_TYPE, data.to_vec());
+        let record = Record::new(key, metadata.to_vec(), vec![entry]);
+        encode_record_block(&record, 1, false).unwrap().0.len()
+    }
+
+    /// Returns a mock provider expecting one sink to be opened at `path`.
+    fn provider_with_sink(path: PathBuf) -> (MockFakeStorageProvider, SinkState) {
+        let state = SinkState::default();
+        let sink_state = state.clone();
+
+        let mut mock_provider = MockFakeStorageProvider::default();
+        mock_provider.expect_open_writer()
+            .withf(move |path_arg| path_arg == path)
+            .times(1)
+            .returning(move |_| Ok(Box::new(MemorySink(sink_state.clone()))));
+        (mock_provider, state)
+    }
+
     #[tokio::test]
     async fn test_write_success_no_metadata() {
         let data = b"some_data";
         let key = "key1";
         let id = ByteCounter::default();
 
-        let mut mock_provider = MockFakeStorageProvider::default();
-        mock_provider.expect_write()
-            .with(eq(PathBuf::from(id.to_string())), eq(*data))
-            .times(1)
-            .returning(|_, _| Ok(()));
-        
+        let (mock_provider, state) = provider_with_sink(PathBuf::from(id.to_string()));
         let mut writer = ShardWriter::new(id, mock_provider);
         
         assert!(writer.write(key, data, None).await.is_ok());
-        assert_eq!(writer.current_size, 9);
+        let expected_size = block_size(key, data, &[]);
+        assert_eq!(writer.current_size, expected_size);
+        assert_eq!(state.data.lock().unwrap().len(), expected_size);
         assert_eq!(writer.entries.len(), 1);
+        assert_eq!(writer.entries[0].key, key);
         assert_eq!(writer.entries[0].offset, 0);
-        assert_eq!(writer.entries[0].size, 9);
+        assert_eq!(writer.entries[0].size, expected_size as u64);
     }
 
     #[tokio::test]
@@ -237,35 +593,17 @@ mod tests {
         let metadata = b"metadata";
         let key = "key1";
         let id = ByteCounter::default();
-        let expected_path = PathBuf::from(id.clone().to_string());
-
-        let mut mock_provider = MockFakeStorageProvider::default();
-        
-        let ep_1 = expected_path.clone();
-        mock_provider.expect_write()
-            .withf(move |path_arg, data_arg| {
-                path_arg.as_os_str() == ep_1.as_os_str() && 
-                data_arg == b"some_data"
-            })
-            .times(1)
-            .returning(|_, _| Ok(()));
-
-        let ep_2 = expected_path.clone();
-        mock_provider.expect_write()
-            .withf(move |path_arg, data_arg| {
-                path_arg.as_os_str() == ep_2.as_os_str() && 
-                data_arg == b"metadata"
-            })
-            .times(1)
-            .returning(|_, _| Ok(()));
 
+        let (mock_provider, _) = provider_with_sink(PathBuf::from(id.to_string()));
         let mut writer = ShardWriter::new(id, mock_provider);
         
         assert!(writer.write(key, data, Some(metadata)).await.is_ok());
-        assert_eq!(writer.current_size, data.len() + metadata.len());
+        let expected_size = block_size(key, data, metadata);
+        assert_eq!(expected_size, block_size(key, data, &[]) + metadata.len());
+        assert_eq!(writer.current_size, expected_size);
         assert_eq!(writer.entries.len(), 1);
         assert_eq!(writer.entries[0].offset, 0);
-        assert_eq!(writer.entries[0].size, data.len() + metadata.len());
+        assert_eq!(writer.entries[0].size, expected_size as u64);
     }
 
     #[tokio::test]
@@ -273,10 +611,11 @@ mod tests {
         let mock_provider = MockFakeStorageProvider::default();
         let id = ByteCounter::default();
         let mut writer = ShardWriter::new(id, mock_provider);
-        let data = vec![0; shard_size() + 1];
+        let data = vec![0; DEFAULT_SHARD_SIZE + 1];
         let key = "key1";
 
-        assert!(writer.write(key, &data, None).await.is_err());
+        assert!(matches!(writer.write(key, &data, None).await, Err(Error::ShardFull)));
+        assert!(writer.entries.is_empty());
     }
 
     #[tokio::test]
@@ -287,66 +626,38 @@ mod tests {
         let key2 = "key2";
         let id = ByteCounter::default();
         
-        let mut mock_provider = MockFakeStorageProvider::default();
-        mock_provider.expect_write()
-            .with(eq(PathBuf::from(id.to_string())), eq(*data1))
-            .times(1)
-            .returning(|_, _| Ok(()));
-        
-        mock_provider.expect_write()
-            .with(eq(PathBuf::from(id.to_string())), eq(*data2))
-            .times(1)
-            .returning(|_, _| Ok(()));
-
+        let (mock_provider, state) = provider_with_sink(PathBuf::from(id.to_string()));
         let mut writer = ShardWriter::new(id, mock_provider);
+        let size1 = block_size(key1, data1, &[]);
+        let size2 = block_size(key2, data2, &[]);
         
         assert!(writer.write(key1, data1, None).await.is_ok());
-        assert_eq!(writer.current_size, 9);
+        assert_eq!(writer.current_size, size1);
         assert_eq!(writer.entries.len(), 1);
 
         assert!(writer.write(key2, data2, None).await.is_ok());
-        assert_eq!(writer.current_size, 18);
+        assert_eq!(writer.current_size, size1 + size2);
+        assert_eq!(state.data.lock().unwrap().len(), size1 + size2);
         assert_eq!(writer.entries.len(), 2);
         assert_eq!(writer.entries[0].offset, 0);
-        assert_eq!(writer.entries[0].size, 9);
-        assert_eq!(writer.entries[1].offset, 9);
-        assert_eq!(writer.entries[1].size, 9);
+        assert_eq!(writer.entries[0].size, size1 as u64);
+        assert_eq!(writer.entries[1].offset, size1 as u64);
+        assert_eq!(writer.entries[1].size, size2 as u64);
     }
 
     #[tokio::test]
     async fn test_write_with_large_metadata() {
         let data = b"some_data";
-        let metadata_size = shard_size() - data.len();
-        let metadata = vec![0; metadata_size];
         let key = "key1";
+        let metadata_size = DEFAULT_SHARD_SIZE - block_size(key, data, &[]);
+        let metadata = vec![0; metadata_size];
         let id = ByteCounter::default();
-        let expected_path = PathBuf::from(id.clone().to_string());
-
-        let mut mock_provider = MockFakeStorageProvider::default();
-        
-        let ep_1 = expected_path.clone();
-        mock_provider.expect_write()
-            .withf(move |path_arg, data_arg| {
-                path_arg.as_os_str() == ep_1.as_os_str() && 
-                data_arg == b"some_data"
-            })
-            .times(1)
-            .returning(|_, _| Ok(()));
-        
-        let ep_2 = expected_path.clone();
-        mock_provider.expect_write()
-            .withf(move |path_arg, data_arg| {
-                path_arg.as_os_str() == ep_2.as_os_str() && 
-                data_arg.len() == metadata_size &&
-                data_arg.iter().all(|&b| b == 0)
-            })
-            .times(1)
-            .returning(|_, _| Ok(()));
 
+        let (mock_provider, _) = provider_with_sink(PathBuf::from(id.to_string()));
         let mut writer = ShardWriter::new(id, mock_provider);
         let result = writer.write(key, data, Some(&metadata)).await;
         assert!(result.is_ok());
-        assert_eq!(writer.current_size, data.len() + metadata_size);
+        assert_eq!(writer.current_size, DEFAULT_SHARD_SIZE);
         assert_eq!(writer.entries.len(), 1);
 
         let additional_data = b"more_data";
@@ -361,21 +672,145 @@ mod tests {
         let key = "key1";
         let id = ByteCounter::default();
 
-        let mut mock_provider = MockFakeStorageProvider::default();
-        mock_provider.expect_write()
-            .with(eq(PathBuf::from(id.to_string())), eq(*data))
-            .times(1)
-            .returning(|_, _| Ok(()));
-            
+        let (mock_provider, state) = provider_with_sink(PathBuf::from(id.to_string()));
         let mut writer = ShardWriter::new(id, mock_provider);
         
 
         assert!(writer.write(key, data, None).await.is_ok());
 
+        let written = state.data.lock().unwrap().clone();
         let mut hasher = Sha256::new();
-        hasher.update(data);
+        hasher.update(&written[BLOCK_PREFIX_SIZE..]);
         let expected_checksum: [u8; 32] = hasher.finalize().into();
 
         assert_eq!(writer.entries[0].checksum, expected_checksum);
+        assert_eq!(written[8..BLOCK_PREFIX_SIZE], expected_checksum);
+    }
+
+    #[tokio::test]
+    async fn test_finalize_writes_index_and_footer() {
+        let id = ByteCounter::default();
+        let (mock_provider, state) = provider_with_sink(PathBuf::from(id.to_string()));
+
+        let mut writer = ShardWriter::new(id, mock_provider)
+            .with_metadata(b"shard-meta".to_vec());
+        writer.write("key1", b"some_data", None).await.unwrap();
+        writer.write("key2", b"more_data", Some(b"metadata")).await.unwrap();
+        assert!(!*state.finished.lock().unwrap());
+
+        let index = writer.finalize().await.unwrap();
+        assert_eq!(index.records.len(), 2);
+        assert!(*state.finished.lock().unwrap());
+
+        let data = state.data.lock().unwrap().clone();
+        assert!(data.ends_with(&MAGIC));
+        let footer = &data[data.len() - FOOTER_SIZE..];
+        let index_offset = u64::from_le_bytes(footer[8..16].try_into().unwrap()) as usize;
+        let index_len = u64::from_le_bytes(footer[16..24].try_into().unwrap()) as usize;
+        assert_eq!(index_offset + index_len + FOOTER_SIZE, data.len());
+
+        let index = decode_index(&data[index_offset..index_offset + index_len]).unwrap();
+        assert_eq!(index.metadata, b"shard-meta");
+        assert_eq!(index.records[0].key, "key1");
+        assert_eq!(index.records[1].key, "key2");
+        assert_eq!(index.records[1].offset, index.records[0].size);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_record_block_layout() {
+        let id = ByteCounter::default();
+        let (mock_provider, state) = provider_with_sink(PathBuf::from(id.to_string()));
+
+        let mut writer = ShardWriter::new(id, mock_provider);
+        writer.write("k", b"abc", Some(b"m")).await.unwrap();
+        let data = state.data.lock().unwrap().clone();
+
+        // Parse the block field by field, as a reader without this crate would
+        let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+        assert_eq!(u64_at(0) as usize, data.len());
+        assert_eq!(data[8..40], Sha256::digest(&data[40..])[..]);
+        let header_len = u64_at(40) as usize;
+        let header = &data[48..48 + header_len];
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&1u64.to_le_bytes());
+        expected.extend_from_slice(b"k");
+        expected.extend_from_slice(&1u64.to_le_bytes());
+        expected.extend_from_slice(b"m");
+        expected.extend_from_slice(&1u64.to_le_bytes());
+        expected.extend_from_slice(&(DEFAULT_ENTRY_NAME.len() as u64).to_le_bytes());
+        expected.extend_from_slice(DEFAULT_ENTRY_NAME.as_bytes());
+        expected.extend_from_slice(&(DEFAULT_CONTENT_TYPE.len() as u64).to_le_bytes());
+        expected.extend_from_slice(DEFAULT_CONTENT_TYPE.as_bytes());
+        expected.extend_from_slice(&0u32.to_le_bytes());
+        expected.extend_from_slice(&0u64.to_le_bytes());
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(&Sha256::digest(b"abc"));
+        assert_eq!(header, expected.as_slice());
+        assert_eq!(&data[48 + header_len..], b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_abort_discards_shard() {
+        let id = ByteCounter::default();
+        let (mock_provider, state) = provider_with_sink(PathBuf::from(id.to_string()));
+
+        let mut writer = ShardWriter::new(id, mock_provider);
+        writer.write("key1", b"some_data", None).await.unwrap();
+        writer.abort().await.unwrap();
+
+        assert!(state.data.lock().unwrap().is_empty());
+        assert!(!*state.finished.lock().unwrap());
+    }
+
+    async fn local_shard(root: &tempfile::TempDir) -> PathBuf {
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = PathBuf::from("bucket/shard");
+        let mut writer = ShardWriter::create(provider, path.clone()).with_metadata(b"shard-meta".to_vec());
+        writer.write("key1", b"some_data", None).await.unwrap();
+        writer.write("key2", b"more_data", Some(b"metadata")).await.unwrap();
+        writer.finalize().await.unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_reopen_appends_records() {
+        let root = tempfile::TempDir::new().unwrap();
+        let path = local_shard(&root).await;
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let mut writer = ShardWriter::reopen(provider, path.clone()).await.unwrap();
+        assert_eq!(writer.entries.len(), 2);
+        let entry = writer.write("key3", b"appended", None).await.unwrap();
+        assert_eq!(entry.offset, writer.entries[1].offset + writer.entries[1].size);
+        writer.finalize().await.unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let reader = ShardReader::open(provider, path.clone()).await.unwrap();
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.metadata(), b"shard-meta");
+        assert_eq!(reader.read_record_by_key("key2").await.unwrap().metadata(), b"metadata");
+        assert_eq!(reader.read_record_by_key("key3").await.unwrap().entries()[0].data(), b"appended");
+        assert!(!root.path().join(staging_path(&path)).exists());
+    }
+
+    #[tokio::test]
+    async fn test_reopen_leaves_shard_intact_until_finalized() {
+        let root = tempfile::TempDir::new().unwrap();
+        let path = local_shard(&root).await;
+        let before = std::fs::read(root.path().join(&path)).unwrap();
+
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let mut writer = ShardWriter::reopen(provider, path.clone()).await.unwrap();
+        writer.write("key3", b"appended", None).await.unwrap();
+
+        // Until `finalize`, the shard still reads with its old index
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        assert_eq!(ShardReader::open(provider, path.clone()).await.unwrap().len(), 2);
+
+        writer.abort().await.unwrap();
+        assert_eq!(std::fs::read(root.path().join(&path)).unwrap(), before);
+        assert!(!root.path().join(staging_path(&path)).exists());
+    }
+}
@@ -2,10 +2,55 @@ use crate::error::Error;
 use crate::types::Result;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
 
 const DEFAULT_LOCAL_STORAGE_PATH: &str = "./local_bucket";
+/// Appended to the path of an object a local sink is still streaming.
+pub(crate) const PARTIAL_SUFFIX: &str = ".partial";
+/// Appended to the path of an object to name the file backing a local lock on it.
+pub(crate) const LOCK_SUFFIX: &str = ".lock";
+
+/// Describes a stored object.
+///
+/// # Fields
+///
+/// * `size` - The size of the object in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ObjectStat {
+    pub size: u64,
+}
+
+/// A writable handle streaming bytes into a single object.
+///
+/// Bytes are appended in order with `write_all` and the object is complete once `finish`
+/// returns. `abort` discards everything written so far. Any object already stored at the
+/// path is left untouched until `finish` replaces it, so an aborted or crashed writer never
+/// destroys it. Local providers back the sink with a temporary file next to the object, object
+/// stores with a multipart upload.
+#[async_trait]
+pub trait StorageSink: Send {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()>;
+    async fn finish(self: Box<Self>) -> Result<()>;
+    async fn abort(self: Box<Self>) -> Result<()>;
+}
+
+/// An exclusive lock taken with `StorageProvider::lock` or `StorageProvider::try_lock`.
+///
+/// The lock is held until `release` or `remove` returns or the handle is dropped. Local
+/// providers back the lock with an advisory file lock, which the operating system also
+/// releases when its holder dies, so a crash never leaves a bucket locked.
+#[async_trait]
+pub trait StorageLock: Send + Sync {
+    async fn release(self: Box<Self>) -> Result<()>;
+    /// Releases the lock and removes what backs it from storage. Only meant for locks that
+    /// nobody waits for, such as those `try_lock` takes.
+    async fn remove(self: Box<Self>) -> Result<()>;
+}
 
 #[async_trait]
 pub trait StorageProvider: Send + Sync + Default {
@@ -13,9 +58,34 @@ pub trait StorageProvider: Send + Sync + Default {
     async fn delete_bucket(&self, name: &str) -> Result<()>;
     async fn bucket_exists(&self, name: &str) -> Result<bool>;
     async fn write(&self, path: &Path, data: &[u8]) -> Result<()>;
-    async fn read(&self, path: &Path) -> Result<Vec<u8>>;
+    /// Opens a sink streaming a new object to `path`, replacing any existing object once finished.
+    async fn open_writer(&self, path: &Path) -> Result<Box<dyn StorageSink>>;
+    /// Reads the whole object at `path` into a shared buffer that readers slice without copying.
+    async fn read(&self, path: &Path) -> Result<Bytes>;
+    /// Reads `len` bytes of the object at `path` starting at `offset`.
+    async fn read_range(&self, path: &Path, offset: u64, len: u64) -> Result<Bytes>;
+    /// Reads several `(offset, len)` ranges of the object at `path`, in the given order.
+    async fn read_ranges(&self, path: &Path, ranges: &[(u64, u64)]) -> Result<Vec<Bytes>> {
+        let mut chunks = Vec::with_capacity(ranges.len());
+        for &(offset, len) in ranges {
+            chunks.push(self.read_range(path, offset, len).await?);
+        }
+        Ok(chunks)
+    }
+    async fn stat(&self, path: &Path) -> Result<ObjectStat>;
+    /// Moves the object at `from` to `to`, atomically replacing any existing object at `to`.
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()>;
     async fn delete(&self, path: &Path) -> Result<()>;
     async fn list(&self, prefix: &Path) -> Result<Vec<String>>;
+    /// Takes an exclusive lock on `path`, waiting while another holder, in this or another
+    /// process, has it. The lock does not prevent access to the object at `path`; it only
+    /// serializes the writers that take it.
+    async fn lock(&self, path: &Path) -> Result<Box<dyn StorageLock>>;
+    /// Takes an exclusive lock on `path` as `lock` does, unless another holder has it.
+    ///
+    /// # Returns
+    /// * `Result<Option<Box<dyn StorageLock>>>` with the lock, or `None` if it is held.
+    async fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn StorageLock>>>;
 }
 
 pub struct LocalStorageProvider {
@@ -28,6 +98,92 @@ impl LocalStorageProvider {
         fs::create_dir_all(&root).await.map_err(Error::from)?;
         Ok(Self { root })
     }
+
+    /// Opens the file backing the lock on the object at `path`, creating it if needed.
+    ///
+    /// The lock is taken on a file next to the object, which is never replaced.
+    async fn open_lock(&self, path: &Path) -> Result<(PathBuf, std::fs::File)> {
+        let path = lock_path(&self.root.join(path));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.map_err(Error::from)?;
+        }
+        let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path).await.map_err(Error::from)?;
+        Ok((path, file.into_std().await))
+    }
+}
+
+/// Returns the path a local sink streams the object at `path` to until it is finished.
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    PathBuf::from(partial)
+}
+
+/// Returns the path of the file backing the lock on the object at `path`.
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(LOCK_SUFFIX);
+    PathBuf::from(lock)
+}
+
+/// Syncs the directory holding `path`, making renames and removals within it durable.
+async fn sync_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) => fs::File::open(parent).await.map_err(Error::from)?.sync_all().await.map_err(Error::from),
+        None => Ok(()),
+    }
+}
+
+/// A `StorageSink` streaming into a temporary local file, which replaces the object once
+/// finished.
+///
+/// # Fields
+///
+/// * `path` - The path of the object.
+/// * `partial` - The path of the temporary file.
+/// * `file` - The temporary file.
+pub struct LocalStorageSink {
+    path: PathBuf,
+    partial: PathBuf,
+    file: BufWriter<fs::File>,
+}
+
+#[async_trait]
+impl StorageSink for LocalStorageSink {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await.map_err(Error::from)
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file.flush().await.map_err(Error::from)?;
+        self.file.get_ref().sync_all().await.map_err(Error::from)?;
+        fs::rename(&self.partial, &self.path).await.map_err(Error::from)?;
+        sync_parent(&self.path).await
+    }
+
+    async fn abort(self: Box<Self>) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.partial).await.map_err(Error::from)
+    }
+}
+
+/// A `StorageLock` holding an advisory lock on a local lock file.
+pub struct LocalStorageLock {
+    path: PathBuf,
+    file: std::fs::File,
+}
+
+#[async_trait]
+impl StorageLock for LocalStorageLock {
+    async fn release(self: Box<Self>) -> Result<()> {
+        self.file.unlock().map_err(Error::from)
+    }
+
+    async fn remove(self: Box<Self>) -> Result<()> {
+        // The file is removed before it is unlocked, so that later holders lock a new one
+        fs::remove_file(&self.path).await.map_err(Error::from)?;
+        self.release().await
+    }
 }
 
 impl Default for LocalStorageProvider {
@@ -61,9 +217,52 @@ impl StorageProvider for LocalStorageProvider {
         fs::write(full_path, data).await.map_err(Error::from)
     }
 
-    async fn read(&self, path: &Path) -> Result<Vec<u8>> {
+    async fn open_writer(&self, path: &Path) -> Result<Box<dyn StorageSink>> {
+        let full_path = self.root.join(path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent).await.map_err(Error::from)?;
+        }
+        let partial = partial_path(&full_path);
+        let file = fs::File::create(&partial).await.map_err(Error::from)?;
+        Ok(Box::new(LocalStorageSink { path: full_path, partial, file: BufWriter::new(file) }))
+    }
+
+    async fn read(&self, path: &Path) -> Result<Bytes> {
+        let full_path = self.root.join(path);
+        fs::read(full_path).await.map(Bytes::from).map_err(Error::from)
+    }
+
+    async fn read_range(&self, path: &Path, offset: u64, len: u64) -> Result<Bytes> {
         let full_path = self.root.join(path);
-        fs::read(full_path).await.map_err(Error::from)
+        let mut file = fs::File::open(full_path).await.map_err(Error::from)?;
+        read_at(&mut file, offset, len).await
+    }
+
+    async fn read_ranges(&self, path: &Path, ranges: &[(u64, u64)]) -> Result<Vec<Bytes>> {
+        let full_path = self.root.join(path);
+        let mut file = fs::File::open(full_path).await.map_err(Error::from)?;
+        let mut chunks = Vec::with_capacity(ranges.len());
+        for &(offset, len) in ranges {
+            chunks.push(read_at(&mut file, offset, len).await?);
+        }
+        Ok(chunks)
+    }
+
+    async fn stat(&self, path: &Path) -> Result<ObjectStat> {
+        let full_path = self.root.join(path);
+        let metadata = fs::metadata(full_path).await.map_err(Error::from)?;
+        Ok(ObjectStat { size: metadata.len() })
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let to = self.root.join(to);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).await.map_err(Error::from)?;
+        }
+        fs::rename(self.root.join(from), &to).await.map_err(Error::from)?;
+
+        // The rename is only durable once the directory holding `to` is synced
+        sync_parent(&to).await
     }
 
     async fn delete(&self, path: &Path) -> Result<()> {
@@ -77,12 +276,200 @@ impl StorageProvider for LocalStorageProvider {
         let mut read_dir = fs::read_dir(full_path).await.map_err(Error::from)?;
         
         while let Some(entry) = read_dir.next_entry().await.map_err(Error::from)? {
-            if let Ok(path) = entry.path().strip_prefix(&self.root) {
-                if let Some(path_str) = path.to_str() {
-                    entries.push(path_str.to_string());
-                }
+            if let Ok(path) = entry.path().strip_prefix(&self.root)
+                && let Some(path_str) = path.to_str() {
+                entries.push(path_str.to_string());
             }
         }
         Ok(entries)
     }
+
+    async fn lock(&self, path: &Path) -> Result<Box<dyn StorageLock>> {
+        let (path, file) = self.open_lock(path).await?;
+        let file = tokio::task::spawn_blocking(move || file.lock().map(|_| file))
+            .await
+            .map_err(|err| Error::Storage(format!("Lock task failed: {}", err)))??;
+        Ok(Box::new(LocalStorageLock { path, file }))
+    }
+
+    async fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn StorageLock>>> {
+        let (path, file) = self.open_lock(path).await?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Box::new(LocalStorageLock { path, file }))),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: StorageProvider> StorageProvider for Arc<P> {
+    async fn create_bucket(&self, name: &str) -> Result<()> {
+        self.as_ref().create_bucket(name).await
+    }
+
+    async fn delete_bucket(&self, name: &str) -> Result<()> {
+        self.as_ref().delete_bucket(name).await
+    }
+
+    async fn bucket_exists(&self, name: &str) -> Result<bool> {
+        self.as_ref().bucket_exists(name).await
+    }
+
+    async fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        self.as_ref().write(path, data).await
+    }
+
+    async fn open_writer(&self, path: &Path) -> Result<Box<dyn StorageSink>> {
+        self.as_ref().open_writer(path).await
+    }
+
+    async fn read(&self, path: &Path) -> Result<Bytes> {
+        self.as_ref().read(path).await
+    }
+
+    async fn read_range(&self, path: &Path, offset: u64, len: u64) -> Result<Bytes> {
+        self.as_ref().read_range(path, offset, len).await
+    }
+
+    async fn read_ranges(&self, path: &Path, ranges: &[(u64, u64)]) -> Result<Vec<Bytes>> {
+        self.as_ref().read_ranges(path, ranges).await
+    }
+
+    async fn stat(&self, path: &Path) -> Result<ObjectStat> {
+        self.as_ref().stat(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.as_ref().rename(from, to).await
+    }
+
+    async fn delete(&self, path: &Path) -> Result<()> {
+        self.as_ref().delete(path).await
+    }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<String>> {
+        self.as_ref().list(prefix).await
+    }
+
+    async fn lock(&self, path: &Path) -> Result<Box<dyn StorageLock>> {
+        self.as_ref().lock(path).await
+    }
+
+    async fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn StorageLock>>> {
+        self.as_ref().try_lock(path).await
+    }
+}
+
+/// Seeks `file` to `offset` and reads exactly `len` bytes.
+async fn read_at(file: &mut fs::File, offset: u64, len: u64) -> Result<Bytes> {
+    file.seek(SeekFrom::Start(offset)).await.map_err(Error::from)?;
+    let mut buffer = vec![0u8; len as usize];
+    file.read_exact(&mut buffer).await.map_err(Error::from)?;
+    Ok(Bytes::from(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_read_range_and_stat() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = Path::new("bucket/object");
+        provider.write(path, b"0123456789").await.unwrap();
+
+        assert_eq!(provider.stat(path).await.unwrap().size, 10);
+        assert_eq!(provider.read_range(path, 2, 3).await.unwrap(), b"234"[..]);
+        assert_eq!(provider.read_range(path, 10, 0).await.unwrap(), b""[..]);
+        assert!(provider.read_range(path, 8, 5).await.is_err());
+
+        let chunks = provider.read_ranges(path, &[(7, 3), (0, 2)]).await.unwrap();
+        assert_eq!(chunks, vec![Bytes::from_static(b"789"), Bytes::from_static(b"01")]);
+    }
+
+    #[tokio::test]
+    async fn test_open_writer_streams_object() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = Path::new("bucket/streamed");
+
+        let mut sink = provider.open_writer(path).await.unwrap();
+        sink.write_all(b"first,").await.unwrap();
+        sink.write_all(b"second").await.unwrap();
+        sink.finish().await.unwrap();
+        assert_eq!(provider.read(path).await.unwrap(), b"first,second"[..]);
+
+        // The object is only replaced once the sink is finished
+        let mut sink = provider.open_writer(path).await.unwrap();
+        sink.write_all(b"discarded").await.unwrap();
+        assert_eq!(provider.read(path).await.unwrap(), b"first,second"[..]);
+        sink.abort().await.unwrap();
+        assert_eq!(provider.read(path).await.unwrap(), b"first,second"[..]);
+        assert!(provider.stat(&partial_path(path)).await.is_err());
+
+        let mut sink = provider.open_writer(path).await.unwrap();
+        sink.write_all(b"replaced").await.unwrap();
+        sink.finish().await.unwrap();
+        assert_eq!(provider.read(path).await.unwrap(), b"replaced"[..]);
+        assert_eq!(provider.list(Path::new("bucket")).await.unwrap(), ["bucket/streamed"]);
+    }
+
+    #[tokio::test]
+    async fn test_stat_missing_object() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        assert!(provider.stat(Path::new("missing")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lock_is_exclusive() {
+        let root = TempDir::new().unwrap();
+        let provider = Arc::new(LocalStorageProvider::new(root.path()).await.unwrap());
+        let path = Path::new("bucket/MANIFEST");
+        let lock = provider.lock(path).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let provider = Arc::clone(&provider);
+            async move { provider.lock(Path::new("bucket/MANIFEST")).await.unwrap().release().await.unwrap() }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        lock.release().await.unwrap();
+        waiting.await.unwrap();
+
+        // Dropping a lock releases it as well
+        drop(provider.lock(path).await.unwrap());
+        provider.lock(path).await.unwrap().release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_try_lock_skips_held_lock() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        let path = Path::new("bucket/shard");
+        let claim = provider.try_lock(path).await.unwrap().unwrap();
+        assert!(provider.try_lock(path).await.unwrap().is_none());
+
+        // Removing the lock frees it and leaves nothing behind
+        claim.remove().await.unwrap();
+        assert!(provider.list(Path::new("bucket")).await.unwrap().is_empty());
+        provider.try_lock(path).await.unwrap().unwrap().release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rename_replaces_object() {
+        let root = TempDir::new().unwrap();
+        let provider = LocalStorageProvider::new(root.path()).await.unwrap();
+        provider.write(Path::new("bucket/object"), b"old").await.unwrap();
+        provider.write(Path::new("staging"), b"new").await.unwrap();
+
+        provider.rename(Path::new("staging"), Path::new("bucket/object")).await.unwrap();
+        assert_eq!(provider.read(Path::new("bucket/object")).await.unwrap(), b"new"[..]);
+        assert!(provider.stat(Path::new("staging")).await.is_err());
+    }
 }